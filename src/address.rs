@@ -1,54 +1,269 @@
 use crate::Line;
-use std::sync::atomic;
+use std::{
+    cell::{Cell, RefCell},
+    sync::atomic,
+};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum Address {
     // always matches
     Always,
-    // never matches
+    // no address given, matches like Always
+    Implicit,
+    // $ the last line
     Final,
     // specific index
     Location(usize),
+    // first~step every step-th line starting at first
+    Step {
+        first: usize,
+        step: usize,
+    },
     // /regex/ matching the line
     Regex(crate::Regex),
+    // /regex/#n the n-th line matching the regex
+    NthMatch(NthMatch),
+    // /regex/{n,} the regex matches at least n times in the line
+    CountAtLeast {
+        regex: crate::Regex,
+        n: usize,
+    },
+    // =/regex/ matching the line number
+    LineNumberRegex(crate::Regex),
     // addr! negates the addr match
     Negate(Box<Address>),
-    // // addr1 - addr2
+    // addr1 - addr2 or addr1 -< addr2 excluding the end
     Between(Between),
     // addr1, addr2, ...
     Set(Vec<Address>),
-    // ?
+    // /regex1/, /regex2/, ... the set of regexes matched in a single pass
+    RegexSet(RegexSet),
+    // +n the end of the range n lines after its start, only used as the rhs of Between
+    Offset(usize),
+    // ? line differs from the previous one
+    Changed(Changed),
+    // /regex1/>/regex2/ the line matches regex1 and the next line matches regex2
+    FollowedBy(crate::Regex, crate::Regex),
+    // indent>n the indentation compared to n
+    Indent {
+        op: std::cmp::Ordering,
+        n: usize,
+        tab_width: usize,
+    },
+    // _ the regex of the following substitution
     Maybe,
+    // n% the line at n percent of the input
+    Percent(Percent),
 }
 
 impl Address {
-    pub(crate) fn matches(&self, line: &Line) -> bool {
+    /// Check if the address matches the `line`, `next` is the content of the following line
+    /// (if available), `last` tells if it is the last line of the input.
+    pub(crate) fn matches(&self, line: &Line, next: Option<&str>, last: bool) -> bool {
         use Address::*;
         match self {
-            Always => true,
-            Final => false,
+            Always | Implicit => true,
+            Final => last,
             Location(idx) => *idx == line.0,
+            Step { first, step } => line.0 >= *first && (line.0 - first).is_multiple_of(*step),
             Regex(ref regex) => regex.0.is_match(&line.1),
-            Negate(addr) => !addr.matches(line),
-            Between(this) => this.matches(line),
+            LineNumberRegex(ref regex) => regex.0.is_match(&line.0.to_string()),
+            FollowedBy(lhs, rhs) => {
+                lhs.0.is_match(&line.1) && next.is_some_and(|s| rhs.0.is_match(s))
+            }
+            Negate(addr) => !addr.matches(line, next, last),
+            Between(this) => this.matches(line, next, last),
+            Changed(this) => this.matches(line),
+            NthMatch(this) => this.matches(line),
+            CountAtLeast { regex, n } => regex.0.find_iter(&line.1).take(*n).count() == *n,
+            Percent(this) => this.matches(line),
+            RegexSet(this) => this.set.is_match(&line.1),
+            Indent { op, n, tab_width } => indentation(&line.1, *tab_width).cmp(n) == *op,
             Set(set) => {
+                let mut matched = false;
                 for addr in set.iter() {
-                    if addr.matches(line) {
-                        return true;
+                    // the stateful addresses, like ranges, need to see all the lines
+                    // to track their bounds, even if the set already matched
+                    if matched && !addr.is_stateful() {
+                        continue;
                     }
+                    matched |= addr.matches(line, next, last);
                 }
-                false
+                matched
             }
-            Maybe => unimplemented!(),
+            Maybe => unreachable!("_ is replaced with the regex of the substitution when parsing"),
+            Offset(_) => unreachable!("+n is only matched as the end of a range"),
+        }
+    }
+
+    /// Check if the address is a range that matched the line, but did not end yet.
+    pub(crate) fn in_range(&self) -> bool {
+        match self {
+            Address::Between(this) => {
+                this.inside.load(atomic::Ordering::Relaxed)
+                    && !this.ends_next.load(atomic::Ordering::Relaxed)
+            }
+            _ => false,
+        }
+    }
+
+    /// The regular expression the address matches the line with, if any.
+    pub(crate) fn regex(&self) -> Option<&crate::Regex> {
+        match self {
+            Address::Regex(regex) | Address::FollowedBy(regex, _) => Some(regex),
+            Address::NthMatch(this) => Some(&this.regex),
+            Address::CountAtLeast { regex, .. } => Some(regex),
+            _ => None,
+        }
+    }
+
+    /// Values of the named groups captured by the regex.
+    pub(crate) fn captures(&self, line: &Line) -> Vec<(String, String)> {
+        let Address::Regex(regex) = self else {
+            return Vec::new();
+        };
+        if regex.0.capture_names().flatten().next().is_none() {
+            return Vec::new();
+        }
+        let Some(caps) = regex.0.captures(&line.1) else {
+            return Vec::new();
+        };
+        regex
+            .0
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), caps.name(name)?.as_str().to_string())))
+            .collect()
+    }
+
+    /// Rough estimate of the cost of checking the address.
+    pub(crate) fn cost(&self) -> usize {
+        use Address::*;
+        match self {
+            Always | Implicit | Final | Offset(_) => 0,
+            Location(_) | Step { .. } | Indent { .. } | Percent(_) => 1,
+            Changed(_) => 2,
+            Regex(_) | LineNumberRegex(_) | NthMatch(_) | RegexSet(_) | Maybe => 10,
+            FollowedBy(..) | CountAtLeast { .. } => 20,
+            Negate(addr) => addr.cost(),
+            Between(this) => this.lhs.cost() + this.rhs.cost(),
+            Set(set) => set.iter().map(|a| a.cost()).sum(),
+        }
+    }
+
+    /// Check if the result of matching depends on the previously matched lines.
+    pub(crate) fn is_stateful(&self) -> bool {
+        use Address::*;
+        match self {
+            Between(_) | Changed(_) | NthMatch(_) => true,
+            Negate(addr) => addr.is_stateful(),
+            Set(set) => set.iter().any(|a| a.is_stateful()),
+            _ => false,
+        }
+    }
+
+    /// Check if matching needs to look at the following line, or to know if it is the last one.
+    pub(crate) fn looks_ahead(&self) -> bool {
+        use Address::*;
+        match self {
+            Final | FollowedBy(..) => true,
+            Negate(addr) => addr.looks_ahead(),
+            Between(this) => this.exclusive || this.lhs.looks_ahead() || this.rhs.looks_ahead(),
+            Set(set) => set.iter().any(|a| a.looks_ahead()),
+            _ => false,
+        }
+    }
+
+    /// Check if matching needs to know the number of lines in the input.
+    pub(crate) fn needs_line_count(&self) -> bool {
+        use Address::*;
+        match self {
+            Percent(_) => true,
+            Negate(addr) => addr.needs_line_count(),
+            Between(this) => this.lhs.needs_line_count() || this.rhs.needs_line_count(),
+            Set(set) => set.iter().any(|a| a.needs_line_count()),
+            _ => false,
+        }
+    }
+
+    /// Set the number of lines in the input, so the percentages can be turned to line numbers.
+    pub(crate) fn set_line_count(&self, total: usize) {
+        use Address::*;
+        match self {
+            Percent(this) => this.resolve(total),
+            Negate(addr) => addr.set_line_count(total),
+            Between(this) => {
+                this.lhs.set_line_count(total);
+                this.rhs.set_line_count(total);
+            }
+            Set(set) => set.iter().for_each(|a| a.set_line_count(total)),
+            _ => (),
+        }
+    }
+
+    /// Forget the state of the stateful addresses, so they can be matched against another input.
+    pub(crate) fn reset(&self) {
+        use Address::*;
+        match self {
+            Between(this) => {
+                this.inside.store(false, atomic::Ordering::Relaxed);
+                this.ends_next.store(false, atomic::Ordering::Relaxed);
+                this.start.store(0, atomic::Ordering::Relaxed);
+                this.lhs.reset();
+                this.rhs.reset();
+            }
+            NthMatch(this) => this.seen.set(0),
+            Changed(this) => *this.previous.borrow_mut() = None,
+            Negate(addr) => addr.reset(),
+            Set(set) => set.iter().for_each(|a| a.reset()),
+            _ => (),
+        }
+    }
+
+    /// Reorder the members of the sets so that the cheaper ones are checked first.
+    /// The stateful members are checked anyway, so the order does not change the result.
+    pub(crate) fn optimize(&mut self) {
+        use Address::*;
+        match self {
+            Negate(addr) => addr.optimize(),
+            Set(set) => {
+                set.iter_mut().for_each(|a| a.optimize());
+                set.sort_by_key(|a| a.cost());
+            }
+            Between(this) => {
+                this.lhs.optimize();
+                this.rhs.optimize();
+            }
+            _ => (),
         }
     }
 }
 
+/// Width of the leading whitespace, tabs advance to the next multiple of `tab_width`.
+fn indentation(s: &str, tab_width: usize) -> usize {
+    let mut width = 0;
+    for c in s.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' if tab_width > 0 => width += tab_width - width % tab_width,
+            '\t' => (),
+            _ => break,
+        }
+    }
+    width
+}
+
 #[derive(Debug)]
 pub(crate) struct Between {
     pub(crate) lhs: Box<Address>,
     pub(crate) rhs: Box<Address>,
+    // the range ends before the line matching rhs
+    pub(crate) exclusive: bool,
     inside: atomic::AtomicBool,
+    // the next line ends the exclusive range
+    ends_next: atomic::AtomicBool,
+    // line number where the lhs matched
+    start: atomic::AtomicUsize,
 }
 
 impl Between {
@@ -56,31 +271,203 @@ impl Between {
         Between {
             lhs: Box::new(lhs),
             rhs: Box::new(rhs),
+            exclusive: false,
             inside: atomic::AtomicBool::new(false),
+            ends_next: atomic::AtomicBool::new(false),
+            start: atomic::AtomicUsize::new(0),
         }
     }
 
-    pub(crate) fn matches(&self, line: &Line) -> bool {
+    /// The range that ends at the line before the one matching `rhs`.
+    pub(crate) fn exclusive(lhs: Address, rhs: Address) -> Self {
+        Between {
+            exclusive: true,
+            ..Between::new(lhs, rhs)
+        }
+    }
+
+    pub(crate) fn matches(&self, line: &Line, next: Option<&str>, last: bool) -> bool {
         if self.inside.load(atomic::Ordering::Relaxed) {
-            if self.rhs.matches(line) {
-                self.inside.store(false, atomic::Ordering::Relaxed)
+            if !self.ends_at(line, next, last) {
+                self.predict_end(line, next);
+                return true;
             }
-            true
-        } else {
-            if self.lhs.matches(line) {
-                if !self.rhs.matches(line) {
-                    self.inside.store(true, atomic::Ordering::Relaxed)
-                }
+            self.inside.store(false, atomic::Ordering::Relaxed);
+            if !self.exclusive {
                 return true;
             }
-            false
+            // the line ending the exclusive range is not a part of it, but it can start the next one
+        }
+        if self.lhs.matches(line, next, last) {
+            self.start.store(line.0, atomic::Ordering::Relaxed);
+            if self.exclusive || !self.ends_at(line, next, last) {
+                self.inside.store(true, atomic::Ordering::Relaxed);
+                self.predict_end(line, next);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// For the exclusive range, check if the `next` line is going to end it, so the `line`
+    /// is the last one in the range. The end address only peeks at the next line without
+    /// counting it, as it sees each line when it is processed. The line after the next one
+    /// is not known, so `$` and `/a/>/b/` cannot be predicted to end the range.
+    fn predict_end(&self, line: &Line, next: Option<&str>) {
+        if !self.exclusive {
+            return;
+        }
+        let ends = match next {
+            Some(next) => {
+                let next = Line(line.0 + 1, next.to_string());
+                match *self.rhs {
+                    Address::Offset(n) => next.0 >= self.start.load(atomic::Ordering::Relaxed) + n,
+                    Address::NthMatch(ref this) => this.peek(&next),
+                    Address::Changed(ref this) => this.peek(&next),
+                    ref rhs => rhs.matches(&next, None, false),
+                }
+            }
+            None => true,
+        };
+        self.ends_next.store(ends, atomic::Ordering::Relaxed);
+    }
+
+    /// Check if the `line` matches the end of the range, for the exclusive range
+    /// it is the first line after the range.
+    fn ends_at(&self, line: &Line, next: Option<&str>, last: bool) -> bool {
+        match *self.rhs {
+            Address::Offset(n) => line.0 >= self.start.load(atomic::Ordering::Relaxed) + n,
+            ref rhs => rhs.matches(line, next, last),
         }
     }
 }
 
 impl PartialEq for Between {
     fn eq(&self, other: &Self) -> bool {
-        self.lhs == other.lhs && self.rhs == other.rhs
+        self.lhs == other.lhs && self.rhs == other.rhs && self.exclusive == other.exclusive
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct NthMatch {
+    pub(crate) regex: crate::Regex,
+    pub(crate) n: usize,
+    seen: Cell<usize>,
+}
+
+impl NthMatch {
+    pub(crate) fn new(regex: crate::Regex, n: usize) -> Self {
+        NthMatch {
+            regex,
+            n,
+            seen: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn matches(&self, line: &Line) -> bool {
+        if !self.regex.0.is_match(&line.1) {
+            return false;
+        }
+        let seen = self.seen.get() + 1;
+        self.seen.set(seen);
+        seen == self.n
+    }
+
+    /// Check if the `line` would match, without counting it.
+    fn peek(&self, line: &Line) -> bool {
+        self.regex.0.is_match(&line.1) && self.seen.get() + 1 == self.n
+    }
+}
+
+impl PartialEq for NthMatch {
+    fn eq(&self, other: &Self) -> bool {
+        self.regex == other.regex && self.n == other.n
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RegexSet {
+    pub(crate) regexes: Vec<crate::Regex>,
+    set: regex::RegexSet,
+}
+
+impl RegexSet {
+    pub(crate) fn new(
+        regexes: Vec<crate::Regex>,
+        size_limit: Option<usize>,
+    ) -> Result<Self, regex::Error> {
+        let mut builder = regex::RegexSetBuilder::new(regexes.iter().map(|r| r.0.as_str()));
+        if let Some(limit) = size_limit {
+            builder.size_limit(limit).dfa_size_limit(limit);
+        }
+        let set = builder.build()?;
+        Ok(RegexSet { regexes, set })
+    }
+}
+
+impl PartialEq for RegexSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.regexes == other.regexes
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Percent {
+    pub(crate) percent: u8,
+    // the line number, known after counting the lines
+    line: Cell<Option<usize>>,
+}
+
+impl Percent {
+    pub(crate) fn new(percent: u8) -> Self {
+        Percent {
+            percent,
+            line: Cell::new(None),
+        }
+    }
+
+    /// Find the line at the percentage of `total` lines, `0%` is the first line
+    /// and `100%` the last one.
+    pub(crate) fn resolve(&self, total: usize) {
+        let line = (total * self.percent as usize).div_ceil(100).max(1);
+        self.line.set(Some(line));
+    }
+
+    pub(crate) fn matches(&self, line: &Line) -> bool {
+        self.line.get() == Some(line.0)
+    }
+}
+
+impl PartialEq for Percent {
+    fn eq(&self, other: &Self) -> bool {
+        self.percent == other.percent
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Changed {
+    previous: RefCell<Option<String>>,
+}
+
+impl Changed {
+    pub(crate) fn matches(&self, line: &Line) -> bool {
+        let mut previous = self.previous.borrow_mut();
+        if previous.as_ref() == Some(&line.1) {
+            return false;
+        }
+        *previous = Some(line.1.to_string());
+        true
+    }
+
+    /// Check if the `line` would match, without remembering it.
+    fn peek(&self, line: &Line) -> bool {
+        self.previous.borrow().as_ref() != Some(&line.1)
+    }
+}
+
+impl PartialEq for Changed {
+    fn eq(&self, _other: &Self) -> bool {
+        true
     }
 }
 
@@ -101,10 +488,19 @@ impl std::fmt::Display for Address {
         use Address::*;
         match self {
             Always => write!(f, "//"),
+            Implicit => Ok(()),
             Final => write!(f, "$"),
             Location(idx) => write!(f, "{}", idx),
+            Percent(this) => write!(f, "{}%", this.percent),
+            Step { first, step } => write!(f, "{}~{}", first, step),
             Regex(regex) => write!(f, "/{}/", regex),
+            LineNumberRegex(regex) => write!(f, "=/{}/", regex),
+            NthMatch(this) => write!(f, "/{}/#{}", this.regex, this.n),
+            CountAtLeast { regex, n } => write!(f, "/{}/{{{},}}", regex, n),
+            FollowedBy(lhs, rhs) => write!(f, "/{}/>/{}/", lhs, rhs),
+            Negate(addr) if matches!(**addr, Set(_) | RegexSet(_)) => write!(f, "({})!", addr),
             Negate(addr) => write!(f, "{}!", addr),
+            Between(this) if this.exclusive => write!(f, "{}-<{}", this.lhs, this.rhs),
             Between(this) => write!(f, "{}-{}", this.lhs, this.rhs),
             Set(addrs) => {
                 let list = addrs
@@ -114,7 +510,26 @@ impl std::fmt::Display for Address {
                     .join(", ");
                 write!(f, "{}", list)
             }
+            RegexSet(this) => {
+                let list = this
+                    .regexes
+                    .iter()
+                    .map(|r| format!("/{}/", r))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "{}", list)
+            }
             Maybe => write!(f, "_"),
+            Offset(n) => write!(f, "+{}", n),
+            Changed(_) => write!(f, "?"),
+            Indent { op, n, .. } => {
+                let op = match op {
+                    std::cmp::Ordering::Less => '<',
+                    std::cmp::Ordering::Equal => '=',
+                    std::cmp::Ordering::Greater => '>',
+                };
+                write!(f, "indent{}{}", op, n)
+            }
         }
     }
 }
@@ -123,7 +538,7 @@ impl std::fmt::Display for Address {
 mod tests {
     use crate::{
         address::Address::{self, *},
-        parser::StringReader,
+        parser::{ParseContext, StringReader},
         Line,
     };
     use std::str::FromStr;
@@ -147,6 +562,42 @@ mod tests {
         false;
         "regex abc does not match line hello"
     )]
+    #[test_case(
+        Regex(crate::Regex::from_str(r"\x00").unwrap()),
+        Line(1, "abc\0def".to_string()),
+        true;
+        "null matches line with NUL byte"
+    )]
+    #[test_case(
+        Regex(crate::Regex::from_str(r"\x00").unwrap()),
+        Line(1, "abc".to_string()),
+        false;
+        "null does not match line without NUL byte"
+    )]
+    #[test_case(
+        LineNumberRegex(crate::Regex::from_str("00$").unwrap()),
+        Line(100, "".to_string()),
+        true;
+        "line number regex matches line 100"
+    )]
+    #[test_case(
+        LineNumberRegex(crate::Regex::from_str("00$").unwrap()),
+        Line(1200, "".to_string()),
+        true;
+        "line number regex matches line 1200"
+    )]
+    #[test_case(
+        LineNumberRegex(crate::Regex::from_str("00$").unwrap()),
+        Line(1001, "".to_string()),
+        false;
+        "line number regex does not match line 1001"
+    )]
+    #[test_case(
+        LineNumberRegex(crate::Regex::from_str("00$").unwrap()),
+        Line(1, "100".to_string()),
+        false;
+        "line number regex does not match line content"
+    )]
     #[test_case(
         Set(vec![Location(1), Location(2), Location(3)]),
         Line(1, "".to_string()),
@@ -159,8 +610,68 @@ mod tests {
         false;
         "set 1,2,3 does not match line 279"
     )]
+    #[test_case(
+        Indent { op: std::cmp::Ordering::Greater, n: 8, tab_width: 4 },
+        Line(1, "\t\t  x".to_string()),
+        true;
+        "indent tabs and spaces greater"
+    )]
+    #[test_case(
+        Indent { op: std::cmp::Ordering::Greater, n: 8, tab_width: 4 },
+        Line(1, "  \t  \tx".to_string()),
+        false;
+        "tabs expand to the tab stops"
+    )]
+    #[test_case(
+        Indent { op: std::cmp::Ordering::Equal, n: 0, tab_width: 8 },
+        Line(1, "x  ".to_string()),
+        true;
+        "no indent"
+    )]
+    #[test_case(
+        Indent { op: std::cmp::Ordering::Less, n: 4, tab_width: 8 },
+        Line(1, "\tx".to_string()),
+        false;
+        "tab is wider than limit"
+    )]
+    #[test_case(
+        Indent { op: std::cmp::Ordering::Equal, n: 3, tab_width: 8 },
+        Line(1, "   ".to_string()),
+        true;
+        "only whitespace"
+    )]
+    #[test_case(
+        CountAtLeast { regex: crate::Regex::from_str("word").unwrap(), n: 3 },
+        Line(1, "word word word".to_string()),
+        true;
+        "exactly the count"
+    )]
+    #[test_case(
+        CountAtLeast { regex: crate::Regex::from_str("word").unwrap(), n: 3 },
+        Line(1, "word, word, words, and wordy".to_string()),
+        true;
+        "more than the count"
+    )]
+    #[test_case(
+        CountAtLeast { regex: crate::Regex::from_str("word").unwrap(), n: 3 },
+        Line(1, "word word".to_string()),
+        false;
+        "less than the count"
+    )]
+    #[test_case(
+        CountAtLeast { regex: crate::Regex::from_str("aa").unwrap(), n: 2 },
+        Line(1, "aaa".to_string()),
+        false;
+        "non overlapping matches"
+    )]
+    #[test_case(
+        CountAtLeast { regex: crate::Regex::from_str("x").unwrap(), n: 1 },
+        Line(1, "".to_string()),
+        false;
+        "empty line"
+    )]
     fn matches(addr: Address, line: Line, expected: bool) {
-        assert_eq!(addr.matches(&line), expected)
+        assert_eq!(addr.matches(&line, None, false), expected)
     }
 
     #[test_case(
@@ -223,6 +734,21 @@ mod tests {
         vec![false, false, false, false, false, true, true, true, true, true];
         "half-open range"
     )]
+    #[test_case(
+        "2-<7",
+        vec![false, true, true, true, true, true, false, false, false, false];
+        "exclusive range of indexes"
+    )]
+    #[test_case(
+        "/start/-</end/",
+        vec![false, true, true, false, false, true, false, false, false, false];
+        "exclusive regex range"
+    )]
+    #[test_case(
+        "/start/-</start/",
+        vec![false, true, true, true, true, true, true, true, true, true];
+        "exclusive range restarted at its end"
+    )]
     fn multiline_example(addr: &str, expected: Vec<bool>) {
         let example = r"
             start
@@ -235,17 +761,256 @@ mod tests {
             123
         ";
         let mut reader = StringReader::from(addr);
-        let mut addr = crate::parser::address::parse(&mut reader).unwrap();
+        let addr =
+            crate::parser::address::parse(&mut reader, &mut ParseContext::new(&Default::default()))
+                .unwrap();
+        let count = example.lines().count();
         assert_eq!(
             example
                 .lines()
                 .enumerate()
                 .map(|(i, s)| {
                     let line = Line(i + 1, s.to_string());
-                    (&mut addr).matches(&line)
+                    addr.matches(&line, example.lines().nth(i + 1), i + 1 == count)
                 })
                 .collect::<Vec<bool>>(),
             expected
         )
     }
+
+    #[test_case(
+        "?",
+        vec![true, true, false, false, true, false, true, true, true, false];
+        "changed"
+    )]
+    #[test_case(
+        "?!",
+        vec![false, false, true, true, false, true, false, false, false, true];
+        "not changed"
+    )]
+    #[test_case(
+        "/a/#2",
+        vec![false, false, false, false, true, false, false, false, false, false];
+        "second match"
+    )]
+    #[test_case(
+        "/a/#1",
+        vec![true, false, false, false, false, false, false, false, false, false];
+        "first match"
+    )]
+    #[test_case(
+        "/a/#6",
+        vec![false, false, false, false, false, false, false, false, false, false];
+        "too few matches"
+    )]
+    #[test_case(
+        "$!",
+        vec![true, true, true, true, true, true, true, true, true, false];
+        "all but last"
+    )]
+    #[test_case(
+        "1!",
+        vec![false, true, true, true, true, true, true, true, true, true];
+        "all but first"
+    )]
+    #[test_case(
+        "1,$",
+        vec![true, false, false, false, false, false, false, false, false, true];
+        "first or last"
+    )]
+    #[test_case(
+        "/a/>/b/",
+        vec![true, false, false, false, false, true, false, false, false, false];
+        "followed by"
+    )]
+    #[test_case(
+        "/b/>^$",
+        vec![false, false, false, false, false, false, true, false, false, false];
+        "followed by empty line"
+    )]
+    #[test_case(
+        "/a/>/a/!",
+        vec![true, true, true, true, false, true, true, true, false, true];
+        "not followed by"
+    )]
+    #[test_case(
+        "/A/i",
+        vec![true, false, false, false, true, true, false, false, true, true];
+        "case insensitive"
+    )]
+    #[test_case(
+        "^B$i>/a/",
+        vec![false, false, false, true, false, false, false, false, false, false];
+        "case insensitive followed by"
+    )]
+    #[test_case(
+        "1~2",
+        vec![true, false, true, false, true, false, true, false, true, false];
+        "odd lines"
+    )]
+    #[test_case(
+        "0~3",
+        vec![false, false, true, false, false, true, false, false, true, false];
+        "every third line"
+    )]
+    #[test_case(
+        "4~10",
+        vec![false, false, false, true, false, false, false, false, false, false];
+        "step larger than the input"
+    )]
+    #[test_case(
+        "2-+2",
+        vec![false, true, true, true, false, false, false, false, false, false];
+        "relative end"
+    )]
+    #[test_case(
+        "/a/-+1",
+        vec![true, true, false, false, true, true, false, false, true, true];
+        "relative end retriggered"
+    )]
+    #[test_case(
+        "/b/-+0",
+        vec![false, true, true, true, false, false, true, false, false, false];
+        "zero offset"
+    )]
+    #[test_case(
+        "8-$",
+        vec![false, false, false, false, false, false, false, true, true, true];
+        "range to the last line"
+    )]
+    #[test_case(
+        "/a/-/b/",
+        vec![true, true, false, false, true, true, true, false, true, true];
+        "inclusive end"
+    )]
+    #[test_case(
+        "/a/-</b/",
+        vec![true, false, false, false, true, true, false, false, true, true];
+        "exclusive end"
+    )]
+    #[test_case(
+        "/a/-<+2",
+        vec![true, true, false, false, true, true, false, false, true, true];
+        "exclusive relative end"
+    )]
+    #[test_case(
+        "3-<$",
+        vec![false, false, true, true, true, true, true, true, true, false];
+        "exclusive range to the last line"
+    )]
+    #[test_case(
+        "/a/-/b/#2",
+        vec![true, true, true, false, true, true, true, true, true, true];
+        "inclusive end at second match"
+    )]
+    #[test_case(
+        "/a/-</b/#2",
+        vec![true, true, false, false, true, true, true, true, true, true];
+        "exclusive end at second match"
+    )]
+    #[test_case(
+        "/b/#3-",
+        vec![false, false, false, true, true, true, true, true, true, true];
+        "range starting at third match"
+    )]
+    fn duplicates_example(addr: &str, expected: Vec<bool>) {
+        let example = ["a", "b", "b", "b", "a", "a", "b", "", "a", "a"];
+        let mut reader = StringReader::from(addr);
+        let addr =
+            crate::parser::address::parse(&mut reader, &mut ParseContext::new(&Default::default()))
+                .unwrap();
+        assert_eq!(
+            example
+                .iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    let line = Line(i + 1, s.to_string());
+                    addr.matches(&line, example.get(i + 1).copied(), i + 1 == example.len())
+                })
+                .collect::<Vec<bool>>(),
+            expected
+        )
+    }
+
+    #[test_case("/b/, /a/-/b/", &["a", "x", "b", "x"], vec![true, true, true, false]; "range ends on matched line")]
+    #[test_case("/a/, /a/-/b/", &["a", "b", "x"], vec![true, true, false]; "range starts on matched line")]
+    #[test_case("/x/, (/a/-/b/)!", &["a", "xb", "y"], vec![false, true, true]; "negated range")]
+    #[test_case("/x/, ((/a/-/b/)!, /c/)!", &["a", "xb", "y"], vec![true, true, false]; "nested negated range")]
+    #[test_case("/a/, /b/#2", &["ab", "b", "b"], vec![true, true, false]; "nth match")]
+    #[test_case("/a/, ?", &["a", "a", "b"], vec![true, true, true]; "changed")]
+    #[test_case("/a/, ? !", &["a", "a", "b"], vec![true, true, false]; "negated changed")]
+    fn stateful_in_set(addr: &str, lines: &[&str], expected: Vec<bool>) {
+        let mut reader = StringReader::from(addr);
+        let mut addr =
+            crate::parser::address::parse(&mut reader, &mut ParseContext::new(&Default::default()))
+                .unwrap();
+        for _ in 0..2 {
+            addr.reset();
+            let result = lines
+                .iter()
+                .enumerate()
+                .map(|(i, s)| addr.matches(&Line(i + 1, s.to_string()), None, false))
+                .collect::<Vec<bool>>();
+            assert_eq!(result, expected);
+            // the order of the members does not matter
+            addr.optimize();
+        }
+    }
+
+    #[test]
+    fn regex_set() {
+        let script = (0..50)
+            .map(|i| format!("/^key{}=/i", i))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let mut reader = StringReader::from(script.as_str());
+        let parsed =
+            crate::parser::address::parse(&mut reader, &mut ParseContext::new(&Default::default()))
+                .unwrap();
+        let RegexSet(ref set) = parsed else {
+            panic!("{:?} is not a regex set", parsed)
+        };
+        assert_eq!(set.regexes.len(), 50);
+        let expected = Set(set.regexes.iter().cloned().map(Regex).collect());
+
+        let input = [
+            "key0=a",
+            "KEY7=b",
+            "key49=c",
+            "key50=d",
+            "xkey1=e",
+            "key1 = f",
+            "",
+            "key12=key13=",
+        ];
+        let result = input
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let line = Line(i + 1, s.to_string());
+                let result = parsed.matches(&line, None, false);
+                assert_eq!(result, expected.matches(&line, None, false), "{}", s);
+                result
+            })
+            .collect::<Vec<bool>>();
+        assert_eq!(
+            result,
+            vec![true, true, true, false, false, false, false, true]
+        );
+    }
+
+    #[test_case("2~3"; "step")]
+    #[test_case("5-$"; "range")]
+    #[test_case("/a/-+3"; "relative range")]
+    #[test_case("/a/-</b/"; "exclusive range")]
+    #[test_case("$!"; "negated")]
+    #[test_case("1, $"; "set")]
+    #[test_case("/a/, /b/"; "regex set")]
+    fn last_line_display(addr: &str) {
+        let mut reader = StringReader::from(addr);
+        let parsed =
+            crate::parser::address::parse(&mut reader, &mut ParseContext::new(&Default::default()))
+                .unwrap();
+        assert_eq!(parsed.to_string(), addr);
+    }
 }
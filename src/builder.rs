@@ -0,0 +1,216 @@
+use crate::{address, command::Command, parser, Action, Program};
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// Address of an instruction created with [`Program::builder`],
+/// the equivalent of the addresses in the scripts.
+#[derive(Debug, PartialEq)]
+pub struct Address(address::Address);
+
+impl Address {
+    /// Matches all the lines, like `//` or no address in the scripts.
+    pub fn any() -> Self {
+        Address(address::Address::Always)
+    }
+
+    /// Matches the line with the number `n`, the line numbers start at 1.
+    pub fn line(n: usize) -> Result<Self> {
+        if n == 0 {
+            bail!("invalid address: {}", n);
+        }
+        Ok(Address(address::Address::Location(n)))
+    }
+
+    /// Matches the lines containing a match of the regular expression, like `/pattern/`.
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(Address(address::Address::Regex(crate::Regex::from_str(
+            pattern,
+        )?)))
+    }
+
+    /// Matches the lines starting at the one matching `from` up to the one matching `to`,
+    /// like `from-to` in the scripts.
+    pub fn range(from: Address, to: Address) -> Self {
+        Address(address::Address::Between(address::Between::new(
+            from.0, to.0,
+        )))
+    }
+
+    /// Matches the lines starting at the one matching `from` up to, but excluding,
+    /// the one matching `to`, like `from-<to` in the scripts.
+    pub fn range_exclusive(from: Address, to: Address) -> Self {
+        Address(address::Address::Between(address::Between::exclusive(
+            from.0, to.0,
+        )))
+    }
+
+    /// Matches the lines not matched by the address, like `addr!` in the scripts.
+    pub fn negate(self) -> Self {
+        Address(address::Address::Negate(Box::new(self.0)))
+    }
+}
+
+/// Builds the program from the instructions, without parsing a script.
+#[derive(Debug, Default)]
+pub struct Builder {
+    actions: Vec<Action>,
+}
+
+impl Builder {
+    /// Start a new instruction running the commands on the lines matching the `address`.
+    pub fn on(self, address: Address) -> Instruction {
+        Instruction {
+            builder: self,
+            address: address.0,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Create the program from the instructions.
+    pub fn build(self) -> Program {
+        Program::from(self.actions)
+    }
+}
+
+/// The instruction added to the [`Builder`], the commands are run in the order they were added.
+#[derive(Debug)]
+pub struct Instruction {
+    builder: Builder,
+    address: address::Address,
+    commands: Vec<Command>,
+}
+
+impl Instruction {
+    /// Print the pattern space followed by a newline, like `p`.
+    pub fn print(mut self) -> Self {
+        self.commands.push(Command::Println);
+        self
+    }
+
+    /// Print the line number, like `=`.
+    pub fn line_number(mut self) -> Self {
+        self.commands.push(Command::LineNumber);
+        self
+    }
+
+    /// Print the `text` as-is, like `'text'`.
+    pub fn insert(mut self, text: &str) -> Self {
+        self.commands.push(Command::Insert(text.to_string()));
+        self
+    }
+
+    /// Print the `text` followed by a newline at the end of the cycle, like `a'text'`.
+    pub fn append(mut self, text: &str) -> Self {
+        self.commands.push(Command::AppendAfter(text.to_string()));
+        self
+    }
+
+    /// Replace the matches of the `regex` with the `template`, like `s/regex/template/limit`,
+    /// where the `limit` of 0 replaces all the matches. The `template` is interpreted the same
+    /// as in the scripts, e.g. `$1` is the first group of the `regex`.
+    pub fn substitute(mut self, regex: &str, template: &str, limit: usize) -> Result<Self> {
+        let regex = crate::Regex::from_str(regex)?;
+        let template = parser::parse_template(template)?;
+        self.commands
+            .push(Command::Substitute(regex, template, limit));
+        Ok(self)
+    }
+
+    /// Delete the pattern space and start processing the next line, like `d`.
+    pub fn delete(mut self) -> Self {
+        self.commands.push(Command::Delete);
+        self
+    }
+
+    /// Stop processing the input with the exit `code`, like `q`.
+    pub fn quit(mut self, code: i32) -> Self {
+        self.commands.push(Command::Quit(code, None));
+        self
+    }
+
+    /// Finish this instruction and start the next one.
+    pub fn on(self, address: Address) -> Instruction {
+        self.finish().on(address)
+    }
+
+    /// Finish this instruction and create the program.
+    pub fn build(self) -> Program {
+        self.finish().build()
+    }
+
+    fn finish(self) -> Builder {
+        let mut builder = self.builder;
+        builder
+            .actions
+            .push(Action::Condition(self.address, self.commands.len()));
+        builder
+            .actions
+            .extend(self.commands.into_iter().map(Action::Command));
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Address;
+    use crate::Program;
+    use std::str::FromStr;
+    use test_case::test_case;
+
+    #[test]
+    fn same_as_parsed() {
+        let program = Program::builder()
+            .on(Address::regex("b").unwrap())
+            .substitute("(b)", "[$1]\\n", 1)
+            .unwrap()
+            .print()
+            .on(Address::range(Address::line(2).unwrap(), Address::regex("d").unwrap()).negate())
+            .line_number()
+            .insert("\t")
+            .print()
+            .on(Address::range_exclusive(
+                Address::line(1).unwrap(),
+                Address::regex("c").unwrap(),
+            ))
+            .build();
+        let parsed = Program::from_str(r"/b/ s/(b)/[$1]\n/1p ; (2-/d/)! =tp ; 1-</c/").unwrap();
+        assert_eq!(program, parsed);
+    }
+
+    #[test_case(Program::builder().build(), "a\nb\nc\n"; "empty")]
+    #[test_case(
+        Program::builder().on(Address::line(2).unwrap()).delete().on(Address::any()).print().build(),
+        "a\nc\n";
+        "delete"
+    )]
+    #[test_case(
+        Program::builder().on(Address::regex("b").unwrap()).append("after").quit(0).build(),
+        "after\n";
+        "append and quit"
+    )]
+    fn process(program: Program, expected: &str) {
+        let mut out = Vec::new();
+        program.process("a\nb\nc\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn slash_in_template() {
+        let program = Program::builder()
+            .on(Address::any())
+            .substitute("a", "x/y", 0)
+            .unwrap()
+            .build();
+        assert_eq!(program, Program::from_str(r"s/a/x\/y/").unwrap());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Address::line(0).is_err());
+        assert!(Address::regex("(").is_err());
+        assert!(Program::builder()
+            .on(Address::any())
+            .substitute("(", "x", 0)
+            .is_err());
+    }
+}
@@ -1,6 +1,12 @@
-use crate::{run, Action, Line, Regex};
+use crate::{
+    format_actions,
+    hash::HashKind,
+    lines::Lookahead,
+    output::{OutputSink, SideFile},
+    run, template, Action, Line, Regex, State,
+};
 use anyhow::Result;
-use std::io::Write;
+use std::{borrow::Cow, collections::hash_map::Entry, io::Write, path::PathBuf};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum Command {
@@ -14,22 +20,61 @@ pub(crate) enum Command {
     LineNumber,
     /// "string" or 'string'
     Insert(String),
+    /// a'string'
+    AppendAfter(String),
+    /// i'string'
+    InsertBefore(String),
+    /// c'string'
+    ///
+    /// Delete the line and print the string instead, for a range only once, at its end.
+    Change(String),
     /// s/src/dst/[limit]
     Substitute(Regex, String, usize),
+    /// s/src/dst/[limit]?
+    ///
+    /// Run the substitution only if a previous substitution was applied to the current line.
+    IfSubstituted(Box<Command>),
     /// k s-e
     Keep(usize, Option<usize>),
-    /// h
-    Hold,
-    /// g
-    Get,
-    /// x
-    Exchange,
+    /// y/src/dst/
+    Transliterate(Vec<char>, Vec<char>),
+    /// F/regex/
+    Split(Regex),
+    /// fN[/regex/] s/src/dst/
+    Field(usize, Regex, Box<Command>),
+    /// ~
+    Reverse,
+    /// U
+    Uppercase,
+    /// L
+    Lowercase,
+    /// T[<>]
+    Trim(TrimSide),
+    /// C name
+    Hash(HashKind),
+    /// h[register]
+    Hold(Option<char>),
+    /// g[register]
+    Get(Option<char>),
+    /// x[register]
+    Exchange(Option<char>),
     /// j
     Joinln,
     /// J
     Join,
+    /// A name
+    Append(String),
+    /// w path
+    Write(PathBuf),
     /// r [num]
     Readln(usize),
+    /// r 'path'
+    ReadFile(PathBuf),
+    /// N
+    ///
+    /// The commands are given the input reader, so the next line is consumed
+    /// directly from it. At the end of the input, the rest of the script is skipped.
+    Next,
     /// R
     ReadReplace,
     /// z
@@ -38,57 +83,144 @@ pub(crate) enum Command {
     Delete,
     /// .
     Break,
-    /// q [code]
-    Quit(i32),
+    /// q ['message'] [code]
+    Quit(i32, Option<String>),
     /// e
     Eval,
     /// :{ act }
     Loop(Vec<Action>),
+    /// :name
+    Label(String),
+    /// b name
+    ///
+    /// Continue after the label with the same name, in the same block.
+    Branch(String),
+    /// t name
+    ///
+    /// Branch to the label only if any substitution was applied to the current line
+    /// since processing it started or since the last branch taken by `t`.
+    BranchIfSub(String),
+    /// H{ act }
+    WithHold(Vec<Action>),
 }
 
+/// Which whitespace is removed by the `T` command.
 #[derive(Debug, PartialEq)]
-pub enum Status {
+pub(crate) enum TrimSide {
+    /// T
+    Both,
+    /// T<
+    Start,
+    /// T>
+    End,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Status {
     Normal,
     Break,
     NoPrint,
-    Quit(i32),
+    /// There is no more input, skip the rest of the script
+    EndOfInput,
+    /// Continue after the label
+    Jump(String),
+    /// Exit with the code, printing the message to stderr
+    Quit(i32, Option<String>),
 }
 
 impl From<&Command> for Status {
     fn from(value: &Command) -> Self {
         match value {
-            Command::Delete => Status::NoPrint,
+            Command::Delete | Command::Change(_) => Status::NoPrint,
             Command::Break => Status::Break,
-            Command::Quit(code) => Status::Quit(*code),
+            Command::Quit(code, message) => Status::Quit(*code, message.clone()),
             _ => Status::Normal,
         }
     }
 }
 
 impl Command {
-    /// Run the command by modifying one of the `pattern` or `hold` buffers
+    /// Run the command by modifying the `pattern` space or the `state`
     /// and returning a status code.
-    pub(crate) fn run<R: Iterator<Item = Result<Line>>>(
+    pub(crate) fn run<R: Lookahead>(
         &self,
         pattern: &mut Line,
-        hold: &mut String,
+        state: &mut State,
         reader: &mut R,
+        out: &mut dyn OutputSink,
     ) -> Result<Status> {
         use Command::*;
         match self {
             // commands that print things
-            Println => println!("{}", pattern.1),
-            Print => print!("{}", pattern.1),
-            Escapeln => {
-                let escaped = pattern.1.escape_default().to_string();
-                println!("{}", escaped)
-            }
-            LineNumber => print!("{}", pattern.0),
-            Insert(message) => print!("{}", message),
-            // commands that modify the buffers
+            Println => {
+                match &state.matcher {
+                    Some(regex) if state.only_matching => {
+                        for m in regex.0.find_iter(&pattern.1).filter(|m| !m.is_empty()) {
+                            let text = state.highlight(m.as_str(), true);
+                            out.emit(&format!("{}{}\n", state.prefix(pattern), text))?
+                        }
+                    }
+                    _ => out.emit(&format!(
+                        "{}{}\n",
+                        state.prefix(pattern),
+                        state.highlight(&pattern.1, true)
+                    ))?,
+                }
+                state.printed = true;
+            }
+            Print => out.emit(&format!(
+                "{}{}",
+                state.prefix(pattern),
+                state.highlight(&pattern.1, true)
+            ))?,
+            Escapeln => out.emit(&format!(
+                "{}{}\n",
+                state.prefix(pattern),
+                pattern.1.escape_default()
+            ))?,
+            LineNumber => out.emit(&pattern.0.to_string())?,
+            Insert(message) => out.emit(message)?,
+            InsertBefore(text) => {
+                out.emit(&format!("{}\n", expand_line_number(text, pattern.0)))?
+            }
+            AppendAfter(text) => state
+                .appended
+                .push(format!("{}\n", expand_line_number(text, pattern.0))),
+            Split(regex) => {
+                for field in regex.0.split(&pattern.1) {
+                    out.emit(&format!("{}\n", field))?
+                }
+            }
+            // commands that modify the state
             Substitute(regex, template, limit) => {
-                let replaced = regex.0.replacen(&pattern.1, *limit, template);
-                pattern.1 = replaced.to_string()
+                let mut captures = Cow::Borrowed(&state.captures);
+                if template.contains("${line}") {
+                    // the line before the substitution
+                    captures
+                        .to_mut()
+                        .push(("line".to_string(), pattern.1.clone()));
+                }
+                let template = expand_captures(template, regex, &captures);
+                let (replaced, spans) = replace_counted(regex, &pattern.1, *limit, &template);
+                if !spans.is_empty() {
+                    pattern.1 = replaced.into_owned();
+                    state.substituted = true;
+                    if state.color {
+                        state.replaced = Some((pattern.1.clone(), spans));
+                    }
+                }
+            }
+            IfSubstituted(cmd) => {
+                if state.substituted {
+                    cmd.run(pattern, state, reader, out)?;
+                }
+            }
+            Field(n, delim, cmd) => {
+                if let Some((start, end)) = field_bounds(&pattern.1, *n, delim) {
+                    let mut field = Line(pattern.0, pattern.1[start..end].to_string());
+                    cmd.run(&mut field, state, reader, out)?;
+                    pattern.1.replace_range(start..end, &field.1);
+                }
             }
             Keep(skip, take) => {
                 pattern.1 = if let Some(take) = take {
@@ -97,22 +229,71 @@ impl Command {
                     pattern.1.chars().skip(*skip).collect()
                 };
             }
+            Transliterate(src, dst) => {
+                pattern.1 = pattern
+                    .1
+                    .chars()
+                    .map(|c| src.iter().position(|s| *s == c).map_or(c, |i| dst[i]))
+                    .collect();
+            }
             Reset => pattern.1.clear(),
-            Hold => {
-                *hold = pattern.1.to_string();
+            Reverse => {
+                pattern.1 = pattern.1.chars().rev().collect();
             }
-            Get => {
-                pattern.1 = hold.to_string();
+            Uppercase => pattern.1 = pattern.1.to_uppercase(),
+            Lowercase => pattern.1 = pattern.1.to_lowercase(),
+            Trim(side) => {
+                let trimmed = match side {
+                    TrimSide::Both => pattern.1.trim(),
+                    TrimSide::Start => pattern.1.trim_start(),
+                    TrimSide::End => pattern.1.trim_end(),
+                };
+                if trimmed.len() != pattern.1.len() {
+                    pattern.1 = trimmed.to_string();
+                }
+            }
+            Hash(kind) => {
+                let digest = kind.hex_digest(pattern.1.as_bytes());
+                pattern.1.push('\t');
+                pattern.1.push_str(&digest);
+            }
+            Hold(None) => {
+                state.hold = pattern.1.to_string();
+            }
+            Hold(Some(register)) => {
+                state.registers.insert(*register, pattern.1.to_string());
+            }
+            Get(None) => {
+                pattern.1 = state.hold.to_string();
+            }
+            Get(Some(register)) => {
+                pattern.1 = state.registers.get(register).cloned().unwrap_or_default();
+            }
+            Exchange(None) => {
+                std::mem::swap(&mut state.hold, &mut pattern.1);
             }
-            Exchange => {
-                std::mem::swap(hold, &mut pattern.1);
+            Exchange(Some(register)) => {
+                let buffer = state.registers.entry(*register).or_default();
+                std::mem::swap(buffer, &mut pattern.1);
             }
             Joinln => {
                 pattern.1.push('\n');
-                pattern.1.push_str(hold);
+                pattern.1.push_str(&state.hold);
             }
             Join => {
-                pattern.1.push_str(hold);
+                pattern.1.push_str(&state.hold);
+            }
+            Append(name) => {
+                let buffer = state.named.entry(name.to_string()).or_default();
+                buffer.push_str(&pattern.1);
+                buffer.push('\n');
+            }
+            Write(path) => {
+                let file = match state.files.entry(path.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(SideFile::create(path)?),
+                };
+                writeln!(file, "{}", pattern.1)?;
             }
             Readln(n) => {
                 for _ in 0..*n {
@@ -124,6 +305,26 @@ impl Command {
                     }
                 }
             }
+            ReadFile(path) => {
+                // like in sed, the files that cannot be read are skipped
+                let content = state.read_files.entry(path.clone()).or_insert_with(|| {
+                    std::fs::read(path)
+                        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                        .unwrap_or_default()
+                });
+                if !content.is_empty() {
+                    state.appended.push(content.clone());
+                }
+            }
+            Next => {
+                let Some(line) = reader.next() else {
+                    return Ok(Status::EndOfInput);
+                };
+                let line = line?;
+                pattern.0 = line.0;
+                pattern.1.push('\n');
+                pattern.1.push_str(&line.1);
+            }
             ReadReplace => {
                 if let Some(line) = reader.next() {
                     *pattern = line?;
@@ -136,16 +337,41 @@ impl Command {
                 pattern.1.clear();
                 return Ok(Status::NoPrint);
             }
-            Break | Quit(_) => return Ok(Status::from(self)),
+            Change(text) => {
+                if !state.in_range {
+                    out.emit(&format!("{}\n", expand_line_number(text, pattern.0)))?;
+                }
+                pattern.1.clear();
+                return Ok(Status::NoPrint);
+            }
+            Break | Quit(..) => return Ok(Status::from(self)),
+            Label(_) => (),
+            Branch(label) => return Ok(Status::Jump(label.clone())),
+            BranchIfSub(label) => {
+                if state.substituted {
+                    state.substituted = false;
+                    return Ok(Status::Jump(label.clone()));
+                }
+            }
             Eval => {
                 let (stdout, code) = eval_sh(&pattern.1)?;
                 pattern.1 = stdout;
                 if let Some(code) = code {
-                    return Ok(Status::Quit(code));
+                    return Ok(Status::Quit(code, None));
+                }
+            }
+            WithHold(ref body) => {
+                // the hold space is processed as the pattern space and vice versa
+                std::mem::swap(&mut state.hold, &mut pattern.1);
+                let result = run(body, pattern, state, reader, out);
+                std::mem::swap(&mut state.hold, &mut pattern.1);
+                if let Some(status @ (Status::Quit(..) | Status::EndOfInput)) = result? {
+                    return Ok(status);
                 }
             }
             Loop(ref body) => loop {
-                if let Some(status) = run(body, pattern, hold, reader)? {
+                state.count_iteration(pattern)?;
+                if let Some(status) = run(body, pattern, state, reader, out)? {
                     match status {
                         Status::Normal => (),
                         Status::Break => return Ok(Status::Normal),
@@ -158,6 +384,112 @@ impl Command {
     }
 }
 
+/// Replace the first `limit` (all if 0) matches of the `regex` in the `text`
+/// with the expanded `template`. Returns the result and the byte spans of the replacements in it.
+fn replace_counted<'a>(
+    regex: &Regex,
+    text: &'a str,
+    limit: usize,
+    template: &str,
+) -> (Cow<'a, str>, Vec<(usize, usize)>) {
+    let plain = template::is_plain(template);
+    let mut acc = String::new();
+    let mut last = 0;
+    let mut spans = Vec::new();
+    for caps in regex.0.captures_iter(text) {
+        if limit > 0 && spans.len() == limit {
+            break;
+        }
+        let m = caps.get(0).unwrap();
+        acc.push_str(&text[last..m.start()]);
+        let start = acc.len();
+        if plain {
+            caps.expand(template, &mut acc);
+        } else {
+            acc.push_str(&template::expand(template, &caps));
+        }
+        spans.push((start, acc.len()));
+        last = m.end();
+    }
+    if spans.is_empty() {
+        return (Cow::Borrowed(text), spans);
+    }
+    acc.push_str(&text[last..]);
+    (Cow::Owned(acc), spans)
+}
+
+/// Byte offsets of the `n`-th (starting at 1) field delimited by the `delim` regex.
+fn field_bounds(s: &str, n: usize, delim: &Regex) -> Option<(usize, usize)> {
+    let mut start = 0;
+    let mut delims = delim.0.find_iter(s).filter(|m| !m.is_empty());
+    for _ in 1..n {
+        start = delims.next()?.end();
+    }
+    let end = delims.next().map_or(s.len(), |m| m.start());
+    Some((start, end))
+}
+
+/// Replace the `${name}` groups in the `template` that are not defined by the `regex`
+/// with the values of the `captures` from the address.
+fn expand_captures<'a>(
+    template: &'a str,
+    regex: &Regex,
+    captures: &[(String, String)],
+) -> Cow<'a, str> {
+    if captures.is_empty() {
+        return Cow::Borrowed(template);
+    }
+    let mut acc = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        acc.push(c);
+        if c != '$' {
+            continue;
+        }
+        match chars.peek() {
+            Some('$') => acc.push(chars.next().unwrap()),
+            Some('{') => {
+                chars.next();
+                let name = chars.by_ref().take_while(|c| *c != '}').collect::<String>();
+                let own = regex.0.capture_names().flatten().any(|n| n == name);
+                match captures.iter().find(|(k, _)| *k == name) {
+                    Some((_, value)) if !own => {
+                        acc.pop();
+                        acc.push_str(&value.replace('$', "$$").replace('\\', "\\\\"));
+                    }
+                    _ => {
+                        acc.push('{');
+                        acc.push_str(&name);
+                        acc.push('}');
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Cow::Owned(acc)
+}
+
+/// Replace `%n` in the `text` with the line number `n` and `%%` with `%`.
+fn expand_line_number(text: &str, n: usize) -> Cow<'_, str> {
+    if !text.contains('%') {
+        return Cow::Borrowed(text);
+    }
+    let mut acc = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('%', Some('n')) => {
+                chars.next();
+                acc.push_str(&n.to_string());
+            }
+            ('%', Some('%')) => acc.push(chars.next().unwrap()),
+            _ => acc.push(c),
+        }
+    }
+    Cow::Owned(acc)
+}
+
 fn eval_sh(cmd: &str) -> Result<(String, Option<i32>)> {
     let out = std::process::Command::new("sh")
         .arg("-c")
@@ -175,6 +507,60 @@ fn eval_sh(cmd: &str) -> Result<(String, Option<i32>)> {
     Ok((stdout, code))
 }
 
+/// Escape the backslashes, the `delim`, and the control characters in the `s`,
+/// so it is read back the same.
+fn escape(s: &str, delim: char) -> String {
+    let mut acc = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => acc.push_str("\\\\"),
+            '\n' => acc.push_str("\\n"),
+            '\t' => acc.push_str("\\t"),
+            '\r' => acc.push_str("\\r"),
+            c if c == delim => {
+                acc.push('\\');
+                acc.push(c);
+            }
+            c if c.is_control() => acc.push_str(&format!("\\u{:04x}", c as u32)),
+            c => acc.push(c),
+        }
+    }
+    acc
+}
+
+/// The string in single quotes, as in `'string'`.
+fn quote(s: &str) -> String {
+    format!("'{}'", escape(s, '\''))
+}
+
+/// The digits are written as they are, the other register names are quoted.
+fn format_register(register: char) -> String {
+    if register.is_ascii_digit() {
+        register.to_string()
+    } else {
+        quote(&register.to_string())
+    }
+}
+
+/// Write back the substitution template, where the backslashes are already
+/// escaped and `\U`, `\L`, `\E`, `\u`, `\l` are the case conversions.
+fn escape_template(template: &str) -> String {
+    let mut acc = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                acc.push(c);
+                if let Some(e) = chars.next() {
+                    acc.push(e);
+                }
+            }
+            c => acc.push_str(&escape(&c.to_string(), '/')),
+        }
+    }
+    acc
+}
+
 impl std::fmt::Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use Command::*;
@@ -183,64 +569,227 @@ impl std::fmt::Display for Command {
             Print => write!(f, "P"),
             Escapeln => write!(f, "l"),
             LineNumber => write!(f, "="),
-            Insert(s) => write!(f, "'{}'", s),
-            Substitute(r, t, l) => write!(f, "s/{}/{}/{}", r, t, l),
+            Insert(s) => write!(f, "{}", quote(s)),
+            AppendAfter(s) => write!(f, "a{}", quote(s)),
+            InsertBefore(s) => write!(f, "i{}", quote(s)),
+            Change(s) => write!(f, "c{}", quote(s)),
+            Substitute(r, t, l) => write!(f, "s/{}/{}/{}", r, escape_template(t), l),
+            IfSubstituted(cmd) => write!(f, "{}?", cmd),
             Keep(s, None) => write!(f, "k {}-", s + 1),
             Keep(s, Some(t)) => write!(f, "k {}-{}", s + 1, s + t),
-            Hold => write!(f, "h"),
-            Get => write!(f, "g"),
-            Exchange => write!(f, "x"),
+            Split(r) => write!(f, "F/{}/", r),
+            Transliterate(s, d) => write!(
+                f,
+                "y/{}/{}/",
+                escape(&s.iter().collect::<String>(), '/'),
+                escape(&d.iter().collect::<String>(), '/')
+            ),
+            Field(n, r, c) => write!(f, "f{}/{}/ {}", n, r, c),
+            Reverse => write!(f, "~"),
+            Uppercase => write!(f, "U"),
+            Lowercase => write!(f, "L"),
+            Trim(TrimSide::Both) => write!(f, "T"),
+            Trim(TrimSide::Start) => write!(f, "T<"),
+            Trim(TrimSide::End) => write!(f, "T>"),
+            Hash(kind) => write!(f, "C {}", kind),
+            Hold(None) => write!(f, "h"),
+            Hold(Some(register)) => write!(f, "h{}", format_register(*register)),
+            Get(None) => write!(f, "g"),
+            Get(Some(register)) => write!(f, "g{}", format_register(*register)),
+            Exchange(None) => write!(f, "x"),
+            Exchange(Some(register)) => write!(f, "x{}", format_register(*register)),
             Joinln => write!(f, "j"),
             Join => write!(f, "J"),
+            Append(name) => write!(f, "A {}", name),
+            Write(path) => write!(f, "w {}", path.display()),
             Readln(n) => write!(f, "r {}", n),
+            ReadFile(path) => write!(f, "r {}", quote(&path.to_string_lossy())),
+            Next => write!(f, "N"),
             ReadReplace => write!(f, "R"),
             Reset => write!(f, "z"),
             Delete => write!(f, "d"),
             Break => write!(f, "."),
-            Quit(c) => write!(f, "q {}", c),
+            Quit(c, None) => write!(f, "q {}", c),
+            Quit(c, Some(m)) => write!(f, "q {} {}", quote(m), c),
             Eval => write!(f, "e"),
-            Loop(body) => {
-                let s = body
-                    .iter()
-                    .map(|a| format!("  {}", a))
-                    .collect::<Vec<String>>()
-                    .join("\n");
-                write!(f, ":{{\n{}\n}}", s)
-            }
+            Loop(body) => write!(f, ":{{ {} }}", format_actions(body)),
+            Label(name) => write!(f, ":{}", name),
+            Branch(name) => write!(f, "b {}", name),
+            BranchIfSub(name) => write!(f, "t {}", name),
+            WithHold(body) => write!(f, "H{{ {} }}", format_actions(body)),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Command;
-    use crate::{lines::MockReader, Line};
+    use super::{Command, TrimSide};
+    use crate::{lines::MockReader, Line, Regex, State};
+    use std::str::FromStr;
+    use test_case::test_case;
 
     #[test]
     fn readln() {
-        let example = vec![1, 2, 3, 4, 5];
-        let mut reader = example.iter().map(|n| Ok(Line(*n, n.to_string())));
+        let example = [1, 2, 3, 4, 5];
+        let mut reader = example
+            .iter()
+            .map(|n| Ok(Line(*n, n.to_string())))
+            .peekable();
 
         let mut pattern = Line(0, "start".to_string());
         assert_eq!(pattern.1, "start");
 
         Command::Readln(1)
-            .run(&mut pattern, &mut String::new(), &mut reader)
+            .run(
+                &mut pattern,
+                &mut State::default(),
+                &mut reader,
+                &mut std::io::sink(),
+            )
             .unwrap();
         assert_eq!(pattern.1, "start\n1");
 
         Command::Readln(4)
-            .run(&mut pattern, &mut String::new(), &mut reader)
+            .run(
+                &mut pattern,
+                &mut State::default(),
+                &mut reader,
+                &mut std::io::sink(),
+            )
             .unwrap();
         assert_eq!(pattern.1, "start\n1\n2\n3\n4\n5");
     }
 
+    #[test_case("a", "x", 0, "banana", "bxnxnx", 3; "all")]
+    #[test_case("a", "x", 2, "banana", "bxnxna", 2; "limited")]
+    #[test_case("z", "x", 0, "banana", "banana", 0; "no match")]
+    #[test_case("(a)(n)", "$2$1", 0, "banana", "bnanaa", 2; "groups")]
+    #[test_case("^", ">", 0, "banana", ">banana", 1; "empty match")]
+    #[test_case("a", "a", 0, "banana", "banana", 3; "same text")]
+    #[test_case("n", "${url: }", 1, "banana", "ba%20ana", 1; "function")]
+    fn replace_counted(
+        regex: &str,
+        template: &str,
+        limit: usize,
+        input: &str,
+        expected: &str,
+        count: usize,
+    ) {
+        let regex = Regex::from_str(regex).unwrap();
+        let (result, spans) = super::replace_counted(&regex, input, limit, template);
+        assert_eq!((result.as_ref(), spans.len()), (expected, count));
+    }
+
+    #[test_case("s/a/<$0>/p", "banana", "b\x1b[01;31m<a>\x1b[0mn\x1b[01;31m<a>\x1b[0mn\x1b[01;31m<a>\x1b[0m\n"; "replaced")]
+    #[test_case("/n/ p", "banana", "ba\x1b[01;31mn\x1b[0ma\x1b[01;31mn\x1b[0ma\n"; "address")]
+    #[test_case("/n/ s/b/B/ p", "banana", "\x1b[01;31mB\x1b[0manana\n"; "replaced before address")]
+    #[test_case("s/b/B/ ; y/a/A/ ; /n/ P", "banana", "BA\x1b[01;31mn\x1b[0mA\x1b[01;31mn\x1b[0mA"; "changed after replacement")]
+    #[test_case("s/^//p", "banana", "banana\n"; "empty replacement")]
+    #[test_case("2 p", "banana", "banana\n"; "no regex")]
+    fn color(script: &str, input: &str, expected: &str) {
+        let program = crate::Program::from_str(script).unwrap();
+        let mut out = Vec::new();
+        let mut pattern = Line(2, input.to_string());
+        let mut state = State {
+            color: true,
+            ..Default::default()
+        };
+        crate::run(
+            &program.actions,
+            &mut pattern,
+            &mut state,
+            &mut MockReader {},
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test_case("id", "${line}->$0", "id=id", "id=id->id=id=id->id"; "whole line")]
+    #[test_case("(?P<line>d)", "${line}", "id", "id"; "group takes precedence")]
+    #[test_case("i", "$${line}", "id", "${line}d"; "escaped")]
+    #[test_case("a", "${line}", "a$1", "a$1$1"; "dollar in line")]
+    #[test_case("a", r"\U${line}", r"a\u", r"A\U\u"; "backslash in line")]
+    fn substitute_line(regex: &str, template: &str, input: &str, expected: &str) {
+        let cmd = Command::Substitute(Regex::from_str(regex).unwrap(), template.to_string(), 0);
+        let mut pattern = Line(0, input.to_string());
+        cmd.run(
+            &mut pattern,
+            &mut State::default(),
+            &mut MockReader {},
+            &mut std::io::sink(),
+        )
+        .unwrap();
+        assert_eq!(pattern.1, expected);
+    }
+
+    #[test_case("s/a/A/ s/b/B/?", "ab", "AB"; "after substitution")]
+    #[test_case("s/x/X/ s/b/B/?", "ab", "ab"; "after no substitution")]
+    #[test_case("s/b/B/?", "ab", "ab"; "no previous substitution")]
+    #[test_case("s/x/X/ ; s/a/a/ ; s/b/B/?", "ab", "aB"; "replaced with the same text")]
+    #[test_case("s/a/A/ ; /x/ s/b/B/? ; s/A/-/?", "ab", "-b"; "previous instructions")]
+    #[test_case("f2 s/c/C/ ; s/a/A/?", "a c", "A C"; "in field")]
+    #[test_case("s/a/A/ s/b/B/? s/A/a/?", "ab", "aB"; "chained")]
+    fn if_substituted(script: &str, input: &str, expected: &str) {
+        let program = crate::Program::from_str(script).unwrap();
+        let mut pattern = Line(1, input.to_string());
+        crate::run(
+            &program.actions,
+            &mut pattern,
+            &mut State::default(),
+            &mut MockReader {},
+            &mut std::io::sink(),
+        )
+        .unwrap();
+        assert_eq!(pattern.1, expected);
+    }
+
+    #[test_case("a'line %n'", "line 1\nline 2\nline 3\n"; "append")]
+    #[test_case("2 i'line %n'", "line 2\n"; "insert")]
+    #[test_case("1-2 c'until %n'", "until 2\n"; "change")]
+    #[test_case("a'%n%%n %%% %x'", "1%n %% %x\n2%n %% %x\n3%n %% %x\n"; "escaped")]
+    #[test_case("'%n' a''", "%n\n%n\n%n\n"; "not in strings")]
+    fn line_number_in_text(script: &str, expected: &str) {
+        let program = crate::Program::from_str(script).unwrap();
+        let mut out = Vec::new();
+        program.process("a\nb\nc\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test_case(2, ",", "a,bab,a", "a,bxb,a"; "middle field")]
+    #[test_case(1, ",", "a,a", "x,a"; "first field")]
+    #[test_case(3, ",", "a,a,a", "a,a,x"; "last field")]
+    #[test_case(2, ",", ",a,", ",x,"; "empty fields")]
+    #[test_case(4, ",", "a,a,a", "a,a,a"; "missing field")]
+    #[test_case(2, r"\s+", "a  a\ta", "a  x\ta"; "preserve delimiters")]
+    fn field(n: usize, delim: &str, input: &str, expected: &str) {
+        let substitute = Command::Substitute(Regex::from_str("a").unwrap(), "x".to_string(), 0);
+        let cmd = Command::Field(n, Regex::from_str(delim).unwrap(), Box::new(substitute));
+        let mut pattern = Line(0, input.to_string());
+        cmd.run(
+            &mut pattern,
+            &mut State::default(),
+            &mut MockReader {},
+            &mut std::io::sink(),
+        )
+        .unwrap();
+        assert_eq!(pattern.1, expected);
+    }
+
     #[test]
     fn join() {
         let mut pattern = Line(0, "one".to_string());
-        let mut hold = "two".to_string();
+        let mut state = State {
+            hold: "two".to_string(),
+            ..Default::default()
+        };
         Command::Join
-            .run(&mut pattern, &mut hold, &mut MockReader {})
+            .run(
+                &mut pattern,
+                &mut state,
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
             .unwrap();
         assert_eq!(pattern.1, "onetwo");
     }
@@ -248,9 +797,17 @@ mod tests {
     #[test]
     fn joinln() {
         let mut pattern = Line(0, "one".to_string());
-        let mut hold = "two".to_string();
+        let mut state = State {
+            hold: "two".to_string(),
+            ..Default::default()
+        };
         Command::Joinln
-            .run(&mut pattern, &mut hold, &mut MockReader {})
+            .run(
+                &mut pattern,
+                &mut state,
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
             .unwrap();
         assert_eq!(pattern.1, "one\ntwo");
     }
@@ -258,11 +815,222 @@ mod tests {
     #[test]
     fn exchange() {
         let mut pattern = Line(0, "one".to_string());
-        let mut hold = "two".to_string();
-        Command::Exchange
-            .run(&mut pattern, &mut hold, &mut MockReader {})
+        let mut state = State {
+            hold: "two".to_string(),
+            ..Default::default()
+        };
+        Command::Exchange(None)
+            .run(
+                &mut pattern,
+                &mut state,
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
             .unwrap();
         assert_eq!(pattern.1, "two");
-        assert_eq!(hold, "one");
+        assert_eq!(state.hold, "one");
+    }
+
+    #[test]
+    fn exchange_registers() {
+        let mut state = State::default();
+        let mut exchange = |value: &str, register| {
+            let mut pattern = Line(0, value.to_string());
+            Command::Exchange(register)
+                .run(
+                    &mut pattern,
+                    &mut state,
+                    &mut MockReader {},
+                    &mut std::io::sink(),
+                )
+                .unwrap();
+            pattern.1
+        };
+        assert_eq!(exchange("one", Some('1')), "");
+        assert_eq!(exchange("two", Some('2')), "");
+        assert_eq!(exchange("hold", None), "");
+        assert_eq!(exchange("a", Some('1')), "one");
+        assert_eq!(exchange("b", Some('2')), "two");
+        assert_eq!(exchange("c", None), "hold");
+        assert_eq!(exchange("d", Some('2')), "b");
+        assert_eq!(exchange("e", Some('1')), "a");
+    }
+
+    #[test]
+    fn hold_and_get_registers() {
+        let mut state = State::default();
+        let mut run = |command: Command, value: &str| {
+            let mut pattern = Line(0, value.to_string());
+            command
+                .run(
+                    &mut pattern,
+                    &mut state,
+                    &mut MockReader {},
+                    &mut std::io::sink(),
+                )
+                .unwrap();
+            pattern.1
+        };
+        run(Command::Hold(Some('1')), "one");
+        run(Command::Hold(Some('2')), "two");
+        run(Command::Hold(None), "hold");
+        assert_eq!(run(Command::Get(Some('1')), ""), "one");
+        assert_eq!(run(Command::Get(Some('2')), ""), "two");
+        assert_eq!(run(Command::Get(None), ""), "hold");
+        assert_eq!(run(Command::Get(Some('3')), "unset"), "");
+
+        run(Command::Hold(Some('2')), "three");
+        assert_eq!(run(Command::Exchange(Some('1')), "x"), "one");
+        assert_eq!(run(Command::Get(Some('1')), ""), "x");
+        assert_eq!(run(Command::Get(Some('2')), ""), "three");
+        assert_eq!(run(Command::Get(None), ""), "hold");
+
+        run(Command::Hold(Some('a')), "first");
+        run(Command::Hold(Some('b')), "second");
+        assert_eq!(run(Command::Get(Some('a')), ""), "first");
+        assert_eq!(run(Command::Get(Some('b')), ""), "second");
+    }
+
+    #[test]
+    fn registers_and_named_buffers() {
+        let mut state = State::default();
+        for (command, value) in [
+            (Command::Append("1".to_string()), "buffer"),
+            (Command::Hold(Some('1')), "register"),
+        ] {
+            command
+                .run(
+                    &mut Line(0, value.to_string()),
+                    &mut state,
+                    &mut MockReader {},
+                    &mut std::io::sink(),
+                )
+                .unwrap();
+        }
+        assert_eq!(state.named["1"], "buffer\n");
+        assert_eq!(state.registers[&'1'], "register");
+    }
+
+    #[test_case("abc", "cba"; "ascii")]
+    #[test_case("żółw 🐢", "🐢 włóż"; "multibyte")]
+    #[test_case("", ""; "empty")]
+    fn reverse(input: &str, expected: &str) {
+        let mut pattern = Line(0, input.to_string());
+        Command::Reverse
+            .run(
+                &mut pattern,
+                &mut State::default(),
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
+            .unwrap();
+        assert_eq!(pattern.1, expected);
+    }
+
+    #[test_case(Command::Uppercase, "Grüße", "GRÜSSE"; "uppercase sharp s")]
+    #[test_case(Command::Uppercase, "żółw 🐢", "ŻÓŁW 🐢"; "uppercase multibyte")]
+    #[test_case(Command::Lowercase, "GRÜSSE", "grüsse"; "lowercase")]
+    #[test_case(Command::Lowercase, "ΟΔΟΣ", "οδος"; "lowercase final sigma")]
+    #[test_case(Command::Uppercase, "", ""; "empty")]
+    fn case(command: Command, input: &str, expected: &str) {
+        let mut pattern = Line(0, input.to_string());
+        command
+            .run(
+                &mut pattern,
+                &mut State::default(),
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
+            .unwrap();
+        assert_eq!(pattern.1, expected);
+    }
+
+    #[test_case("s/a/A/", "line", "Abc"; "substitute")]
+    #[test_case("x", "abc", "line"; "exchange")]
+    #[test_case("/x/ z ; s/$/!/", "line", "abc!"; "address")]
+    #[test_case("d ; s/^/x/", "line", ""; "delete")]
+    #[test_case(". ; s/^/x/", "line", "abc"; "stop")]
+    fn with_hold(script: &str, expected_pattern: &str, expected_hold: &str) {
+        let program = crate::Program::from_str(script).unwrap();
+        let mut pattern = Line(1, "line".to_string());
+        let mut state = State {
+            hold: "abc".to_string(),
+            ..Default::default()
+        };
+        let status = Command::WithHold(program.actions)
+            .run(
+                &mut pattern,
+                &mut state,
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
+            .unwrap();
+        assert_eq!(status, crate::Status::Normal);
+        assert_eq!(pattern.1, expected_pattern);
+        assert_eq!(state.hold, expected_hold);
+    }
+
+    #[test_case("abc", "ABC", "cab", "CAB"; "letters")]
+    #[test_case("ab", "ba", "abba", "baab"; "swap")]
+    #[test_case("ó\n", "o ", "zółw\nżółw", "zołw żołw"; "multibyte and newline")]
+    fn transliterate(src: &str, dst: &str, input: &str, expected: &str) {
+        let mut pattern = Line(0, input.to_string());
+        Command::Transliterate(src.chars().collect(), dst.chars().collect())
+            .run(
+                &mut pattern,
+                &mut State::default(),
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
+            .unwrap();
+        assert_eq!(pattern.1, expected);
+    }
+
+    #[test_case(TrimSide::Both, " \t a b \t", "a b"; "both")]
+    #[test_case(TrimSide::Start, " \t a b \t", "a b \t"; "start")]
+    #[test_case(TrimSide::End, " \t a b \t", " \t a b"; "end")]
+    #[test_case(TrimSide::Both, "  ", ""; "only whitespace")]
+    fn trim(side: TrimSide, input: &str, expected: &str) {
+        let mut pattern = Line(0, input.to_string());
+        Command::Trim(side)
+            .run(
+                &mut pattern,
+                &mut State::default(),
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
+            .unwrap();
+        assert_eq!(pattern.1, expected);
+    }
+
+    #[cfg(any(feature = "crc", feature = "sha2"))]
+    fn hash(kind: crate::hash::HashKind, input: &str) -> String {
+        let mut pattern = Line(0, input.to_string());
+        Command::Hash(kind)
+            .run(
+                &mut pattern,
+                &mut State::default(),
+                &mut MockReader {},
+                &mut std::io::sink(),
+            )
+            .unwrap();
+        pattern.1
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn hash_crc32() {
+        let result = hash(crate::hash::HashKind::Crc32, "123456789");
+        assert_eq!(result, "123456789\tcbf43926");
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn hash_sha256() {
+        let result = hash(crate::hash::HashKind::Sha256, "abc");
+        assert_eq!(
+            result,
+            "abc\tba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
     }
 }
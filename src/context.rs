@@ -0,0 +1,69 @@
+use crate::{Line, OutputSink};
+use anyhow::Result;
+use std::collections::VecDeque;
+
+/// Prints the lines surrounding the matched lines, like `grep -C`.
+#[derive(Debug)]
+pub(crate) struct Context {
+    before: usize,
+    after: usize,
+    /// The recent lines that were not matched, printed if followed by a match
+    buffer: VecDeque<Line>,
+    /// How many of the following lines are still printed after the match
+    remaining: usize,
+    /// The number of the last printed line
+    last: Option<usize>,
+    /// The line printed between the non-adjacent groups of lines
+    separator: Option<String>,
+}
+
+impl Context {
+    pub(crate) fn new(before: usize, after: usize, separator: Option<String>) -> Self {
+        Context {
+            before,
+            after,
+            buffer: VecDeque::with_capacity(before),
+            remaining: 0,
+            last: None,
+            separator,
+        }
+    }
+
+    /// Print the lines preceding the matched line, separating them with the separator
+    /// from the previously printed lines if there is a gap between them.
+    /// The `first` and `last` are the numbers of the lines processed in the cycle.
+    pub(crate) fn matched(
+        &mut self,
+        first: usize,
+        last: usize,
+        out: &mut dyn OutputSink,
+    ) -> Result<()> {
+        let start = self.buffer.front().map_or(first, |line| line.0);
+        if let Some(separator) = &self.separator {
+            if self.last.is_some_and(|n| start > n + 1) {
+                out.emit(&format!("{}\n", separator))?;
+            }
+        }
+        for line in self.buffer.drain(..) {
+            out.emit(&format!("{}\n", line.1))?;
+        }
+        self.last = Some(last);
+        self.remaining = self.after;
+        Ok(())
+    }
+
+    /// Print the line not matched if it follows closely a match, otherwise remember it.
+    pub(crate) fn unmatched(&mut self, line: Line, out: &mut dyn OutputSink) -> Result<()> {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.last = Some(line.0);
+            out.emit(&format!("{}\n", line.1))?;
+        } else if self.before > 0 {
+            if self.buffer.len() == self.before {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(line);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+/// The checksums computed by the `C` command, available when
+/// the `crc` or `sha2` features are enabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HashKind {
+    /// C crc32
+    #[cfg(feature = "crc")]
+    Crc32,
+    /// C sha256
+    #[cfg(feature = "sha2")]
+    Sha256,
+}
+
+impl HashKind {
+    /// The lowercase hexadecimal digest of the `data`.
+    pub(crate) fn hex_digest(&self, #[allow(unused_variables)] data: &[u8]) -> String {
+        match *self {
+            #[cfg(feature = "crc")]
+            HashKind::Crc32 => format!("{:08x}", crc32fast::hash(data)),
+            #[cfg(feature = "sha2")]
+            HashKind::Sha256 => {
+                use sha2::Digest;
+                sha2::Sha256::digest(data)
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for HashKind {
+    fn fmt(&self, #[allow(unused_variables)] f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            #[cfg(feature = "crc")]
+            HashKind::Crc32 => write!(f, "crc32"),
+            #[cfg(feature = "sha2")]
+            HashKind::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "crc", feature = "sha2")))]
+mod tests {
+    use super::HashKind;
+    use test_case::test_case;
+
+    #[cfg(feature = "crc")]
+    #[test_case("", "00000000"; "empty")]
+    #[test_case("a", "e8b7be43"; "single")]
+    #[test_case("123456789", "cbf43926"; "check value")]
+    #[test_case("The quick brown fox jumps over the lazy dog", "414fa339"; "sentence")]
+    fn crc32(input: &str, expected: &str) {
+        assert_eq!(HashKind::Crc32.hex_digest(input.as_bytes()), expected);
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test_case("", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"; "empty")]
+    #[test_case("abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"; "abc")]
+    #[test_case(
+        "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1";
+        "two blocks"
+    )]
+    #[test_case("żółw", "f36b811094f4408ff06897bb585bbd814e42bb0463782c8b0b4d28d9ca8e2591"; "unicode")]
+    fn sha256(input: &str, expected: &str) {
+        assert_eq!(HashKind::Sha256.hex_digest(input.as_bytes()), expected);
+    }
+}
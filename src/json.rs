@@ -0,0 +1,443 @@
+use crate::{
+    address::Address,
+    command::{Command, TrimSide},
+    program::Program,
+    Action,
+};
+use std::fmt::Write;
+
+/// Minimal JSON document used for exporting the parsed programs.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    fn typed<const N: usize>(kind: &str, fields: [(&str, Json); N]) -> Json {
+        let mut obj = vec![("type".to_string(), Json::from(kind))];
+        obj.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+        Json::Object(obj)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Number(value as i64)
+    }
+}
+
+impl From<&crate::Regex> for Json {
+    fn from(value: &crate::Regex) -> Self {
+        Json::from(value.0.as_str())
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    item.fmt(f)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    f.write_char(':')?;
+                    value.fmt(f)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl From<&Program> for Json {
+    fn from(value: &Program) -> Self {
+        Json::object([
+            ("instructions", instructions(&value.actions)),
+            ("final", instructions(&value.finally)),
+        ])
+    }
+}
+
+/// Group the flat list of actions into instructions with the address and the commands.
+fn instructions(actions: &[Action]) -> Json {
+    let mut acc = Vec::new();
+    let mut iter = actions.iter();
+    while let Some(action) = iter.next() {
+        let Action::Condition(addr, len) = action else {
+            unreachable!()
+        };
+        let commands = iter
+            .by_ref()
+            .take(*len)
+            .map(|a| match a {
+                Action::Command(cmd) => Json::from(cmd),
+                Action::Condition(..) => unreachable!(),
+            })
+            .collect();
+        acc.push(Json::object([
+            ("address", Json::from(addr)),
+            ("commands", Json::Array(commands)),
+        ]));
+    }
+    Json::Array(acc)
+}
+
+impl From<&Address> for Json {
+    fn from(value: &Address) -> Self {
+        use Address::*;
+        match value {
+            Always => Json::typed("always", []),
+            Implicit => Json::typed("implicit", []),
+            Final => Json::typed("last", []),
+            Location(n) => Json::typed("location", [("line", Json::from(*n))]),
+            Percent(this) => {
+                Json::typed("percent", [("percent", Json::from(this.percent as usize))])
+            }
+            Step { first, step } => Json::typed(
+                "step",
+                [("first", Json::from(*first)), ("step", Json::from(*step))],
+            ),
+            Regex(regex) => Json::typed("regex", [("regex", Json::from(regex))]),
+            NthMatch(this) => Json::typed(
+                "nth_match",
+                [
+                    ("regex", Json::from(&this.regex)),
+                    ("n", Json::from(this.n)),
+                ],
+            ),
+            CountAtLeast { regex, n } => Json::typed(
+                "count_at_least",
+                [("regex", Json::from(regex)), ("n", Json::from(*n))],
+            ),
+            FollowedBy(lhs, rhs) => Json::typed(
+                "followed_by",
+                [("regex", Json::from(lhs)), ("next", Json::from(rhs))],
+            ),
+            LineNumberRegex(regex) => {
+                Json::typed("line_number_regex", [("regex", Json::from(regex))])
+            }
+            Negate(addr) => Json::typed("negate", [("address", Json::from(addr.as_ref()))]),
+            Between(this) => Json::typed(
+                if this.exclusive {
+                    "exclusive_range"
+                } else {
+                    "range"
+                },
+                [
+                    ("from", Json::from(this.lhs.as_ref())),
+                    ("to", Json::from(this.rhs.as_ref())),
+                ],
+            ),
+            Set(addrs) => Json::typed(
+                "set",
+                [(
+                    "addresses",
+                    Json::Array(addrs.iter().map(Json::from).collect()),
+                )],
+            ),
+            RegexSet(this) => Json::typed(
+                "set",
+                [(
+                    "addresses",
+                    Json::Array(
+                        this.regexes
+                            .iter()
+                            .map(|r| Json::typed("regex", [("regex", Json::from(r))]))
+                            .collect(),
+                    ),
+                )],
+            ),
+            Changed(_) => Json::typed("changed", []),
+            Indent { op, n, tab_width } => {
+                let op = match op {
+                    std::cmp::Ordering::Less => "<",
+                    std::cmp::Ordering::Equal => "=",
+                    std::cmp::Ordering::Greater => ">",
+                };
+                Json::typed(
+                    "indent",
+                    [
+                        ("op", Json::from(op)),
+                        ("n", Json::from(*n)),
+                        ("tab_width", Json::from(*tab_width)),
+                    ],
+                )
+            }
+            Maybe => Json::typed("maybe", []),
+            Offset(n) => Json::typed("offset", [("lines", Json::from(*n))]),
+        }
+    }
+}
+
+impl From<&Command> for Json {
+    fn from(value: &Command) -> Self {
+        use Command::*;
+        let name = match value {
+            Println => "p",
+            Print => "P",
+            Escapeln => "l",
+            LineNumber => "=",
+            Insert(_) => "string",
+            AppendAfter(_) => "a",
+            InsertBefore(_) => "i",
+            Change(_) => "c",
+            Substitute(..) => "s",
+            IfSubstituted(_) => "?",
+            Keep(..) => "k",
+            Split(_) => "F",
+            Transliterate(..) => "y",
+            Field(..) => "f",
+            Reverse => "~",
+            Uppercase => "U",
+            Lowercase => "L",
+            Trim(_) => "T",
+            Hash(_) => "C",
+            Hold(_) => "h",
+            Get(_) => "g",
+            Exchange(_) => "x",
+            Joinln => "j",
+            Join => "J",
+            Append(_) => "A",
+            Write(_) => "w",
+            Readln(_) | ReadFile(_) => "r",
+            Next => "N",
+            ReadReplace => "R",
+            Reset => "z",
+            Delete => "d",
+            Break => ".",
+            Quit(..) => "q",
+            Eval => "e",
+            Loop(_) | Label(_) => ":",
+            Branch(_) => "b",
+            BranchIfSub(_) => "t",
+            WithHold(_) => "H",
+        };
+        let mut obj = vec![("command".to_string(), Json::from(name))];
+        let mut push = |key: &str, value: Json| obj.push((key.to_string(), value));
+        match value {
+            Insert(text) | AppendAfter(text) | InsertBefore(text) | Change(text) => {
+                push("text", Json::from(text.as_str()))
+            }
+            Substitute(regex, template, limit) => {
+                push("regex", Json::from(regex));
+                push("template", Json::from(template.as_str()));
+                push("limit", Json::from(*limit));
+            }
+            Keep(skip, take) => {
+                push("start", Json::from(skip + 1));
+                push("length", take.map_or(Json::Null, Json::from));
+            }
+            Split(regex) => push("regex", Json::from(regex)),
+            Transliterate(src, dst) => {
+                push("source", Json::String(src.iter().collect()));
+                push("target", Json::String(dst.iter().collect()));
+            }
+            Trim(side) => {
+                let side = match side {
+                    TrimSide::Both => "both",
+                    TrimSide::Start => "start",
+                    TrimSide::End => "end",
+                };
+                push("side", Json::from(side))
+            }
+            Hash(kind) => push("hash", Json::String(kind.to_string())),
+            IfSubstituted(cmd) => push("apply", Json::from(cmd.as_ref())),
+            Field(n, delim, cmd) => {
+                push("field", Json::from(*n));
+                push("delimiter", Json::from(delim));
+                push("apply", Json::from(cmd.as_ref()));
+            }
+            Append(name) => push("name", Json::from(name.as_str())),
+            Label(name) | Branch(name) | BranchIfSub(name) => {
+                push("label", Json::from(name.as_str()))
+            }
+            Write(path) => push("path", Json::from(path.to_string_lossy().as_ref())),
+            Readln(n) => push("count", Json::from(*n)),
+            Hold(Some(register)) | Get(Some(register)) | Exchange(Some(register)) => {
+                push("register", Json::from(register.to_string().as_str()))
+            }
+            ReadFile(path) => push("path", Json::from(path.to_string_lossy().as_ref())),
+            Quit(code, message) => {
+                push("code", Json::Number(*code as i64));
+                push("message", message.as_deref().map_or(Json::Null, Json::from));
+            }
+            Loop(body) | WithHold(body) => push("body", instructions(body)),
+            _ => (),
+        }
+        Json::Object(obj)
+    }
+}
+
+impl Program {
+    /// Serialize the parsed program to JSON, e.g. for external tools.
+    pub fn to_json(&self) -> String {
+        Json::from(self).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+    use crate::Program;
+    use std::{iter::Peekable, str::Chars, str::FromStr};
+    use test_case::test_case;
+
+    fn parse(s: &str) -> Json {
+        let mut chars = s.chars().peekable();
+        let json = parse_value(&mut chars);
+        assert_eq!(chars.next(), None);
+        json
+    }
+
+    fn parse_value(chars: &mut Peekable<Chars>) -> Json {
+        match chars.peek().unwrap() {
+            'n' => {
+                assert_eq!(chars.by_ref().take(4).collect::<String>(), "null");
+                Json::Null
+            }
+            '"' => Json::String(parse_string(chars)),
+            '[' => {
+                chars.next();
+                let mut items = Vec::new();
+                while chars.peek() != Some(&']') {
+                    items.push(parse_value(chars));
+                    chars.next_if_eq(&',');
+                }
+                chars.next();
+                Json::Array(items)
+            }
+            '{' => {
+                chars.next();
+                let mut fields = Vec::new();
+                while chars.peek() != Some(&'}') {
+                    let key = parse_string(chars);
+                    assert_eq!(chars.next(), Some(':'));
+                    fields.push((key, parse_value(chars)));
+                    chars.next_if_eq(&',');
+                }
+                chars.next();
+                Json::Object(fields)
+            }
+            _ => {
+                let mut num = String::new();
+                while let Some(c) = chars.next_if(|c| *c == '-' || c.is_ascii_digit()) {
+                    num.push(c);
+                }
+                Json::Number(num.parse().unwrap())
+            }
+        }
+    }
+
+    fn parse_string(chars: &mut Peekable<Chars>) -> String {
+        assert_eq!(chars.next(), Some('"'));
+        let mut acc = String::new();
+        loop {
+            match chars.next().unwrap() {
+                '"' => return acc,
+                '\\' => match chars.next().unwrap() {
+                    'n' => acc.push('\n'),
+                    't' => acc.push('\t'),
+                    'r' => acc.push('\r'),
+                    'u' => {
+                        let hex = chars.by_ref().take(4).collect::<String>();
+                        acc.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap())
+                    }
+                    c => acc.push(c),
+                },
+                c => acc.push(c),
+            }
+        }
+    }
+
+    fn get<'a>(json: &'a Json, key: &str) -> &'a Json {
+        let Json::Object(fields) = json else {
+            panic!("{} is not an object", json)
+        };
+        &fields.iter().find(|(k, _)| k == key).unwrap().1
+    }
+
+    fn index(json: &Json, i: usize) -> &Json {
+        let Json::Array(items) = json else {
+            panic!("{} is not an array", json)
+        };
+        &items[i]
+    }
+
+    #[test]
+    fn substitute_in_set() {
+        let program = Program::from_str("1,3s/a/b/g").unwrap();
+        let json = parse(&program.to_json());
+        assert_eq!(json, Json::from(&program));
+
+        let instruction = index(get(&json, "instructions"), 0);
+        let address = get(instruction, "address");
+        assert_eq!(get(address, "type"), &Json::from("set"));
+        let addresses = get(address, "addresses");
+        assert_eq!(get(index(addresses, 0), "line"), &Json::from(1));
+        assert_eq!(get(index(addresses, 1), "line"), &Json::from(3));
+
+        let command = index(get(instruction, "commands"), 0);
+        assert_eq!(get(command, "command"), &Json::from("s"));
+        assert_eq!(get(command, "regex"), &Json::from("a"));
+        assert_eq!(get(command, "template"), &Json::from("b"));
+        assert_eq!(get(command, "limit"), &Json::from(0));
+        assert_eq!(get(&json, "final"), &Json::Array(Vec::new()));
+    }
+
+    #[test_case(r#"/"\d"/ :{ "a\n\t\u0001" k2- q'stop' 1 } ; $ { ? F/,/ }"#; "nested")]
+    #[test_case("indent>4 f2/;/ s/x/y/ ; 2-$! r3 A buf ; /a/>^b$ p ; 1-</x/ p"; "fields and ranges")]
+    fn round_trip(script: &str) {
+        let program = Program::from_str(script).unwrap();
+        assert_eq!(parse(&program.to_json()), Json::from(&program));
+    }
+}
@@ -1,18 +1,148 @@
 mod address;
+mod builder;
 mod command;
+mod context;
+mod hash;
+mod json;
 mod lines;
+mod output;
 mod parser;
 mod program;
+mod repl;
+mod template;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use command::Status;
+#[cfg(feature = "gzip")]
+pub use lines::gzip::is_compressed;
 pub use {
-    command::Status,
-    lines::{FilesReader, Line, StdinReader},
-    program::Program,
+    builder::{Address, Builder, Instruction},
+    lines::{FilesReader, Line, StdinReader, Timeout},
+    output::{Output, OutputSink},
+    parser::{ParseOptions, ParseStats, GRAMMAR},
+    program::{Outcome, Program, Session, Summary},
+    repl::repl,
 };
 
 #[derive(Debug, Clone)]
-pub(crate) struct Regex(regex::Regex);
+pub(crate) struct Regex(std::sync::Arc<regex::Regex>);
+
+/// The ANSI escape codes around the highlighted matches, bold red like in `grep --color`.
+const COLOR_START: &str = "\x1b[01;31m";
+const COLOR_END: &str = "\x1b[0m";
+
+/// The state shared by the commands when processing the input.
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    /// The hold space
+    pub(crate) hold: String,
+    /// The named buffers
+    pub(crate) named: std::collections::HashMap<String, String>,
+    /// The registers used by `h`, `g`, and `x` instead of the hold space
+    pub(crate) registers: std::collections::HashMap<char, String>,
+    /// The files written by `w`, opened when writing to them for the first time
+    pub(crate) files: std::collections::HashMap<std::path::PathBuf, output::SideFile>,
+    /// Named groups captured by the address of the current instruction
+    pub(crate) captures: Vec<(String, String)>,
+    /// Track which instruction changed the line and prefix the printed lines with it
+    pub(crate) annotate: bool,
+    /// Index of the instruction that last changed the current line
+    pub(crate) changed_by: Option<usize>,
+    /// Any substitution was applied to the current line, checked by `s/src/dst/?`
+    pub(crate) substituted: bool,
+    /// The current line was printed by `p`, so it is not printed again when printing all the lines
+    pub(crate) printed: bool,
+    /// Prefix the printed lines with the line number and a tab
+    pub(crate) number_tab: bool,
+    /// Limit of the loop iterations per line
+    pub(crate) max_iterations: Option<usize>,
+    /// Number of the loop iterations for the current line
+    pub(crate) iterations: usize,
+    /// Text to be printed after the current line, added by `a` and `r 'path'`
+    pub(crate) appended: Vec<String>,
+    /// Content of the files read by `r 'path'`, empty if they could not be read
+    pub(crate) read_files: std::collections::HashMap<std::path::PathBuf, String>,
+    /// Print only the parts matching the regex address with `p`
+    pub(crate) only_matching: bool,
+    /// The regex address of the current instruction, when printing only the matches
+    pub(crate) matcher: Option<Regex>,
+    /// The range address of the current instruction did not reach its end yet
+    pub(crate) in_range: bool,
+    /// Number of the non-empty matches of the regex addresses, counted only when set
+    pub(crate) hits: Option<usize>,
+    /// Highlight the matches in the printed lines with the ANSI escape codes
+    pub(crate) color: bool,
+    /// The pattern space after the last substitution and the byte spans of the replaced text
+    pub(crate) replaced: Option<(String, Vec<(usize, usize)>)>,
+}
+
+impl State {
+    /// The prefix for the printed `line`: the `N\t` line number when numbering,
+    /// followed by the `[N] ` index of the instruction that changed it when annotating.
+    fn prefix(&self, line: &Line) -> String {
+        let mut prefix = String::new();
+        if self.number_tab {
+            prefix.push_str(&format!("{}\t", line.0));
+        }
+        if let Some(index) = self.changed_by.filter(|_| self.annotate) {
+            prefix.push_str(&format!("[{}] ", index));
+        }
+        prefix
+    }
+
+    /// Highlight the `text` when using colors: the text replaced by the last substitution if
+    /// it was not changed since, otherwise the matches of the regex address (with `address`).
+    fn highlight<'a>(&self, text: &'a str, address: bool) -> std::borrow::Cow<'a, str> {
+        use std::borrow::Cow;
+
+        if !self.color {
+            return Cow::Borrowed(text);
+        }
+        let spans = match (&self.replaced, &self.matcher) {
+            (Some((replaced, spans)), _) if replaced == text => spans.clone(),
+            (_, Some(regex)) if address => regex
+                .0
+                .find_iter(text)
+                .filter(|m| !m.is_empty())
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+            _ => return Cow::Borrowed(text),
+        };
+        let mut acc = String::with_capacity(text.len());
+        let mut last = 0;
+        for (start, end) in spans.into_iter().filter(|(start, end)| start < end) {
+            acc.push_str(&text[last..start]);
+            acc.push_str(COLOR_START);
+            acc.push_str(&text[start..end]);
+            acc.push_str(COLOR_END);
+            last = end;
+        }
+        acc.push_str(&text[last..]);
+        Cow::Owned(acc)
+    }
+
+    /// Count the iteration of a loop or a jump back to a label, failing when
+    /// there are more of them for the current line than the limit.
+    fn count_iteration(&mut self, pattern: &Line) -> Result<()> {
+        self.iterations += 1;
+        if let Some(max) = self.max_iterations.filter(|max| self.iterations > *max) {
+            bail!(
+                "loop exceeded the limit of {} iterations at line {}",
+                max,
+                pattern.0
+            );
+        }
+        Ok(())
+    }
+
+    /// Print the text added by `a` and `r 'path'` at the end of the cycle.
+    fn flush_appended(&mut self, out: &mut dyn OutputSink) -> Result<()> {
+        for text in self.appended.drain(..) {
+            out.emit(&text)?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum Action {
@@ -30,12 +160,44 @@ impl std::fmt::Display for Action {
     }
 }
 
+/// Write the actions as the script that parses back to the same actions,
+/// the instructions are separated with `;`.
+pub(crate) fn format_actions(actions: &[Action]) -> String {
+    use command::Command::{Break, Write};
+
+    let mut instructions: Vec<String> = Vec::new();
+    let mut iter = actions.iter();
+    while let Some(Action::Condition(addr, len)) = iter.next() {
+        let mut instruction = addr.to_string();
+        let mut previous: Option<&command::Command> = None;
+        for action in iter.by_ref().take(*len) {
+            let Action::Command(cmd) = action else {
+                unreachable!()
+            };
+            match previous {
+                // the path of `w` ends with the line
+                Some(Write(_)) => instruction.push('\n'),
+                _ if !instruction.is_empty() => instruction.push(' '),
+                _ => (),
+            }
+            instruction.push_str(&cmd.to_string());
+            previous = Some(cmd);
+        }
+        // `.` already ends the instruction
+        if !matches!(previous, Some(Break)) {
+            instruction.push_str(" ;");
+        }
+        instructions.push(instruction);
+    }
+    instructions.join(" ")
+}
+
 impl std::str::FromStr for Regex {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> std::result::Result<Regex, Self::Err> {
         let regex = regex::Regex::new(s)?;
-        Ok(Regex(regex))
+        Ok(Regex(std::sync::Arc::new(regex)))
     }
 }
 
@@ -46,30 +208,88 @@ impl PartialEq for Regex {
 }
 
 impl std::fmt::Display for Regex {
+    /// The pattern with `/` escaped, as it is written between the delimiters.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        use std::fmt::Write;
+
+        let mut chars = self.0.as_str().chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '/' => f.write_str("\\/")?,
+                '\\' => {
+                    f.write_char(c)?;
+                    if let Some(e) = chars.next() {
+                        f.write_char(e)?;
+                    }
+                }
+                _ => f.write_char(c)?,
+            }
+        }
+        Ok(())
     }
 }
 
-fn run<R: Iterator<Item = Result<Line>>>(
+fn run<R: lines::Lookahead>(
     actions: &[Action],
     pattern: &mut Line,
-    hold: &mut String,
+    state: &mut State,
     reader: &mut R,
+    out: &mut dyn OutputSink,
 ) -> Result<Option<Status>> {
     let mut status = None;
     let mut pos = 0;
+    let mut instruction = 0;
     while pos < actions.len() {
         match &actions[pos] {
             Action::Condition(cond, jump) => {
-                if cond.matches(pattern) {
+                instruction += 1;
+                // don't wait for the next line unless needed
+                let matched = if cond.looks_ahead() {
+                    let last = reader.is_last();
+                    cond.matches(pattern, reader.peek_line(), last)
+                } else {
+                    cond.matches(pattern, None, false)
+                };
+                if matched {
                     status = Some(Status::Normal);
+                    state.captures = cond.captures(pattern);
+                    state.in_range = cond.in_range();
+                    if state.only_matching || state.color {
+                        state.matcher = cond.regex().cloned();
+                    }
+                    if let (Some(hits), Some(regex)) = (state.hits.as_mut(), cond.regex()) {
+                        *hits += regex
+                            .0
+                            .find_iter(&pattern.1)
+                            .filter(|m| !m.is_empty())
+                            .count();
+                    }
                 } else {
                     pos += jump;
                 }
             }
             Action::Command(cmd) => {
-                let s = cmd.run(pattern, hold, reader)?;
+                let before = state.annotate.then(|| pattern.1.clone());
+                let s = cmd.run(pattern, state, reader, out)?;
+                if before.is_some_and(|b| b != pattern.1) {
+                    state.changed_by = Some(instruction);
+                }
+                if let Status::Jump(label) = s {
+                    // the labels were checked when parsing, so they are in the same block
+                    let target = actions
+                        .iter()
+                        .position(|a| matches!(a, Action::Command(command::Command::Label(l)) if *l == label))
+                        .expect("the label is defined");
+                    if target < pos {
+                        state.count_iteration(pattern)?;
+                    }
+                    instruction = actions[..target]
+                        .iter()
+                        .filter(|a| matches!(a, Action::Condition(..)))
+                        .count();
+                    pos = target + 1;
+                    continue;
+                }
                 if s != Status::Normal {
                     status = Some(s);
                     break;
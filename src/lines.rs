@@ -1,24 +1,126 @@
 use anyhow::Result;
 use std::{
     fs::File,
-    io::{BufRead, BufReader, Lines},
+    io::{BufRead, BufReader, Read},
     path::PathBuf,
+    time::{Duration, Instant},
 };
 
+/// How often (in lines) the timeout is checked.
+const TIMEOUT_CHECK_EVERY: usize = 1024;
+
 #[derive(Debug, PartialEq, Default)]
 pub struct Line(pub usize, pub String);
 
+/// Error returned by [`FilesReader`] when reading the file took longer than the timeout.
+/// The rest of the file is skipped, and reading continues with the next file.
+#[derive(Debug, PartialEq)]
+pub struct Timeout(pub PathBuf);
+
+impl std::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "timeout exceeded, skipping the rest of {}",
+            self.0.display()
+        )
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Lines of the reader without the trailing `\n`. Unless in binary mode,
+/// the `\r` preceding the `\n` is removed as well, same as in [`BufRead::lines`].
+/// The invalid UTF-8 sequences are replaced with `U+FFFD`, so binary data can be processed.
+pub(crate) struct Records<B: BufRead> {
+    inner: B,
+    binary: bool,
+}
+
+impl<B: BufRead> Records<B> {
+    pub(crate) fn new(inner: B, binary: bool) -> Self {
+        Records { inner, binary }
+    }
+}
+
+impl<B: BufRead> Iterator for Records<B> {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut bytes = Vec::new();
+        match self.inner.read_until(b'\n', &mut bytes) {
+            Ok(0) => None,
+            Ok(_) => {
+                let mut buf = String::from_utf8(bytes)
+                    .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned());
+                if buf.ends_with('\n') {
+                    buf.pop();
+                    if !self.binary && buf.ends_with('\r') {
+                        buf.pop();
+                    }
+                }
+                Some(Ok(buf))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// The opened input, decompressed if needed.
+type Input = BufReader<Box<dyn Read>>;
+
+/// Open the `input` for reading. With the `gzip` feature, the inputs starting with
+/// the gzip magic bytes are decompressed.
+fn open<R: Read + 'static>(input: R) -> std::io::Result<Input> {
+    #[cfg(feature = "gzip")]
+    let input = gzip::maybe_decompress(input)?;
+    #[cfg(not(feature = "gzip"))]
+    let input: Box<dyn Read> = Box::new(input);
+    Ok(BufReader::new(input))
+}
+
+#[cfg(feature = "gzip")]
+pub(crate) mod gzip {
+    use flate2::bufread::MultiGzDecoder;
+    use std::{
+        io::{BufRead, BufReader, Read},
+        path::Path,
+    };
+
+    const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+    /// Check if the file starts with the gzip magic bytes.
+    pub fn is_compressed(path: &Path) -> std::io::Result<bool> {
+        let mut head = Vec::with_capacity(MAGIC.len());
+        std::fs::File::open(path)?
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut head)?;
+        Ok(head == MAGIC)
+    }
+
+    /// Decompress the `input` if it starts with the gzip magic bytes, otherwise read it as-is.
+    /// Like `gzip -dc`, the concatenated gzip members are decompressed one after another.
+    pub(crate) fn maybe_decompress<R: Read + 'static>(input: R) -> std::io::Result<Box<dyn Read>> {
+        let mut input = BufReader::new(input);
+        if !input.fill_buf()?.starts_with(&MAGIC) {
+            return Ok(Box::new(input));
+        }
+        Ok(Box::new(MultiGzDecoder::new(input)))
+    }
+}
+
+#[derive(Default)]
 pub struct StdinReader {
-    buffer: Lines<BufReader<std::io::Stdin>>,
+    buffer: Option<Records<Input>>,
+    binary: bool,
     counter: usize,
 }
 
-impl Default for StdinReader {
-    fn default() -> Self {
-        StdinReader {
-            buffer: BufReader::new(std::io::stdin()).lines(),
-            counter: 0,
-        }
+impl StdinReader {
+    /// Keep the line endings other than `\n` (e.g. `\r` in CRLF) as a part of the lines.
+    pub fn binary(mut self) -> Self {
+        self.binary = true;
+        self
     }
 }
 
@@ -26,7 +128,14 @@ impl Iterator for StdinReader {
     type Item = Result<Line>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.buffer.next()? {
+        // opened lazily, since checking if it is compressed blocks until there is some input
+        if self.buffer.is_none() {
+            match open(std::io::stdin()) {
+                Ok(input) => self.buffer = Some(Records::new(input, self.binary)),
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+        match self.buffer.as_mut()?.next()? {
             Ok(line) => {
                 self.counter += 1;
                 let line = Line(self.counter, line.to_string());
@@ -39,21 +148,51 @@ impl Iterator for StdinReader {
 
 pub struct FilesReader {
     paths: Vec<PathBuf>,
-    file: Option<Lines<BufReader<File>>>,
+    file: Option<Records<Input>>,
     counter: usize,
+    current: PathBuf,
+    file_lines: usize,
+    started: Instant,
+    timeout: Option<Duration>,
+    binary: bool,
 }
 
 impl FilesReader {
+    /// Keep the line endings other than `\n` (e.g. `\r` in CRLF) as a part of the lines.
+    pub fn binary(mut self) -> Self {
+        self.binary = true;
+        self
+    }
+
+    /// Skip the rest of the file if processing it takes longer than `timeout`,
+    /// returning the [`Timeout`] error before continuing with the next file.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     fn next_file(&mut self) -> Option<Result<()>> {
         let path = self.paths.pop()?;
-        let file = match File::open(path) {
+        let file = match File::open(&path).and_then(open) {
             Ok(file) => file,
             Err(err) => return Some(Err(err.into())),
         };
-        let reader = BufReader::new(file).lines();
+        let reader = Records::new(file, self.binary);
         self.file = Some(reader);
+        self.current = path;
+        self.file_lines = 0;
+        self.started = Instant::now();
         Some(Ok(()))
     }
+
+    fn is_timed_out(&self) -> bool {
+        let Some(timeout) = self.timeout else {
+            return false;
+        };
+        self.file_lines > 0
+            && self.file_lines.is_multiple_of(TIMEOUT_CHECK_EVERY)
+            && self.started.elapsed() > timeout
+    }
 }
 
 impl From<Vec<PathBuf>> for FilesReader {
@@ -62,6 +201,11 @@ impl From<Vec<PathBuf>> for FilesReader {
             paths: value.iter().cloned().rev().collect(),
             file: None,
             counter: 0,
+            current: PathBuf::new(),
+            file_lines: 0,
+            started: Instant::now(),
+            timeout: None,
+            binary: false,
         }
     }
 }
@@ -71,10 +215,16 @@ impl Iterator for FilesReader {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
+            if self.is_timed_out() {
+                self.file = None;
+                self.file_lines = 0;
+                return Some(Err(Timeout(self.current.clone()).into()));
+            }
             if let Some(ref mut buffer) = self.file {
                 match buffer.next() {
                     Some(Ok(line)) => {
                         self.counter += 1;
+                        self.file_lines += 1;
                         let line = Line(self.counter, line.to_string());
                         return Some(Ok(line));
                     }
@@ -92,9 +242,78 @@ impl Iterator for FilesReader {
     }
 }
 
+/// Reader that can tell if there is nothing more to read, needed for matching `$`.
+pub(crate) trait Lookahead: Iterator<Item = Result<Line>> {
+    fn is_last(&mut self) -> bool;
+
+    /// Content of the next line without consuming it, `None` if it cannot be read.
+    fn peek_line(&mut self) -> Option<&str>;
+}
+
+impl<I: Iterator<Item = Result<Line>>> Lookahead for std::iter::Peekable<I> {
+    fn is_last(&mut self) -> bool {
+        self.peek().is_none()
+    }
+
+    fn peek_line(&mut self) -> Option<&str> {
+        match self.peek() {
+            Some(Ok(line)) => Some(&line.1),
+            _ => None,
+        }
+    }
+}
+
+/// Peekable reader counting the lines that were consumed (not only peeked).
+pub(crate) struct Counted<I: Iterator> {
+    inner: std::iter::Peekable<I>,
+    pub(crate) consumed: usize,
+}
+
+impl<I: Iterator<Item = Result<Line>>> Counted<I> {
+    pub(crate) fn new(iter: I) -> Self {
+        Counted {
+            inner: iter.peekable(),
+            consumed: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Line>>> Iterator for Counted<I> {
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.inner.next();
+        if line.is_some() {
+            self.consumed += 1;
+        }
+        line
+    }
+}
+
+impl<I: Iterator<Item = Result<Line>>> Lookahead for Counted<I> {
+    fn is_last(&mut self) -> bool {
+        self.inner.is_last()
+    }
+
+    fn peek_line(&mut self) -> Option<&str> {
+        self.inner.peek_line()
+    }
+}
+
 #[cfg(test)]
 pub(crate) struct MockReader {}
 
+#[cfg(test)]
+impl Lookahead for MockReader {
+    fn is_last(&mut self) -> bool {
+        true
+    }
+
+    fn peek_line(&mut self) -> Option<&str> {
+        None
+    }
+}
+
 #[cfg(test)]
 impl Iterator for MockReader {
     type Item = Result<Line>;
@@ -102,3 +321,121 @@ impl Iterator for MockReader {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FilesReader, Timeout};
+    use std::time::Duration;
+    use test_case::test_case;
+
+    #[test]
+    fn timeout_skips_rest_of_file() {
+        let dir = std::env::temp_dir();
+        let (long, short) = (
+            dir.join("se-timeout-long.txt"),
+            dir.join("se-timeout-short.txt"),
+        );
+        let content = (1..=5000).map(|i| format!("{}\n", i)).collect::<String>();
+        std::fs::write(&long, content).unwrap();
+        std::fs::write(&short, "end\n").unwrap();
+
+        let reader =
+            FilesReader::from(vec![long.clone(), short.clone()]).with_timeout(Duration::ZERO);
+        let mut timeouts = Vec::new();
+        let mut lines = Vec::new();
+        for line in reader {
+            match line {
+                Ok(line) => lines.push(line.1),
+                Err(err) => timeouts.push(err.downcast::<Timeout>().unwrap()),
+            }
+        }
+        std::fs::remove_file(&long).unwrap();
+        std::fs::remove_file(&short).unwrap();
+
+        assert_eq!(lines.len(), super::TIMEOUT_CHECK_EVERY + 1);
+        assert_eq!(lines.last().unwrap(), "end");
+        assert_eq!(timeouts, [Timeout(long)]);
+    }
+
+    #[cfg(feature = "gzip")]
+    fn compress(content: &str) -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn decompress_gzip() {
+        let dir = std::env::temp_dir();
+        let (plain, compressed) = (dir.join("se-gzip.txt"), dir.join("se-gzip.txt.gz"));
+        let content = (1..=5000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        std::fs::write(&plain, &content).unwrap();
+        std::fs::write(&compressed, compress(&content)).unwrap();
+        assert!(super::gzip::is_compressed(&compressed).unwrap());
+        assert!(!super::gzip::is_compressed(&plain).unwrap());
+
+        let read = |path: &std::path::PathBuf| {
+            FilesReader::from(vec![path.clone()])
+                .map(|l| l.unwrap().1)
+                .collect::<Vec<String>>()
+        };
+        let (from_plain, from_compressed) = (read(&plain), read(&compressed));
+        std::fs::remove_file(&plain).unwrap();
+        std::fs::remove_file(&compressed).unwrap();
+        assert_eq!(from_plain.len(), 5000);
+        assert_eq!(from_compressed, from_plain);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn decompress_concatenated() {
+        use std::io::Read;
+
+        let mut input = compress("a\nb\n");
+        input.extend(compress("c\n"));
+        let mut read = String::new();
+        super::gzip::maybe_decompress(std::io::Cursor::new(input))
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, "a\nb\nc\n");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test_case(b"a\nb\n"; "text")]
+    #[test_case(&[0x1f, 0x0a]; "partial magic")]
+    #[test_case(b""; "empty")]
+    fn not_compressed(input: &'static [u8]) {
+        use std::io::Read;
+
+        let mut read = Vec::new();
+        super::gzip::maybe_decompress(input)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, input);
+    }
+
+    #[test_case(false, &["a", "b", "c\r\rd", "e\r"]; "text")]
+    #[test_case(true, &["a\r", "b", "c\r\rd\r", "e\r"]; "binary")]
+    fn line_endings(binary: bool, expected: &[&str]) {
+        let input = "a\r\nb\nc\r\rd\r\ne\r";
+        let reader = super::Records::new(input.as_bytes(), binary);
+        let lines = reader.map(|l| l.unwrap()).collect::<Vec<String>>();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let input: &[u8] = b"a\xff\x00b\nc\n";
+        let reader = super::Records::new(input, false);
+        let lines = reader.map(|l| l.unwrap()).collect::<Vec<String>>();
+        assert_eq!(lines, ["a\u{fffd}\0b", "c"]);
+    }
+}
@@ -1,59 +1,585 @@
-use anyhow::Result;
-use clap::Parser;
-use se::{FilesReader, Line, Program, Status, StdinReader};
-use std::{path::PathBuf, str::FromStr};
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, ValueEnum};
+use se::{
+    repl, FilesReader, Line, Outcome, Output, ParseOptions, Program, Session, StdinReader, Summary,
+    Timeout, GRAMMAR,
+};
+use std::{
+    cell::Cell,
+    ffi::OsString,
+    fs::File,
+    io::{BufWriter, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 fn main() -> Result<()> {
-    let args = parse_args();
+    let mut args = parse_args();
 
-    let program = if let Some(path) = &args.script.path {
-        Program::try_from(path)?
+    if args.help_grammar {
+        print!("{}", GRAMMAR);
+        return Ok(());
+    }
+
+    let options = ParseOptions {
+        tab_width: args.tab_width,
+        expand_env: args.expand_env,
+        multiline: args.multiline,
+        regex_size_limit: args.regex_size_limit,
+        streaming: args.streaming,
+        literal_replacement: args.literal_replacement,
+        fixed_strings: args.fixed_strings,
+        ..Default::default()
+    };
+
+    if args.repl {
+        return repl(
+            std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+            &options,
+        );
+    }
+
+    if let Some(path) = &args.files0_from {
+        args.files.extend(read_files0(path)?);
+    }
+    if args.recursive {
+        args.files = walk_dirs(std::mem::take(&mut args.files), args.max_depth)?;
+    }
+
+    let mut program = if let Some(path) = &args.script.path {
+        Program::parse_file(path, &options)?
     } else if let Some(command) = &args.script.command {
-        Program::from_str(command)?
+        Program::parse_str(command, &options)?
     } else {
         unreachable!()
     };
+    if let Some(prelude) = &args.pre {
+        program.prepend(Program::parse_str(prelude, &options)?);
+    }
+    if args.sort_instructions {
+        program.optimize();
+    }
+    if args.dump_ast {
+        println!("{}", program.to_json());
+        return Ok(());
+    }
+    if args.annotate {
+        program.annotate();
+    }
+    if args.number_tab {
+        program.number_tab();
+    }
+    if args.only_matching {
+        program.only_matching();
+    }
+    let color = match args.color {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => std::io::stdout().is_terminal(),
+    };
+    // the edited files are never colored
+    if color && args.in_place.is_none() {
+        program.color();
+    }
+    let count_matches = args.count_matches || (args.count && args.only_matching);
+    if count_matches {
+        program.count_matches();
+    }
+    let before = args.before_context.or(args.context).unwrap_or(0);
+    let after = args.after_context.or(args.context).unwrap_or(0);
+    // like with `-n`, only the lines printed by the commands are shown when counting the matches,
+    // printing only the matching parts, or printing the lines with their context
+    let print_all = !(args.quiet
+        || args.count
+        || count_matches
+        || args.only_matching
+        || before > 0
+        || after > 0);
+    if before > 0 || after > 0 {
+        program.context(before, after);
+        if args.no_group_separator {
+            program.group_separator(None);
+        } else if let Some(separator) = args.group_separator.take() {
+            program.group_separator(Some(separator));
+        }
+    }
+    if let Some(path) = &args.match_lines_from {
+        let content = std::fs::read_to_string(path)?;
+        program.only_lines(content.lines().map(String::from).collect());
+    }
+    program.max_iterations((args.max_iterations > 0).then_some(args.max_iterations));
+    if let Some(path) = args.changes_log.take() {
+        program.log_changes(path);
+    }
+    for dump in args.dump.iter() {
+        let Some((name, path)) = dump.split_once('=') else {
+            bail!("invalid --dump argument '{}', expected NAME=FILE", dump);
+        };
+        program.dump_buffer(name, path.into());
+    }
+
+    if program.needs_line_count() && args.files.is_empty() {
+        bail!("the percentage addresses need the number of lines, they cannot be used with the standard input");
+    }
+
+    if let Some(suffix) = &args.in_place {
+        if args.files.is_empty() {
+            bail!("--in-place needs the files to edit, it cannot edit the standard input");
+        }
+        // the files written by `w` and the dumped buffers are shared by all the edited files
+        let mut session = Session::default();
+        let mut outcome = Outcome::Finished;
+        for path in args.files.iter() {
+            session.set_file(Some(path.clone()));
+            let retries = if args.retry_on_locked {
+                RENAME_RETRIES
+            } else {
+                0
+            };
+            outcome = edit_in_place(
+                &program,
+                &mut session,
+                path,
+                suffix,
+                args.binary,
+                print_all,
+                retries,
+            )?;
+            if let Outcome::Quit(..) = outcome {
+                break;
+            }
+        }
+        program.finish(session)?;
+        quit(outcome);
+        return Ok(());
+    }
 
-    let mut reader: Box<dyn Iterator<Item = Result<Line>>> = if args.files.is_empty() {
-        Box::new(StdinReader::default())
+    // with --separate each file is processed by a separate run of the program,
+    // so the line numbers, ranges, hold space, and `$` apply to each file on its own,
+    // while the files written by `w` and the dumped buffers are shared by all of them
+    let inputs = if args.separate && !args.files.is_empty() {
+        args.files.iter().map(|path| vec![path.clone()]).collect()
     } else {
-        Box::new(FilesReader::from(args.files))
+        vec![std::mem::take(&mut args.files)]
     };
 
-    let (status, count) = program.run(&mut reader, args.all)?;
+    // stdout is line-buffered, which is slow for large outputs, but needed when streaming
+    let stdout: Box<dyn Write> = if args.streaming {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(BufWriter::new(std::io::stdout().lock()))
+    };
+    let mut out = Output::new(stdout);
+    if args.no_trailing_sep {
+        out = out.without_trailing_newline();
+    }
+
+    let started = Instant::now();
+    let (lines, bytes) = (Cell::new(0), Cell::new(0));
+    let mut outcome = Outcome::Finished;
+    let mut summary = Summary::default();
+    let mut session = Session::default();
+
+    for files in inputs {
+        if args.separate {
+            session.set_file(files.first().cloned());
+        }
+        if program.needs_line_count() {
+            program.line_count(count_lines(&files)?);
+        }
+        let reader: Box<dyn Iterator<Item = Result<Line>>> = if files.is_empty() {
+            let reader = StdinReader::default();
+            Box::new(if args.binary { reader.binary() } else { reader })
+        } else {
+            let mut reader = FilesReader::from(files);
+            if args.binary {
+                reader = reader.binary();
+            }
+            match args.timeout_per_file {
+                Some(secs) => Box::new(
+                    reader
+                        .with_timeout(Duration::from_secs_f64(secs))
+                        .filter(report_timeout),
+                ),
+                None => Box::new(reader),
+            }
+        };
+        let mut reader = reader.inspect(|line| {
+            if let Ok(line) = line {
+                lines.set(lines.get() + 1);
+                // including the newline
+                bytes.set(bytes.get() + line.1.len() + 1);
+            }
+        });
+
+        let file_summary;
+        (outcome, file_summary) =
+            match program.run_with(&mut reader, &mut out, print_all, &mut session) {
+                Ok(result) => result,
+                Err(err) => {
+                    // print whatever was processed before the error
+                    out.flush()?;
+                    return Err(err);
+                }
+            };
+        summary += file_summary;
+        if let Outcome::Quit(..) = outcome {
+            break;
+        }
+    }
+    program.finish(session)?;
 
-    if args.count {
-        println!("{}", count)
+    if args.measure {
+        eprintln!(
+            "lines: {}, bytes: {}, elapsed: {:?}",
+            lines.get(),
+            bytes.get(),
+            started.elapsed()
+        );
     }
-    if let Status::Quit(code) = status {
-        std::process::exit(code)
+
+    if args.summary {
+        eprintln!(
+            "lines: {}, kept: {}, deleted: {}, modified: {}",
+            summary.lines, summary.kept, summary.deleted, summary.modified
+        );
+    }
+
+    if count_matches {
+        writeln!(out, "{}", summary.hits)?
+    } else if args.count {
+        writeln!(out, "{}", summary.matches)?
+    }
+    out.flush()?;
+    if let Outcome::Quit(..) = outcome {
+        quit(outcome);
     }
     Ok(())
 }
 
+/// Exit with the code of `q`, printing its message.
+fn quit(outcome: Outcome) {
+    if let Outcome::Quit(code, message) = outcome {
+        if let Some(message) = message {
+            eprintln!("{}", message);
+        }
+        std::process::exit(code)
+    }
+}
+
+/// Print the warning about the skipped file when the line is the [`Timeout`] error,
+/// returns `false` for it, so that processing continues with the next file.
+fn report_timeout(line: &Result<Line>) -> bool {
+    match line.as_ref().map_err(|err| err.downcast_ref::<Timeout>()) {
+        Err(Some(timeout)) => {
+            eprintln!("warning: {}", timeout);
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Read the files to count their lines, needed by the percentage addresses.
+fn count_lines(paths: &[PathBuf]) -> Result<usize> {
+    let mut total = 0;
+    for line in FilesReader::from(paths.to_vec()) {
+        line?;
+        total += 1;
+    }
+    Ok(total)
+}
+
+/// How many times replacing the edited file is retried with `--retry-on-locked`.
+const RENAME_RETRIES: u32 = 5;
+
+/// How long to wait before the first retry, each following one waits twice as long.
+const RENAME_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Run the program on the file and replace it with the output. The output is written
+/// to a temporary file first, so the file is left untouched if the program fails.
+/// With a non-empty `suffix` the original file is copied to the path with the suffix appended.
+/// The files written by `w` and the dumped buffers are kept in the `session` shared by the edited files.
+/// Replacing the file is retried `retries` times, since on Windows it can be briefly locked,
+/// e.g. by an antivirus.
+fn edit_in_place(
+    program: &Program,
+    session: &mut Session,
+    path: &Path,
+    suffix: &str,
+    binary: bool,
+    print_all: bool,
+    retries: u32,
+) -> Result<Outcome> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", path.display()))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(".se-tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    // the output would be written uncompressed
+    #[cfg(feature = "gzip")]
+    if se::is_compressed(path)? {
+        bail!(
+            "cannot edit the compressed file {} in place",
+            path.display()
+        );
+    }
+
+    if program.needs_line_count() {
+        program.line_count(count_lines(&[path.to_path_buf()])?);
+    }
+    let mut reader = FilesReader::from(vec![path.to_path_buf()]);
+    if binary {
+        reader = reader.binary();
+    }
+    let mut out = Output::new(BufWriter::new(File::create(&tmp)?));
+    let result = program
+        .run_with(&mut reader, &mut out, print_all, session)
+        .and_then(|(outcome, _)| {
+            out.flush()?;
+            Ok(outcome)
+        });
+    drop(out);
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            std::fs::remove_file(&tmp)?;
+            return Err(err);
+        }
+    };
+
+    std::fs::set_permissions(&tmp, std::fs::metadata(path)?.permissions())?;
+    if !suffix.is_empty() {
+        let mut backup = path.as_os_str().to_os_string();
+        backup.push(suffix);
+        std::fs::copy(path, backup)?;
+    }
+    if let Err(err) = retry(retries, RENAME_BACKOFF, || std::fs::rename(&tmp, path)) {
+        std::fs::remove_file(&tmp)?;
+        bail!("cannot replace {}: {}", path.display(), err);
+    }
+    Ok(outcome)
+}
+
+/// Call `f` until it succeeds, but no more than `retries` times after the first failure.
+fn retry<T>(
+    retries: u32,
+    backoff: Duration,
+    mut f: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut delay = backoff;
+    for attempt in 1..=retries {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                eprintln!(
+                    "warning: {}, retrying in {:?} ({}/{})",
+                    err, delay, attempt, retries
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+    f()
+}
+
 #[derive(Parser)]
 struct Args {
-    /// Print all the lines (except the ones that were deleted)
-    #[arg(short, long)]
-    all: bool,
+    /// Print only the lines printed by the commands, same as `sed -n`
+    #[arg(short = 'n', long)]
+    quiet: bool,
 
     /// Print the number of matches
     #[arg(short, long)]
     count: bool,
 
+    /// Print the number of the regex address matches, rather than the matched lines, same as `-co`
+    #[arg(long)]
+    count_matches: bool,
+
+    /// Read the lines without removing `\r` before `\n`, so CRLF line endings are preserved
+    #[arg(long)]
+    binary: bool,
+
+    /// Print also N lines before and after the matched lines
+    #[arg(short = 'C', long, value_name = "N")]
+    context: Option<usize>,
+
+    /// Print also N lines before the matched lines
+    #[arg(short = 'B', long, value_name = "N")]
+    before_context: Option<usize>,
+
+    /// Print also N lines after the matched lines
+    #[arg(short = 'A', long, value_name = "N")]
+    after_context: Option<usize>,
+
+    /// Separate the groups of lines printed with the context by SEP instead of `--`
+    #[arg(long, value_name = "SEP")]
+    group_separator: Option<String>,
+
+    /// Don't separate the groups of lines printed with the context
+    #[arg(long, conflicts_with = "group_separator")]
+    no_group_separator: bool,
+
+    /// Highlight the matches in the printed lines, `auto` when printing to the terminal
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Make `p` print only the parts of the line matching the regex address, one per line
+    #[arg(short = 'o', long)]
+    only_matching: bool,
+
+    /// Edit the files in place, keeping the backups with the SUFFIX appended to their names if given
+    #[arg(
+        short = 'i',
+        long,
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "",
+        conflicts_with_all = ["count", "count_matches", "summary", "measure", "timeout_per_file"]
+    )]
+    in_place: Option<String>,
+
+    /// Process each of the files separately, restarting the line numbers,
+    /// the ranges, and the hold space, with `$` matching the last line of each file
+    #[arg(short, long)]
+    separate: bool,
+
+    /// Retry replacing the edited files a few times if they are locked, e.g. by an antivirus
+    #[arg(long, requires = "in_place")]
+    retry_on_locked: bool,
+
+    /// Don't print the newline at the end of the output
+    #[arg(long)]
+    no_trailing_sep: bool,
+
     #[command(flatten)]
     script: Script,
 
+    /// Run the instructions of SCRIPT before the main script on every line
+    #[arg(long, value_name = "SCRIPT", allow_hyphen_values = true)]
+    pre: Option<String>,
+
+    /// Write the content of the named buffer to FILE after processing the input
+    #[arg(long, value_name = "NAME=FILE")]
+    dump: Vec<String>,
+
+    /// Write the number, the old and the new content of each changed line to FILE,
+    /// prefixed with the path of the file with --in-place or --separate
+    #[arg(long, value_name = "FILE")]
+    changes_log: Option<PathBuf>,
+
+    /// Process only the lines that are exactly equal to one of the lines in FILE
+    #[arg(long, value_name = "FILE")]
+    match_lines_from: Option<PathBuf>,
+
+    /// Prefix the printed lines with their line number followed by a tab
+    #[arg(long)]
+    number_tab: bool,
+
+    /// Prefix the printed lines with the index of the instruction that last changed them
+    #[arg(long)]
+    annotate: bool,
+
+    /// Fail when the loops run more than N iterations on a single line (0 for no limit)
+    #[arg(long, value_name = "N", default_value_t = 1_000_000)]
+    max_iterations: usize,
+
+    /// Print the number of the read, kept, deleted, and modified lines to stderr
+    #[arg(long)]
+    summary: bool,
+
+    /// Print the number of processed lines and bytes, and the elapsed time to stderr
+    #[arg(long)]
+    measure: bool,
+
+    /// Print the parsed script as JSON and exit
+    #[arg(long)]
+    dump_ast: bool,
+
+    /// Interactive mode: read a line, then apply each of the following lines as a script to it
+    #[arg(long, exclusive = true)]
+    repl: bool,
+
+    /// Print the grammar of the scripts
+    #[arg(long, exclusive = true)]
+    help_grammar: bool,
+
+    /// Reorder the members of the address sets to check the cheaper ones first
+    #[arg(long)]
+    sort_instructions: bool,
+
+    /// Replace `${NAME}` in the regular expressions and substitution templates with the environment variables
+    #[arg(long)]
+    expand_env: bool,
+
+    /// Match `^` and `$` at the line breaks in all the regexes, like the `m` flag
+    #[arg(long)]
+    multiline: bool,
+
+    /// Treat the patterns of the addresses and commands as literal strings instead of regexes
+    #[arg(short = 'F', long)]
+    fixed_strings: bool,
+
+    /// Keep the escape sequences like `\n` in the substitution templates as-is (`$1` is still expanded)
+    #[arg(long)]
+    literal_replacement: bool,
+
+    /// Reject the addresses waiting for the next line (`$`, `/a/>/b/`) and the `A` command,
+    /// so that unbounded streams are processed line by line, flush the output after each line.
+    /// The final block `$ { ... }` is allowed, since it runs after the end of the input
+    #[arg(long)]
+    streaming: bool,
+
+    /// Fail when any of the compiled regexes would take more than BYTES of memory
+    #[arg(long, value_name = "BYTES")]
+    regex_size_limit: Option<usize>,
+
+    /// Width of the tab used when measuring the indentation by the `indent` addresses
+    #[arg(long, value_name = "N", default_value_t = 8)]
+    tab_width: usize,
+
+    /// Skip the rest of the file when processing it takes longer than SECONDS
+    #[arg(long, value_name = "SECONDS")]
+    timeout_per_file: Option<f64>,
+
+    /// Process the files in the directories, walking their subdirectories
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Walk at most N levels of the subdirectories (0 for only the files directly in the directories)
+    #[arg(long, value_name = "N", requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// Read the NUL-separated names of the processed files from FILE (`-` for stdin)
+    #[arg(long = "files0-from", value_name = "FILE")]
+    files0_from: Option<PathBuf>,
+
     /// Files that are processed
     #[arg(name = "FILE")]
     files: Vec<PathBuf>,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
 #[derive(Parser)]
-#[group(multiple = true, required = true)]
+#[group(multiple = true)]
 struct Script {
     /// Commands that are executed
-    #[arg(allow_hyphen_values = true)]
+    #[arg(
+        allow_hyphen_values = true,
+        required_unless_present_any = ["path", "help_grammar", "repl"]
+    )]
     command: Option<String>,
 
     /// Read the commands from the file
@@ -62,7 +588,7 @@ struct Script {
 }
 
 fn parse_args() -> Args {
-    let mut args = Args::parse();
+    let mut args = Args::parse_from(split_context_args(std::env::args_os()));
     if args.script.path.is_some() {
         if let Some(arg) = args.script.command {
             // it's not a command, dumbo
@@ -72,3 +598,138 @@ fn parse_args() -> Args {
     }
     args
 }
+
+/// Separate the values attached to the context options, e.g. `-C2` becomes `-C 2`,
+/// otherwise they would be taken for the commands, since these can start with a hyphen.
+fn split_context_args(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = args.into_iter();
+    let mut result = Vec::new();
+    for arg in args.by_ref() {
+        if arg == "--" {
+            result.push(arg);
+            break;
+        }
+        match arg.to_str().and_then(split_context_arg) {
+            Some((flags, value)) => {
+                result.push(flags.into());
+                result.push(value.into());
+            }
+            None => result.push(arg),
+        }
+    }
+    result.extend(args);
+    result
+}
+
+/// Split the short flags ending with `-A`, `-B`, or `-C` from the number attached to them.
+fn split_context_arg(arg: &str) -> Option<(&str, &str)> {
+    let (flags, value) = arg.split_at(arg.find(|c: char| c.is_ascii_digit())?);
+    let flags_ok = flags.len() > 1
+        && flags.starts_with('-')
+        && flags[1..].chars().all(|c| c.is_ascii_alphabetic())
+        && flags.ends_with(['A', 'B', 'C']);
+    (flags_ok && value.chars().all(|c| c.is_ascii_digit())).then_some((flags, value))
+}
+
+fn read_files0(path: &PathBuf) -> Result<Vec<PathBuf>> {
+    let mut buf = Vec::new();
+    if path.as_os_str() == "-" {
+        std::io::stdin().read_to_end(&mut buf)?;
+    } else {
+        std::fs::File::open(path)?.read_to_end(&mut buf)?;
+    }
+    let mut files = Vec::new();
+    for name in buf.split(|b| *b == b'\0').filter(|s| !s.is_empty()) {
+        files.push(path_from_bytes(name)?);
+    }
+    Ok(files)
+}
+
+/// On unix the paths are arbitrary bytes, so they don't need to be valid UTF-8.
+#[cfg(unix)]
+fn path_from_bytes(name: &[u8]) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(name)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(name: &[u8]) -> Result<PathBuf> {
+    Ok(PathBuf::from(std::str::from_utf8(name)?))
+}
+
+/// Replace the directories with the files in them, sorted by their names, walking
+/// the subdirectories at most `max_depth` levels deep. The symbolic links to
+/// the directories are not followed.
+fn walk_dirs(paths: Vec<PathBuf>, max_depth: Option<usize>) -> Result<Vec<PathBuf>> {
+    fn walk(
+        dir: &Path,
+        depth: usize,
+        max_depth: Option<usize>,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                if max_depth.is_none_or(|max| depth < max) {
+                    walk(&path, depth + 1, max_depth, files)?;
+                }
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            walk(&path, 0, max_depth, &mut files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::retry;
+    use std::{io, time::Duration};
+
+    #[test]
+    fn retry_transient_failure() {
+        let mut attempts = 0;
+        let result = retry(5, Duration::ZERO, || {
+            attempts += 1;
+            if attempts < 3 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "locked"));
+            }
+            Ok(attempts)
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let mut attempts = 0;
+        let result: io::Result<()> = retry(2, Duration::ZERO, || {
+            attempts += 1;
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "locked"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn no_retries() {
+        let mut attempts = 0;
+        let result: io::Result<()> = retry(0, Duration::ZERO, || {
+            attempts += 1;
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "locked"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
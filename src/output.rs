@@ -0,0 +1,226 @@
+use anyhow::Result;
+#[cfg(feature = "gzip")]
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+#[cfg(feature = "zstd")]
+use {
+    anyhow::{anyhow, bail},
+    std::process::{Child, ChildStdin, Command, Stdio},
+};
+
+/// Destination of the printed records, e.g. lines printed by `p` or strings printed by `"..."`.
+pub trait OutputSink {
+    fn emit(&mut self, record: &str) -> Result<()>;
+}
+
+impl<W: Write> OutputSink for W {
+    fn emit(&mut self, record: &str) -> Result<()> {
+        self.write_all(record.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Sink holding the records, so that they can be emitted later.
+#[derive(Debug, Default)]
+pub(crate) struct Deferred(Vec<String>);
+
+impl OutputSink for Deferred {
+    fn emit(&mut self, record: &str) -> Result<()> {
+        self.0.push(record.to_string());
+        Ok(())
+    }
+}
+
+impl Deferred {
+    /// Emit the held records to the `out` sink.
+    pub(crate) fn replay(self, out: &mut dyn OutputSink) -> Result<()> {
+        for record in self.0 {
+            out.emit(&record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writer wrapping the output, that can optionally omit the final newline.
+pub struct Output<W: Write> {
+    inner: W,
+    trailing_newline: bool,
+    pending: bool,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(inner: W) -> Self {
+        Output {
+            inner,
+            trailing_newline: true,
+            pending: false,
+        }
+    }
+
+    /// Don't write the newline at the end of the output. To achieve this,
+    /// the final newline of each write is held back until the next write.
+    pub fn without_trailing_newline(mut self) -> Self {
+        self.trailing_newline = false;
+        self
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pending {
+            self.inner.write_all(b"\n")?;
+            self.pending = false;
+        }
+        match buf.split_last() {
+            Some((b'\n', rest)) if !self.trailing_newline => {
+                self.inner.write_all(rest)?;
+                self.pending = true;
+            }
+            _ => self.inner.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Output<W> {
+    fn drop(&mut self) {
+        // errors can't be reported here, flush explicitly to handle them
+        let _ = self.inner.flush();
+    }
+}
+
+/// File written by the `w` command. With the `gzip` feature, the files with the `.gz`
+/// extension are compressed, and with the `zstd` feature, the ones with the `.zst` extension
+/// are compressed by piping them through the `zstd` program.
+#[derive(Debug)]
+pub(crate) enum SideFile {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<BufWriter<File>>),
+    #[cfg(feature = "zstd")]
+    Compressed(Child, BufWriter<ChildStdin>),
+}
+
+impl SideFile {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            #[cfg(feature = "gzip")]
+            Some("gz") => {
+                let file = BufWriter::new(File::create(path)?);
+                Ok(SideFile::Gzip(GzEncoder::new(file, Compression::default())))
+            }
+            #[cfg(feature = "zstd")]
+            Some("zst") => {
+                let mut child = Command::new("zstd")
+                    .arg("-c")
+                    .stdin(Stdio::piped())
+                    .stdout(File::create(path)?)
+                    .spawn()
+                    .map_err(|err| {
+                        anyhow!("cannot run zstd to compress {}: {}", path.display(), err)
+                    })?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                Ok(SideFile::Compressed(child, BufWriter::new(stdin)))
+            }
+            _ => Ok(SideFile::Plain(BufWriter::new(File::create(path)?))),
+        }
+    }
+
+    /// Flush the file, and for the compressed ones, wait until the compression finishes.
+    pub(crate) fn finish(self) -> Result<()> {
+        match self {
+            SideFile::Plain(mut file) => file.flush()?,
+            #[cfg(feature = "gzip")]
+            SideFile::Gzip(encoder) => encoder.finish()?.flush()?,
+            #[cfg(feature = "zstd")]
+            SideFile::Compressed(mut child, mut stdin) => {
+                stdin.flush()?;
+                // closing the stdin ends the input of the compressor
+                drop(stdin);
+                let status = child.wait()?;
+                if !status.success() {
+                    bail!("compressing the file failed with {}", status);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for SideFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            SideFile::Plain(file) => file.write(buf),
+            #[cfg(feature = "gzip")]
+            SideFile::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            SideFile::Compressed(_, stdin) => stdin.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SideFile::Plain(file) => file.flush(),
+            #[cfg(feature = "gzip")]
+            SideFile::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            SideFile::Compressed(_, stdin) => stdin.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Output, OutputSink};
+    use crate::{program::numbered, Program};
+    use std::{io::Write, str::FromStr};
+
+    struct Records(Vec<String>);
+
+    impl OutputSink for Records {
+        fn emit(&mut self, record: &str) -> anyhow::Result<()> {
+            self.0.push(record.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_sink() {
+        let program = Program::from_str(r#"=p ; /b/ "!\n""#).unwrap();
+        let mut reader = numbered(&["a", "b"]);
+        let mut sink = Records(Vec::new());
+        program.run(&mut reader, &mut sink, false).unwrap();
+        assert_eq!(sink.0, ["1", "a\n", "2", "b\n", "!\n"]);
+    }
+
+    #[test]
+    fn trailing_newline() {
+        let mut out = Output::new(Vec::new());
+        write!(out, "a\nb").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "c").unwrap();
+        assert_eq!(out.inner, b"a\nb\nc\n");
+    }
+
+    #[test]
+    fn without_trailing_newline() {
+        let mut out = Output::new(Vec::new()).without_trailing_newline();
+        write!(out, "a\nb").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "c").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out).unwrap();
+        assert_eq!(out.inner, b"a\nb\nc\n\n");
+    }
+}
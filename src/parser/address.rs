@@ -1,6 +1,9 @@
 use super::{
     reader::Reader,
-    utils::{parse_regex, read_integer, skip_line, skip_whitespace},
+    utils::{
+        parse_regex, parse_regex_with_flags, read_integer, skip_line, skip_whitespace, ParseContext,
+    },
+    Error, DEFAULT_TAB_WIDTH,
 };
 use crate::address::{
     self,
@@ -8,14 +11,17 @@ use crate::address::{
 };
 use anyhow::{bail, Result};
 
-pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Address> {
+pub(crate) fn parse<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Address> {
     let mut addrs = Vec::new();
     let mut has_any = false;
+    let mut is_implicit = false;
     loop {
-        let mut addr = parse_brackets(reader)?;
+        let mut addr = parse_brackets(reader, context)?;
         match addr {
             Always => has_any = true,
+            Implicit => is_implicit = true,
             Set(ref mut rhs) => addrs.append(rhs),
+            RegexSet(ref mut rhs) => addrs.extend(rhs.regexes.drain(..).map(Regex)),
             _ => addrs.push(addr),
         }
 
@@ -28,63 +34,135 @@ pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Address> {
     }
 
     // optimizations
-    if has_any {
+    if is_implicit && !has_any && addrs.is_empty() {
+        return Ok(Implicit);
+    }
+    if has_any || is_implicit {
         return Ok(Always);
     }
     if addrs.len() == 1 {
         return Ok(addrs.remove(0));
     }
+    if addrs.iter().all(|a| matches!(a, Regex(_))) {
+        let regexes = addrs
+            .iter()
+            .map(|a| match a {
+                Regex(regex) => regex.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        // keep checking them one by one if together they exceed the size limit
+        if let Ok(set) = address::RegexSet::new(regexes, context.options.regex_size_limit) {
+            return Ok(RegexSet(set));
+        }
+    }
     Ok(Set(addrs))
 }
 
-fn parse_brackets<R: Reader>(reader: &mut R) -> Result<Address> {
+fn parse_brackets<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Address> {
     if reader.next_is('(')? {
         skip_whitespace(reader);
-        let addr = parse(reader)?;
+        let addr = explicit(parse(reader, context)?);
         skip_whitespace(reader);
         reader.expect(')')?;
         Ok(maybe_negate(addr, reader)?)
     } else {
-        let addr = parse_range(reader)?;
+        let addr = parse_range(reader, context)?;
         skip_whitespace(reader);
         Ok(maybe_negate(addr, reader)?)
     }
 }
 
-fn parse_range<R: Reader>(reader: &mut R) -> Result<Address> {
-    let addr = parse_simple_addr(reader)?;
+fn parse_range<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Address> {
+    let addr = parse_simple_addr(reader, context)?;
     skip_whitespace(reader);
     if reader.next_is('-')? {
         let lhs = addr.unwrap_or(Location(1));
         skip_whitespace(reader);
-        let rhs = parse_simple_addr(reader)?.unwrap_or(Final);
+        let exclusive = reader.next_is('<')?;
+        if exclusive {
+            skip_whitespace(reader);
+        }
+        let rhs = if reader.next_is('+')? {
+            let s = read_integer(reader)?;
+            if s.is_empty() {
+                bail!("missing number of lines after -+");
+            }
+            Offset(s.parse()?)
+        } else {
+            match parse_simple_addr(reader, context)? {
+                Some(rhs) => rhs,
+                None if exclusive => bail!("missing the end of the range after -<"),
+                None => Final,
+            }
+        };
         if let (Location(lo), Location(hi)) = (&lhs, &rhs) {
             if lo > hi {
                 bail!("invalid bounds: {} > {} in {}-{}", lo, hi, lo, hi);
             }
+            if exclusive && lo == hi {
+                bail!("empty range: {}-<{}", lo, hi);
+            }
+        }
+        if exclusive {
+            return Ok(Between(address::Between::exclusive(lhs, rhs)));
         }
         return Ok(Between(address::Between::new(lhs, rhs)));
     }
-    Ok(addr.unwrap_or(Always))
+    Ok(addr.unwrap_or(Implicit))
 }
 
-fn parse_simple_addr<R: Reader>(reader: &mut R) -> Result<Option<Address>> {
+fn parse_simple_addr<R: Reader>(
+    reader: &mut R,
+    context: &mut ParseContext,
+) -> Result<Option<Address>> {
     if let Some(c) = reader.peek()? {
         match c {
+            // `=` not followed by `/` is the line number command
+            '=' if reader.peek_n(1)? == Some('/') => {
+                reader.skip();
+                let addr = match parse_regex(reader, context)? {
+                    Some(regex) => LineNumberRegex(regex),
+                    None => Always,
+                };
+                return Ok(Some(addr));
+            }
             '#' => {
                 skip_line(reader);
                 skip_whitespace(reader);
-                return parse_simple_addr(reader);
+                return parse_simple_addr(reader, context);
             }
             '/' | '^' => {
-                let addr = match parse_regex(reader)? {
-                    Some(regex) => Regex(regex),
+                let regex = parse_regex_with_flags(reader, context)?;
+                let addr = match regex {
+                    Some(regex) if reader.next_is('>')? => {
+                        parse_followed_by(reader, regex, context)?
+                    }
+                    Some(regex) if reader.next_is('{')? => {
+                        let n = parse_count(reader)?;
+                        CountAtLeast { regex, n }
+                    }
+                    Some(regex) => match parse_nth(reader)? {
+                        Some(n) => NthMatch(address::NthMatch::new(regex, n)),
+                        None => Regex(regex),
+                    },
                     None => Always,
                 };
                 return Ok(Some(addr));
             }
             c if c.is_ascii_digit() => {
                 let s = read_integer(reader)?;
+                if reader.next_is('~')? {
+                    return Ok(Some(parse_step(reader, s.parse()?)?));
+                }
+                if reader.next_is('%')? {
+                    return match s.parse() {
+                        Ok(percent) if percent <= 100 => {
+                            Ok(Some(Percent(address::Percent::new(percent))))
+                        }
+                        _ => bail!("invalid percentage: {}%", s),
+                    };
+                }
                 match s.parse() {
                     Ok(num) => {
                         if num == 0 {
@@ -103,40 +181,233 @@ fn parse_simple_addr<R: Reader>(reader: &mut R) -> Result<Option<Address>> {
                 reader.skip();
                 return Ok(Some(Maybe));
             }
+            '\\' => {
+                // \0 matches lines containing NUL bytes
+                reader.skip();
+                reader.expect('0')?;
+                return Ok(Some(Regex(context.compile(r"\x00")?)));
+            }
+            '?' => {
+                reader.skip();
+                return Ok(Some(Changed(address::Changed::default())));
+            }
+            // `i` not followed by `n` is the insert command
+            'i' if reader.peek_n(1)? == Some('n') => return Ok(Some(parse_indent(reader)?)),
             _ => (),
         }
     }
     Ok(None)
 }
 
+/// Parse the optional `#n` suffix of a regex, `#` followed by a non-digit starts a comment.
+fn parse_nth<R: Reader>(reader: &mut R) -> Result<Option<usize>> {
+    if !reader.next_is('#')? {
+        return Ok(None);
+    }
+    let s = read_integer(reader)?;
+    if s.is_empty() {
+        skip_line(reader);
+        return Ok(None);
+    }
+    let n = s.parse()?;
+    if n == 0 {
+        bail!("invalid match number: {}", s);
+    }
+    Ok(Some(n))
+}
+
+/// Parse the `n,}` of the `/regex/{n,}` count, after the `{` was consumed.
+fn parse_count<R: Reader>(reader: &mut R) -> Result<usize> {
+    let s = read_integer(reader)?;
+    if s.is_empty() {
+        bail!("missing the count of matches");
+    }
+    reader.expect(',')?;
+    reader.expect('}')?;
+    match s.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("invalid count of matches: {{{},}}", s),
+    }
+}
+
+/// Parse the step of `first~step`, after the `~` was consumed.
+fn parse_step<R: Reader>(reader: &mut R, first: usize) -> Result<Address> {
+    let s = read_integer(reader)?;
+    if s.is_empty() {
+        bail!("missing step after {}~", first);
+    }
+    let step = s.parse()?;
+    if step == 0 {
+        bail!("invalid step: {}~{}", first, s);
+    }
+    Ok(Step { first, step })
+}
+
+/// Parse the `/regex/` matching the next line, after the `>` was consumed.
+fn parse_followed_by<R: Reader>(
+    reader: &mut R,
+    regex: crate::Regex,
+    context: &mut ParseContext,
+) -> Result<Address> {
+    if !matches!(reader.peek()?, Some('/' | '^')) {
+        bail!(Error::Missing('/'));
+    }
+    match parse_regex_with_flags(reader, context)? {
+        Some(next) => Ok(FollowedBy(regex, next)),
+        None => bail!("empty regular expression"),
+    }
+}
+
+/// Parse `indent>n`, `indent<n`, or `indent=n`. The tab width can be
+/// changed later using the parse context.options.
+fn parse_indent<R: Reader>(reader: &mut R) -> Result<Address> {
+    for c in "indent".chars() {
+        reader.expect(c)?;
+    }
+    let op = match reader.next()? {
+        Some('<') => std::cmp::Ordering::Less,
+        Some('=') => std::cmp::Ordering::Equal,
+        Some('>') => std::cmp::Ordering::Greater,
+        Some(c) => bail!(Error::Unexpected(c)),
+        None => bail!(Error::Missing('>')),
+    };
+    let s = read_integer(reader)?;
+    if s.is_empty() {
+        bail!("missing the indentation width");
+    }
+    Ok(Indent {
+        op,
+        n: s.parse()?,
+        tab_width: DEFAULT_TAB_WIDTH,
+    })
+}
+
 fn maybe_negate<R: Reader>(addr: Address, reader: &mut R) -> Result<Address> {
     if reader.next_is('!')? {
-        Ok(!addr)
+        Ok(!explicit(addr))
     } else {
         Ok(addr)
     }
 }
 
+/// Brackets or negation make the address explicit.
+fn explicit(addr: Address) -> Address {
+    match addr {
+        Implicit => Always,
+        _ => addr,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Address::{self, *};
-    use crate::{address, parser::StringReader};
+    use crate::{
+        address,
+        parser::{utils::ParseContext, ParseOptions, StringReader},
+    };
+    use std::str::FromStr;
     use test_case::test_case;
 
-    #[test_case("", Always; "empty")]
+    #[test_case("", Implicit; "empty")]
+    #[test_case("  ", Implicit; "only whitespace")]
+    #[test_case("1,", Always; "set with empty")]
     #[test_case("()", Always; "empty brackets")]
     #[test_case("//", Always; "empty regex")]
     #[test_case("//!", Negate(Box::new(Always)); "negated empty regex")]
     #[test_case("!", Negate(Box::new(Always)); "negated empty")]
     #[test_case("$", Final; "finally")]
+    #[test_case("/a/#3", NthMatch(address::NthMatch::new(crate::Regex::from_str("a").unwrap(), 3)); "nth match")]
+    #[test_case("/a/{3,}", CountAtLeast { regex: crate::Regex::from_str("a").unwrap(), n: 3 }; "count at least")]
+    #[test_case("/a/i{2,}!", Negate(Box::new(CountAtLeast { regex: crate::Regex::from_str("(?i)a").unwrap(), n: 2 })); "negated count with flags")]
+    #[test_case("/a/{2,}-5", Between(address::Between::new(CountAtLeast { regex: crate::Regex::from_str("a").unwrap(), n: 2 }, Location(5))); "count to line")]
+    #[test_case("/a/#comment\n", Regex(crate::Regex::from_str("a").unwrap()); "regex followed by comment")]
+    #[test_case("=/00$/", LineNumberRegex(crate::Regex::from_str("00$").unwrap()); "line number regex")]
+    #[test_case("# comment\n5", Location(5); "comment")]
+    #[test_case("#/comment/\n5", Location(5); "comment starting with slash")]
+    #[test_case(r"\0", Regex(crate::Regex::from_str(r"\x00").unwrap()); "null")]
+    #[test_case("indent>8", Indent { op: std::cmp::Ordering::Greater, n: 8, tab_width: 8 }; "indent")]
+    #[test_case("indent=0!", Negate(Box::new(Indent { op: std::cmp::Ordering::Equal, n: 0, tab_width: 8 })); "negated indent")]
     #[test_case("1-5!", Negate(Box::new(Between(address::Between::new(Location(1), Location(5))))); "negated range")]
     #[test_case("((1-5)!)", Negate(Box::new(Between(address::Between::new(Location(1), Location(5))))); "brackets and negated range")]
     #[test_case("1,$", Set(vec![Location(1), Final]); "first or last")]
     #[test_case("1,$!", Set(vec![Location(1), Negate(Box::new(Final))]); "first or last negated")]
+    #[test_case("50%", Percent(address::Percent::new(50)); "percentage")]
+    #[test_case("0%-10%", Between(address::Between::new(Percent(address::Percent::new(0)), Percent(address::Percent::new(10)))); "range of percentages")]
+    #[test_case("/a/, /b/", RegexSet(address::RegexSet::new(vec![crate::Regex::from_str("a").unwrap(), crate::Regex::from_str("b").unwrap()], None).unwrap()); "regex set")]
+    #[test_case("(/a/, /b/), 1", Set(vec![Regex(crate::Regex::from_str("a").unwrap()), Regex(crate::Regex::from_str("b").unwrap()), Location(1)]); "regex set in mixed set")]
+    #[test_case("/start/-10", Between(address::Between::new(Regex(crate::Regex::from_str("start").unwrap()), Location(10))); "regex to line")]
+    #[test_case("/a-b/", Regex(crate::Regex::from_str("a-b").unwrap()); "dash in regex")]
+    #[test_case(r"^a\-b$", Regex(crate::Regex::from_str(r"^a\-b$").unwrap()); "escaped dash in whole line")]
+    #[test_case("/a-b/-/c-d/", Between(address::Between::new(Regex(crate::Regex::from_str("a-b").unwrap()), Regex(crate::Regex::from_str("c-d").unwrap()))); "dashes in range of regexes")]
+    #[test_case("/a/i - +2", Between(address::Between::new(Regex(crate::Regex::from_str("(?i)a").unwrap()), Offset(2))); "regex with flags to offset")]
+    #[test_case("/a/#2-5", Between(address::Between::new(NthMatch(address::NthMatch::new(crate::Regex::from_str("a").unwrap(), 2)), Location(5))); "nth match to line")]
+    #[test_case("/a/,-5", Set(vec![Regex(crate::Regex::from_str("a").unwrap()), Between(address::Between::new(Location(1), Location(5)))]); "regex or range to line")]
+    #[test_case("/a/-", Between(address::Between::new(Regex(crate::Regex::from_str("a").unwrap()), Final)); "regex to end")]
+    #[test_case("/a/-</b/", Between(address::Between::exclusive(Regex(crate::Regex::from_str("a").unwrap()), Regex(crate::Regex::from_str("b").unwrap()))); "exclusive range")]
+    #[test_case("2 -< +3!", Negate(Box::new(Between(address::Between::exclusive(Location(2), Offset(3))))); "negated exclusive range to offset")]
     #[test_case("(1,$)!", Negate(Box::new(Set(vec![Location(1), Final]))); "negate set in brackets")]
     fn parse(input: &str, expected: Address) {
         let mut reader = StringReader::from(input);
-        let result = super::parse(&mut reader).unwrap();
+        let result = super::parse(
+            &mut reader,
+            &mut ParseContext::new(&ParseOptions::default()),
+        )
+        .unwrap();
+        assert_eq!(result, expected)
+    }
+
+    #[test_case("1-3!", "(1-3)!"; "range binds tighter than negation")]
+    #[test_case("1-3 !", "(1-3)!"; "negated range with space")]
+    #[test_case("1-<3!", "(1-<3)!"; "exclusive range binds tighter than negation")]
+    #[test_case("1,2!,3", "1,(2!),3"; "negation binds tighter than set")]
+    #[test_case("5,6,10!", "5,6,(10)!"; "negation of the last member")]
+    #[test_case("1-3!,5", "((1-3)!),5"; "negated range in set")]
+    #[test_case("1-3,5!", "(1-3),(5!)"; "range and negation in set")]
+    #[test_case("(1,2),3", "1,2,3"; "brackets in set are flattened")]
+    #[test_case("((1)!)!", "1"; "double negation")]
+    #[test_case("((1-3)!)!", "1-3"; "double negation of range")]
+    #[test_case("(1,2!)!", "((1,(2)!))!"; "negated set with negated member")]
+    fn precedence(input: &str, equivalent: &str) {
+        let parse = |s| {
+            super::parse(
+                &mut StringReader::from(s),
+                &mut ParseContext::new(&ParseOptions::default()),
+            )
+            .unwrap()
+        };
+        assert_eq!(parse(input), parse(equivalent))
+    }
+
+    #[test_case("5,6,10!", "(5,6,10)!"; "negation of set")]
+    #[test_case("1,2!,3", "(1,2,3)!"; "negation in the middle")]
+    fn different_precedence(input: &str, other: &str) {
+        let parse = |s| {
+            super::parse(
+                &mut StringReader::from(s),
+                &mut ParseContext::new(&ParseOptions::default()),
+            )
+            .unwrap()
+        };
+        assert_ne!(parse(input), parse(other))
+    }
+
+    #[test_case("1-3!", &[4, 5, 6]; "negated range")]
+    #[test_case("(1-3)!", &[4, 5, 6]; "negated range in brackets")]
+    #[test_case("1,2!,3", &[1, 3, 4, 5, 6]; "negated member")]
+    #[test_case("(1,2,3)!", &[4, 5, 6]; "negated set")]
+    #[test_case("5,6,1!", &[2, 3, 4, 5, 6]; "negated last member")]
+    #[test_case("2-4!,3", &[1, 3, 5, 6]; "negated range or line")]
+    #[test_case("(2-4,3)!", &[1, 5, 6]; "negated range and line")]
+    fn precedence_matches(input: &str, expected: &[usize]) {
+        let mut reader = StringReader::from(input);
+        let addr = super::parse(
+            &mut reader,
+            &mut ParseContext::new(&ParseOptions::default()),
+        )
+        .unwrap();
+        let result: Vec<usize> = (1..=6)
+            .filter(|&n| addr.matches(&crate::Line(n, String::new()), None, n == 6))
+            .collect();
         assert_eq!(result, expected)
     }
 }
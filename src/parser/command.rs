@@ -1,13 +1,27 @@
 use super::{
-    instruction::parse_instruction,
-    reader::Reader,
-    utils::{parse_regex, read_integer, skip_line, skip_whitespace},
+    instruction::parse_block,
+    reader::{Reader, StringReader},
+    utils::{
+        expand_env, parse_regex, read_flags, read_integer, read_name, read_pattern,
+        skip_inline_whitespace, skip_line, skip_whitespace, ParseContext,
+    },
     Error,
 };
-use crate::command::Command::{self, *};
+use crate::{
+    command::{
+        Command::{self, *},
+        TrimSide,
+    },
+    hash::HashKind,
+    template, Regex,
+};
 use anyhow::{anyhow, bail, Result};
+use std::str::FromStr;
+
+/// Fields are delimited with whitespace unless the delimiter is given.
+const DEFAULT_FIELD_DELIMITER: &str = r"\s+";
 
-pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Vec<Command>> {
+pub(crate) fn parse<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Vec<Command>> {
     let mut cmds = Vec::new();
     while let Some(c) = reader.next()? {
         let cmd = match c {
@@ -18,8 +32,18 @@ pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Vec<Command>> {
             }
             'b' => {
                 skip_whitespace(reader);
-                reader.expect(';')?;
-                cmds.push(Break);
+                if reader.next_is(';')? {
+                    cmds.push(Break);
+                } else {
+                    cmds.push(Branch(read_name(reader)?));
+                    end_branch(reader)?;
+                }
+                break;
+            }
+            't' if is_label_next(reader)? => {
+                skip_inline_whitespace(reader);
+                cmds.push(BranchIfSub(read_name(reader)?));
+                end_branch(reader)?;
                 break;
             }
             'p' => Println,
@@ -27,7 +51,10 @@ pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Vec<Command>> {
             'n' => Insert("\n".to_string()),
             't' => Insert("\t".to_string()),
             'l' => Escapeln,
-            's' => parse_substitute(reader)?,
+            's' => parse_substitute(reader, context)?,
+            'F' => parse_split(reader, context)?,
+            'y' => parse_transliterate(reader)?,
+            'f' => parse_field(reader, context)?,
             'k' => {
                 skip_whitespace(reader);
                 parse_keep(reader)?
@@ -35,26 +62,91 @@ pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Vec<Command>> {
             '=' => LineNumber,
             'd' => Delete,
             'z' => Reset,
-            'h' => Hold,
-            'g' => Get,
-            'x' => Exchange,
+            '~' => Reverse,
+            'U' => Uppercase,
+            'L' => Lowercase,
+            'T' => match reader.peek()? {
+                Some('<') => {
+                    reader.skip();
+                    Trim(TrimSide::Start)
+                }
+                Some('>') => {
+                    reader.skip();
+                    Trim(TrimSide::End)
+                }
+                _ => Trim(TrimSide::Both),
+            },
+            'C' => {
+                skip_whitespace(reader);
+                Hash(parse_hash(&read_name(reader)?)?)
+            }
+            'h' => Hold(parse_register(reader)?),
+            'g' => Get(parse_register(reader)?),
+            'x' => Exchange(parse_register(reader)?),
             'j' => Joinln,
             'J' => Join,
+            'a' => AppendAfter(parse_text(reader)?),
+            'i' => InsertBefore(parse_text(reader)?),
+            'c' => Change(parse_text(reader)?),
+            'A' => {
+                if context.options.streaming {
+                    bail!("'A' keeps the lines in memory, it cannot be used when streaming");
+                }
+                skip_whitespace(reader);
+                Append(read_name(reader)?)
+            }
+            'w' => {
+                // the path ends with the line or the instruction
+                let mut path = String::new();
+                while let Some(c) = reader.peek()? {
+                    if c == '\n' || c == ';' {
+                        break;
+                    }
+                    path.push(c);
+                    reader.skip();
+                }
+                let path = path.trim();
+                if path.is_empty() {
+                    bail!("missing the file name after 'w'");
+                }
+                Write(path.into())
+            }
             'e' => Eval,
             'r' => {
-                skip_whitespace(reader);
-                let s = read_integer(reader)?;
-                let num = if s.is_empty() { 1 } else { s.parse()? };
-                Readln(num)
+                // the optional arguments need to be on the same line
+                skip_inline_whitespace(reader);
+                match reader.peek()? {
+                    Some(c) if c == '\'' || c == '"' => {
+                        reader.skip();
+                        ReadFile(unescape(read_until(reader, c)?)?.into())
+                    }
+                    _ => {
+                        let s = read_integer(reader)?;
+                        let num = if s.is_empty() { 1 } else { s.parse()? };
+                        Readln(num)
+                    }
+                }
             }
+            'N' => Next,
             'R' => ReadReplace,
             'q' => {
-                skip_whitespace(reader);
+                skip_inline_whitespace(reader);
+                let message = match reader.peek()? {
+                    Some(c) if c == '\'' || c == '"' => {
+                        reader.skip();
+                        let msg = unescape(read_until(reader, c)?)?;
+                        skip_inline_whitespace(reader);
+                        Some(msg)
+                    }
+                    _ => None,
+                };
                 let s = read_integer(reader)?;
                 let code = if s.is_empty() { 0 } else { s.parse()? };
-                Quit(code)
+                Quit(code, message)
             }
-            ':' => parse_loop(reader)?,
+            ':' if reader.peek()? == Some('{') => parse_loop(reader, context)?,
+            ':' => Label(read_name(reader)?),
+            'H' => parse_with_hold(reader, context)?,
             '\'' | '"' => {
                 let msg = unescape(read_until(reader, c)?)?;
                 Insert(msg)
@@ -76,16 +168,96 @@ pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Vec<Command>> {
     Ok(cmds)
 }
 
-fn parse_substitute<R: Reader>(reader: &mut R) -> Result<Command> {
+/// Check if the whitespace after `t` is followed by the label name that ends the instruction,
+/// so `t name ;` is the branch, while `t p q` prints the tab followed by the other commands.
+fn is_label_next<R: Reader>(reader: &mut R) -> Result<bool> {
+    let is_inline_whitespace = |c: char| c != '\n' && c.is_whitespace();
+    let mut n = 0;
+    while reader.peek_n(n)?.is_some_and(is_inline_whitespace) {
+        n += 1;
+    }
+    let start = n;
+    while reader
+        .peek_n(n)?
+        .is_some_and(|c| c.is_alphanumeric() || c == '_')
+    {
+        n += 1;
+    }
+    if start == 0 || n == start {
+        return Ok(false);
+    }
+    while reader.peek_n(n)?.is_some_and(is_inline_whitespace) {
+        n += 1;
+    }
+    Ok(matches!(
+        reader.peek_n(n)?,
+        None | Some(';' | '\n' | '}' | '#')
+    ))
+}
+
+/// The branch ends the instruction, like `.` does.
+fn end_branch<R: Reader>(reader: &mut R) -> Result<()> {
+    skip_inline_whitespace(reader);
+    match reader.peek()? {
+        Some(';') => reader.skip(),
+        None | Some('\n' | '}' | '#') => (),
+        Some(_) => bail!(Error::Missing(';')),
+    }
+    Ok(())
+}
+
+/// Read the optional name of the register following the command,
+/// a single digit or a single quoted character.
+fn parse_register<R: Reader>(reader: &mut R) -> Result<Option<char>> {
+    match reader.peek()? {
+        Some(c) if c.is_ascii_digit() => {
+            reader.skip();
+            if reader.peek()?.is_some_and(|c| c.is_ascii_digit()) {
+                bail!("register names are single characters");
+            }
+            Ok(Some(c))
+        }
+        Some(q) if q == '\'' || q == '"' => {
+            reader.skip();
+            let name = unescape(read_until(reader, q)?)?;
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Some(c)),
+                _ => bail!("register names are single characters, got '{}'", name),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Find the hash by its name, if the feature implementing it was enabled.
+fn parse_hash(name: &str) -> Result<HashKind> {
+    match name {
+        #[cfg(feature = "crc")]
+        "crc32" => Ok(HashKind::Crc32),
+        #[cfg(feature = "sha2")]
+        "sha256" => Ok(HashKind::Sha256),
+        #[cfg(not(feature = "crc"))]
+        "crc32" => bail!("the 'crc32' hash needs se to be built with the 'crc' feature"),
+        #[cfg(not(feature = "sha2"))]
+        "sha256" => bail!("the 'sha256' hash needs se to be built with the 'sha2' feature"),
+        _ => bail!("unknown hash '{}'", name),
+    }
+}
+
+fn parse_substitute<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Command> {
     if reader.peek()? != Some('/') {
         bail!(Error::Missing('/'));
     }
 
-    // Parse: s/src/dst/[limit]
-    let Some(src) = parse_regex(reader)? else {
+    // Parse: s/src/dst/[limit][flags][?]
+    let Some(pattern) = read_pattern(reader, context)? else {
         bail!("empty regular expression");
     };
-    let dst = read_template(reader)?;
+    let mut dst = read_template(reader, context.options.literal_replacement)?;
+    if !context.options.expand_env && template::uses_env(&dst) {
+        bail!("${{env:...}} in the substitution needs the --expand-env flag");
+    }
 
     let mut limit = 0;
     if let Some(c) = reader.peek()? {
@@ -96,20 +268,118 @@ fn parse_substitute<R: Reader>(reader: &mut R) -> Result<Command> {
             limit = read_integer(reader)?.parse()?;
         }
     }
+    let flags = read_flags(reader, context)?;
+    let src = context.compile(&format!("{}{}", flags, pattern))?;
+    if context.options.expand_env {
+        // the named groups of the regex take precedence
+        let groups = src.0.capture_names().flatten().collect::<Vec<_>>();
+        dst = expand_env(&dst, &groups, false);
+    }
+
+    let cmd = Substitute(src, dst, limit);
+    if reader.peek()? == Some('?') {
+        reader.skip();
+        return Ok(IfSubstituted(Box::new(cmd)));
+    }
+    Ok(cmd)
+}
+
+fn parse_text<R: Reader>(reader: &mut R) -> Result<String> {
+    // Parse: 'text' or "text"
+    skip_inline_whitespace(reader);
+    match reader.next()? {
+        Some(c) if c == '\'' || c == '"' => unescape(read_until(reader, c)?),
+        _ => bail!(Error::Missing('\'')),
+    }
+}
+
+fn parse_transliterate<R: Reader>(reader: &mut R) -> Result<Command> {
+    // Parse: y/src/dst/
+    if !reader.next_is('/')? {
+        bail!(Error::Missing('/'));
+    }
+    let src = unescape(read_until(reader, '/')?)?
+        .chars()
+        .collect::<Vec<_>>();
+    let dst = unescape(read_until(reader, '/')?)?
+        .chars()
+        .collect::<Vec<_>>();
+    if src.len() != dst.len() {
+        bail!(
+            "y/src/dst/ needs the same number of characters in src and dst, got {} and {}",
+            src.len(),
+            dst.len()
+        );
+    }
+    Ok(Transliterate(src, dst))
+}
+
+fn parse_split<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Command> {
+    if reader.peek()? != Some('/') {
+        bail!(Error::Missing('/'));
+    }
+    let Some(regex) = parse_regex(reader, context)? else {
+        bail!("empty regular expression");
+    };
+    Ok(Split(regex))
+}
+
+fn parse_field<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Command> {
+    // Parse: fN[/delim/] s/src/dst/[limit]
+    let s = read_integer(reader)?;
+    let n: usize = if s.is_empty() {
+        bail!("missing field number")
+    } else {
+        s.parse()?
+    };
+    if n == 0 {
+        bail!("field numbers need to be >0");
+    }
+    let delim = match reader.peek()? {
+        Some('/') => parse_regex(reader, context)?,
+        _ => None,
+    };
+    let delim = match delim {
+        Some(regex) => regex,
+        None => Regex::from_str(DEFAULT_FIELD_DELIMITER)?,
+    };
+    skip_whitespace(reader);
+    reader.expect('s')?;
+    Ok(Field(
+        n,
+        delim,
+        Box::new(parse_substitute(reader, context)?),
+    ))
+}
 
-    Ok(Substitute(src, dst, limit))
+/// Read the substitution template given as a string, e.g. by the builder,
+/// the same way as the templates in the scripts.
+pub(crate) fn parse_template(template: &str) -> Result<String> {
+    let template = format!("{}/", template.replace('/', "\\/"));
+    read_template(&mut StringReader::from(template.as_str()), false)
 }
 
-fn read_template<R: Reader>(reader: &mut R) -> Result<String> {
+/// Read the substitution template, with the `literal` flag only
+/// the escaped delimiter is unescaped.
+fn read_template<R: Reader>(reader: &mut R, literal: bool) -> Result<String> {
     let delim = '/';
+    let unescape_template = |s: String| {
+        if literal {
+            Ok(s.replace('\\', "\\\\"))
+        } else {
+            unescape_template(s)
+        }
+    };
+    let mut template = String::new();
     let mut acc = String::new();
     while let Some(c) = reader.peek()? {
         match c {
             c if c == delim => {
                 reader.skip();
-                return unescape(acc);
+                template.push_str(&unescape_template(acc)?);
+                return Ok(template);
             }
-            c if c.is_ascii_digit() => {
+            c if c.is_ascii_digit() && is_unescaped_dollar(&acc) => {
                 // replace $N with ${N}
                 // "$123something" string is interpreted as "${123}something" rather than "${123something}"
                 acc.push('{');
@@ -118,14 +388,26 @@ fn read_template<R: Reader>(reader: &mut R) -> Result<String> {
             }
             '\\' => {
                 reader.skip();
-                if let Some(e) = reader.next()? {
-                    if e != delim {
-                        acc.push(c);
-                    }
-                    acc.push(e);
-                } else {
+                let Some(e) = reader.next()? else {
                     break;
+                };
+                // `\u` followed by a hex digit is the `\uXXXX` unicode escape
+                let case = match e {
+                    _ if literal => false,
+                    'U' | 'L' | 'E' | 'l' => true,
+                    'u' => !reader.peek()?.is_some_and(|c| c.is_ascii_hexdigit()),
+                    _ => false,
+                };
+                if case {
+                    template.push_str(&unescape_template(std::mem::take(&mut acc))?);
+                    template.push(c);
+                    template.push(e);
+                    continue;
+                }
+                if e != delim {
+                    acc.push(c);
                 }
+                acc.push(e);
             }
             _ => {
                 reader.skip();
@@ -136,6 +418,17 @@ fn read_template<R: Reader>(reader: &mut R) -> Result<String> {
     bail!(Error::Missing(delim))
 }
 
+/// Unescape the part of the template, escaping the literal backslashes as `\\`
+/// to distinguish them from the case conversions like `\U`.
+fn unescape_template(s: String) -> Result<String> {
+    Ok(unescape(s)?.replace('\\', "\\\\"))
+}
+
+/// Check if the string ends with `$` that is not escaped as `$$`.
+fn is_unescaped_dollar(s: &str) -> bool {
+    s.chars().rev().take_while(|c| *c == '$').count() % 2 == 1
+}
+
 fn parse_keep<R: Reader>(reader: &mut R) -> Result<Command> {
     let s = read_integer(reader)?;
     let lhs = if s.is_empty() {
@@ -171,27 +464,24 @@ fn parse_keep<R: Reader>(reader: &mut R) -> Result<Command> {
     Ok(Keep(lhs, rhs))
 }
 
-fn parse_loop<R: Reader>(reader: &mut R) -> Result<Command> {
+fn parse_loop<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Command> {
     reader.expect('{')?;
-    let mut body = Vec::new();
-    let mut finally = Vec::new();
-    loop {
-        skip_whitespace(reader);
-        match reader.peek()? {
-            Some('}') => {
-                reader.skip();
-                break;
-            }
-            Some(_) => parse_instruction(reader, &mut body, &mut finally)?,
-            None => bail!(Error::Missing('}')),
-        }
-    }
+    let (body, finally) = parse_block(reader, context)?;
     if !finally.is_empty() {
         bail!("loops cannot contain the final block ($)")
     }
     Ok(Loop(body))
 }
 
+fn parse_with_hold<R: Reader>(reader: &mut R, context: &mut ParseContext) -> Result<Command> {
+    reader.expect('{')?;
+    let (body, finally) = parse_block(reader, context)?;
+    if !finally.is_empty() {
+        bail!("the hold space block cannot contain the final block ($)")
+    }
+    Ok(WithHold(body))
+}
+
 fn read_until<R: Reader>(reader: &mut R, delim: char) -> Result<String> {
     let mut acc = String::new();
     while let Some(c) = reader.next()? {
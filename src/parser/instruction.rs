@@ -1,24 +1,58 @@
-use super::{address, command, reader::Reader, utils};
+use super::{address, command, reader::Reader, utils, utils::ParseContext, Error};
 use crate::{address::Address, command::Command, Action};
 use anyhow::{bail, Result};
 
 pub(crate) fn parse_instruction<R: Reader>(
     reader: &mut R,
     actions: &mut Vec<Action>,
-    finally: &mut Vec<Command>,
+    finally: &mut Vec<Action>,
+    context: &mut ParseContext,
 ) -> Result<()> {
     // [address][commands]
     utils::skip_whitespace(reader);
-    let mut address = address::parse(reader)?;
+    let mut address = address::parse(reader, context)?;
     utils::skip_whitespace(reader);
-    let commands = command::parse(reader)?;
 
-    if address == Address::Final {
-        for cmd in commands.into_iter() {
-            finally.push(cmd);
+    let is_final_block = address == Address::Final && reader.next_is('{')?;
+    if is_final_block {
+        // $ { instructions }
+        let (body, nested) = parse_block(reader, context)?;
+        if !nested.is_empty() {
+            bail!("the final block cannot contain another final block ($)")
+        }
+        finally.extend(body);
+        utils::skip_whitespace(reader);
+    }
+
+    let commands = command::parse(reader, context)?;
+
+    if address == Address::Implicit && !context.options.preserve_implicit_any {
+        address = Address::Always;
+    }
+
+    if is_final_block {
+        if !commands.is_empty() {
+            finally.push(Action::Condition(Address::Always, commands.len()));
+            for cmd in commands.into_iter() {
+                finally.push(Action::Command(cmd));
+            }
         }
     } else {
+        // the final block runs after the end of the input, so it does not look ahead
+        if context.options.streaming && address.looks_ahead() {
+            bail!(
+                "the address '{}' needs to look at the next line, it cannot be used when streaming",
+                address
+            );
+        }
+        if context.options.streaming && address.needs_line_count() {
+            bail!(
+                "the address '{}' needs the number of lines in the input, it cannot be used when streaming",
+                address
+            );
+        }
         address.replace_maybe(commands.first())?;
+        address.set_tab_width(context.options.tab_width);
         actions.push(Action::Condition(address, commands.len()));
         for cmd in commands.into_iter() {
             actions.push(Action::Command(cmd));
@@ -27,10 +61,36 @@ pub(crate) fn parse_instruction<R: Reader>(
     Ok(())
 }
 
+/// Parse the instructions until the closing `}`, the opening `{` should
+/// be already consumed. Returns the instructions and the final block.
+pub(crate) fn parse_block<R: Reader>(
+    reader: &mut R,
+    context: &mut ParseContext,
+) -> Result<(Vec<Action>, Vec<Action>)> {
+    let mut body = Vec::new();
+    let mut finally = Vec::new();
+    loop {
+        utils::skip_whitespace(reader);
+        match reader.peek()? {
+            Some('}') => {
+                reader.skip();
+                break;
+            }
+            Some(_) => parse_instruction(reader, &mut body, &mut finally, context)?,
+            None => bail!(Error::Missing('}')),
+        }
+    }
+    Ok((body, finally))
+}
+
 impl Address {
     fn replace_maybe(&mut self, subst: Option<&Command>) -> Result<()> {
         match self {
             Address::Maybe => {
+                let subst = match subst {
+                    Some(Command::IfSubstituted(cmd)) => Some(cmd.as_ref()),
+                    subst => subst,
+                };
                 let Some(Command::Substitute(regex, _, _)) = subst else {
                     bail!("_ must be followed by a substitution")
                 };
@@ -45,4 +105,17 @@ impl Address {
         }
         Ok(())
     }
+
+    fn set_tab_width(&mut self, width: usize) {
+        match self {
+            Address::Indent { tab_width, .. } => *tab_width = width,
+            Address::Negate(addr) => addr.set_tab_width(width),
+            Address::Between(between) => {
+                between.lhs.set_tab_width(width);
+                between.rhs.set_tab_width(width);
+            }
+            Address::Set(addrs) => addrs.iter_mut().for_each(|a| a.set_tab_width(width)),
+            _ => (),
+        }
+    }
 }
@@ -6,13 +6,125 @@ mod reader;
 mod regex_reader;
 mod utils;
 
+pub(crate) use command::parse_template;
 #[cfg(test)]
 pub(crate) use reader::StringReader;
+#[cfg(test)]
+pub(crate) use utils::ParseContext;
+
+/// The grammar of the scripts.
+pub const GRAMMAR: &str = r#"Location       = [1-9][0-9]*
+Step           = [0-9]+ '~' [1-9][0-9]*
+Percent        = [0-9]+ '%'
+Regex          = '/' [^/]* '/'
+WholeLine      = '^' [^$]* '$'
+Flags          = [im]+
+Matcher        = ( Regex | WholeLine ) Flags?
+NthMatch       = Matcher '#' [1-9][0-9]*
+CountAtLeast   = Matcher '{' [1-9][0-9]* ',' '}'
+FollowedBy     = Matcher '>' Matcher
+Indent         = 'indent' ( '<' | '=' | '>' ) [0-9]+
+AddressAtom    = '$' | '_' | '?' | '\0' | Location | Step | Percent | Matcher | '=' Regex | NthMatch | CountAtLeast | FollowedBy | Indent
+Range          = AddressAtom? '-' ( '<'? ( AddressAtom | '+' [0-9]+ ) )?
+Brackets       = AddressAtom | '(' Address ')'
+Negated        = ( Brackets | Range ) '!'?
+Address        = ( Negated ',' )* Negated
+
+Substitute     = 's' Regex [^/]* '/' ( [1-9][0-9]* | 'g' )? Flags? '?'?
+String         = '"' [^"]* '"' | "'" [^']* "'"
+Quit           = 'q' String? [0-9]*
+Keep           = 'k' ([1-9][0-9]*)? '-' ([1-9][0-9]*)?
+Readln         = 'r' ( [0-9]* | String )
+Split          = 'F' Regex
+Transliterate  = 'y' '/' [^/]* '/' [^/]* '/'
+Field          = 'f' [1-9][0-9]* Regex? Substitute
+Append         = 'A' [A-Za-z0-9_]+
+Write          = 'w' [^;\n]+
+Register       = [ghx] ( [0-9] | String )?
+Text           = [aci] String
+Trim           = 'T' ( '<' | '>' )?
+Hash           = 'C' ( 'crc32' | 'sha256' )
+Loop           = ':' '{' Script '}'
+WithHold       = 'H' '{' Script '}'
+Label          = ':' [A-Za-z0-9_]+
+Branch         = [bt] [ \t]+ [A-Za-z0-9_]+
+Command        = [=bdejJlLnNpPRtUz~] | Quit | Keep | Readln | Split | Transliterate | Field | Append | Write | Register | Text | Trim | Hash | String | Substitute | Loop | WithHold | Label | Branch
+
+Comment        = '#' [^\n]*
+Instruction    = Address? Command* | '$' '{' Script '}' Command*
+Script         = ( Instruction ( ';' | '.' ) )* Instruction?
+"#;
+
+/// Width of the tab used when measuring the indentation.
+pub(crate) const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// Options altering how the script is parsed.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Keep the instructions with no address distinct from the ones using `//`.
+    /// Both match all the lines, but they are displayed differently.
+    pub preserve_implicit_any: bool,
+    /// Width of the tab used by the `indent` addresses.
+    pub tab_width: usize,
+    /// Replace `${NAME}` in the regular expressions and the substitution templates
+    /// with the values of the environment variables, and allow the `${env:...}`
+    /// function looking them up for each match.
+    pub expand_env: bool,
+    /// Use the multi-line mode, where `^` and `$` match at the line breaks,
+    /// for all the regular expressions in the addresses and substitutions.
+    pub multiline: bool,
+    /// Limit of the size in bytes of each compiled regular expression,
+    /// e.g. when the scripts come from untrusted sources.
+    pub regex_size_limit: Option<usize>,
+    /// Reject the addresses that need to wait for the next line (`$` and `/a/>/b/`)
+    /// and the `A` command that keeps the lines until the end of the input,
+    /// so that the script can process unbounded streams.
+    pub streaming: bool,
+    /// Don't interpret the escape sequences like `\n` or `\U` in the substitution templates,
+    /// except for the escaped `/` delimiter. The `$1` or `${name}` groups are still expanded.
+    pub literal_replacement: bool,
+    /// Treat the patterns of all the regular expressions as literal strings, like `grep -F`.
+    /// The `^...$` patterns still match the whole line.
+    pub fixed_strings: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            preserve_implicit_any: false,
+            tab_width: DEFAULT_TAB_WIDTH,
+            expand_env: false,
+            multiline: false,
+            regex_size_limit: None,
+            streaming: false,
+            literal_replacement: false,
+            fixed_strings: false,
+        }
+    }
+}
+
+/// Summary of the parsed script.
+#[derive(Debug, Default, PartialEq)]
+pub struct ParseStats {
+    /// Number of the instructions, including the ones in loops
+    /// and in the final block.
+    pub instructions: usize,
+    /// Number of the commands, including the loops themselves.
+    pub commands: usize,
+    /// Number of the regular expressions in addresses and commands.
+    pub regexes: usize,
+}
 
 #[derive(Debug)]
 pub enum Error {
     Missing(char),
     Unexpected(char),
+    /// The error with the line and column in the script where parsing stopped.
+    Syntax {
+        msg: String,
+        line: usize,
+        col: usize,
+    },
 }
 
 impl std::fmt::Display for Error {
@@ -21,6 +133,41 @@ impl std::fmt::Display for Error {
         match self {
             Missing(c) => write!(f, "missing '{}'", c),
             Unexpected(c) => write!(f, "unexpected '{}'", c),
+            Syntax { msg, line, col } => write!(f, "{}:{}: {}", line, col, msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command, reader::StringReader, Error, ParseContext, ParseOptions, GRAMMAR};
+
+    #[test]
+    fn grammar_mentions_all_commands() {
+        for c in (0..=127u8).map(char::from).filter(|c| c.is_ascii_graphic()) {
+            let script = c.to_string();
+            let reader = &mut StringReader::from(script.as_str());
+            let accepted = match command::parse(
+                reader,
+                &mut ParseContext::new(&ParseOptions::default()),
+            ) {
+                Err(err) => {
+                    !matches!(err.downcast_ref::<Error>(), Some(Error::Unexpected(u)) if *u == c)
+                }
+                Ok(_) => true,
+            };
+            if accepted {
+                assert!(
+                    GRAMMAR.contains(c),
+                    "command '{}' is not mentioned in the grammar",
+                    c
+                );
+            }
         }
     }
+
+    #[test]
+    fn grammar_is_documented() {
+        assert!(include_str!("../../README.md").contains(GRAMMAR))
+    }
 }
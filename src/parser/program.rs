@@ -1,19 +1,116 @@
 use super::{
     instruction::parse_instruction,
     reader::{FileReader, Reader, StringReader},
-    utils::skip_whitespace,
+    utils::{skip_whitespace, ParseContext},
+    Error, ParseOptions, ParseStats,
 };
-use crate::{command::Command, program::Program, Action};
-use anyhow::Result;
-use std::str::FromStr;
+use crate::{address::Address, command::Command, program::Program, Action};
+use anyhow::{anyhow, bail, Result};
+use std::{collections::HashSet, path::PathBuf, str::FromStr};
 
-impl TryFrom<&std::path::PathBuf> for Program {
+impl Program {
+    /// Parse the script using custom `options`.
+    pub fn parse_str(s: &str, options: &ParseOptions) -> Result<Self> {
+        let reader = &mut StringReader::from(s);
+        let (actions, finally) = parse(reader, options)?;
+        Ok(Program::new(actions, finally))
+    }
+
+    /// Parse the script from a file using custom `options`.
+    pub fn parse_file(path: &PathBuf, options: &ParseOptions) -> Result<Self> {
+        let reader = &mut FileReader::try_from(path)?;
+        let (actions, finally) = parse(reader, options)?;
+        Ok(Program::new(actions, finally))
+    }
+
+    /// Parse the script using custom `options` and summarize it.
+    pub fn parse_with_stats(s: &str, options: &ParseOptions) -> Result<(Self, ParseStats)> {
+        let program = Program::parse_str(s, options)?;
+        let stats = program.stats();
+        Ok((program, stats))
+    }
+
+    /// Summarize the parsed script.
+    pub fn stats(&self) -> ParseStats {
+        let mut stats = ParseStats::default();
+        tally_actions(&self.actions, &mut stats);
+        tally_actions(&self.finally, &mut stats);
+        stats
+    }
+}
+
+fn tally_actions(actions: &[Action], stats: &mut ParseStats) {
+    for action in actions.iter() {
+        match action {
+            Action::Condition(addr, _) => {
+                stats.instructions += 1;
+                stats.regexes += count_regexes(addr);
+            }
+            Action::Command(cmd) => tally_command(cmd, stats),
+        }
+    }
+}
+
+fn tally_command(cmd: &Command, stats: &mut ParseStats) {
+    stats.commands += 1;
+    match cmd {
+        Command::Substitute(..) | Command::Split(_) | Command::IfSubstituted(_) => {
+            stats.regexes += 1
+        }
+        // the delimiter and the substitution
+        Command::Field(..) => stats.regexes += 2,
+        Command::Loop(body) | Command::WithHold(body) => tally_actions(body, stats),
+        _ => (),
+    }
+}
+
+/// Check that the labels are unique and the branches jump to the labels
+/// defined in the same block.
+fn check_labels(actions: &[Action]) -> Result<()> {
+    let mut labels = HashSet::new();
+    for action in actions.iter() {
+        if let Action::Command(Command::Label(name)) = action {
+            if !labels.insert(name) {
+                bail!("duplicate label '{}'", name);
+            }
+        }
+    }
+    for action in actions.iter() {
+        match action {
+            Action::Command(Command::Branch(name) | Command::BranchIfSub(name))
+                if !labels.contains(name) =>
+            {
+                bail!(
+                    "undefined label '{}', the branches can jump only within the same block",
+                    name
+                )
+            }
+            Action::Command(Command::Loop(body) | Command::WithHold(body)) => check_labels(body)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn count_regexes(addr: &Address) -> usize {
+    match addr {
+        Address::Regex(_)
+        | Address::LineNumberRegex(_)
+        | Address::NthMatch(_)
+        | Address::CountAtLeast { .. } => 1,
+        Address::FollowedBy(..) => 2,
+        Address::Negate(addr) => count_regexes(addr),
+        Address::Between(this) => count_regexes(&this.lhs) + count_regexes(&this.rhs),
+        Address::Set(addrs) => addrs.iter().map(count_regexes).sum(),
+        _ => 0,
+    }
+}
+
+impl TryFrom<&PathBuf> for Program {
     type Error = anyhow::Error;
 
-    fn try_from(value: &std::path::PathBuf) -> Result<Self, Self::Error> {
-        let reader = &mut FileReader::try_from(value)?;
-        let (actions, finally) = parse(reader)?;
-        Ok(Program(actions, finally))
+    fn try_from(value: &PathBuf) -> Result<Self, Self::Error> {
+        Program::parse_file(value, &ParseOptions::default())
     }
 }
 
@@ -21,29 +118,37 @@ impl FromStr for Program {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let reader = &mut StringReader::from(s);
-        let (actions, finally) = parse(reader)?;
-        Ok(Program(actions, finally))
+        Program::parse_str(s, &ParseOptions::default())
     }
 }
 
-fn parse<R: Reader>(reader: &mut R) -> Result<(Vec<Action>, Vec<Command>)> {
-    let mut actions = Vec::new();
-    let mut finally = Vec::new();
-    while reader.peek()?.is_some() {
-        parse_instruction(reader, &mut actions, &mut finally)?;
-        skip_whitespace(reader);
-    }
-    Ok((actions, finally))
+fn parse<R: Reader>(reader: &mut R, options: &ParseOptions) -> Result<(Vec<Action>, Vec<Action>)> {
+    let mut context = ParseContext::new(options);
+    let mut parse_all = || -> Result<_> {
+        let mut actions = Vec::new();
+        let mut finally = Vec::new();
+        while reader.peek()?.is_some() {
+            parse_instruction(reader, &mut actions, &mut finally, &mut context)?;
+            skip_whitespace(reader);
+        }
+        check_labels(&actions)?;
+        check_labels(&finally)?;
+        Ok((actions, finally))
+    };
+    parse_all().map_err(|err: anyhow::Error| {
+        let (line, col) = reader.position();
+        let msg = err.to_string();
+        anyhow!(Error::Syntax { msg, line, col })
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Program;
+    use super::{ParseOptions, ParseStats, Program};
     use crate::{
         address::{self, Address::*},
-        command::Command::*,
-        Action,
+        command::{Command::*, TrimSide},
+        Action, Line,
     };
     use std::str::FromStr;
     use test_case::test_case;
@@ -168,6 +273,14 @@ mod tests {
                 0,
             )),
     ]); "substitute with numbered group")]
+    #[test_case(r"F/,\s*/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Split(crate::Regex::from_str(r",\s*").unwrap())),
+    ]); "split")]
+    #[test_case(r"/a/ A buf_1", Program::from(vec![
+        Action::Condition(Regex(crate::Regex::from_str("a").unwrap()), 1),
+        Action::Command(Append("buf_1".to_string())),
+    ]); "append to buffer")]
     #[test_case(r"1d;3d;7d", Program::from(vec![
         Action::Condition(Location(1), 1),
         Action::Command(Delete),
@@ -212,8 +325,450 @@ mod tests {
                 5,
             )),
     ]); "maybe in set")]
+    #[test_case("f2/,/ s/a/b/1", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Field(
+            2,
+            crate::Regex::from_str(",").unwrap(),
+            Box::new(Substitute(crate::Regex::from_str("a").unwrap(), "b".to_string(), 1)),
+        )),
+    ]); "substitute in field")]
+    #[test_case("s/a/b/ s/c/d/2i?p", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(Substitute(crate::Regex::from_str("a").unwrap(), "b".to_string(), 0)),
+        Action::Command(IfSubstituted(Box::new(
+            Substitute(crate::Regex::from_str("(?i)c").unwrap(), "d".to_string(), 2),
+        ))),
+        Action::Command(Println),
+    ]); "conditional substitute")]
+    #[test_case("s/x/a1$1$$2/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Substitute(crate::Regex::from_str("x").unwrap(), "a1${1}$$2".to_string(), 0)),
+    ]); "digits in template")]
+    #[test_case(r"s/(\w+)/\U$1\E\\\u0041\u$2/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Substitute(crate::Regex::from_str(r"(\w+)").unwrap(), r"\U${1}\E\\A\u${2}".to_string(), 0)),
+    ]); "case conversion in template")]
+    #[test_case("q 'build failed' 2", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Quit(2, Some("build failed".to_string()))),
+    ]); "quit with message")]
+    #[test_case("q\"done\"", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Quit(0, Some("done".to_string()))),
+    ]); "quit with message and no code")]
+    #[test_case("q \n 'next'", Program::from(vec![
+        Action::Condition(Always, 2),
+        Action::Command(Quit(0, None)),
+        Action::Command(Insert("next".to_string())),
+    ]); "quit ends at newline")]
+    #[test_case("r\t2 \n p", Program::from(vec![
+        Action::Condition(Always, 2),
+        Action::Command(Readln(2)),
+        Action::Command(Println),
+    ]); "readln ends at newline")]
+    #[test_case("r 'a b.txt' r\"x\"r3", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(ReadFile("a b.txt".into())),
+        Action::Command(ReadFile("x".into())),
+        Action::Command(Readln(3)),
+    ]); "read file")]
+    #[test_case("/ABC/i s/x/y/gmi", Program::from(vec![
+        Action::Condition(Regex(crate::Regex::from_str("(?i)ABC").unwrap()), 1),
+        Action::Command(Substitute(crate::Regex::from_str("(?mi)x").unwrap(), "y".to_string(), 0)),
+    ]); "regex flags")]
+    #[test_case("/a/ix", Program::from(vec![
+        Action::Condition(Regex(crate::Regex::from_str("(?i)a").unwrap()), 1),
+        Action::Command(Exchange(None)),
+    ]); "regex flag followed by command")]
+    #[test_case("x2p x x0", Program::from(vec![
+        Action::Condition(Always, 4),
+        Action::Command(Exchange(Some('2'))),
+        Action::Command(Println),
+        Action::Command(Exchange(None)),
+        Action::Command(Exchange(Some('0'))),
+    ]); "exchange with registers")]
+    #[test_case("h1 g h g2p", Program::from(vec![
+        Action::Condition(Always, 5),
+        Action::Command(Hold(Some('1'))),
+        Action::Command(Get(None)),
+        Action::Command(Hold(None)),
+        Action::Command(Get(Some('2'))),
+        Action::Command(Println),
+    ]); "hold and get with registers")]
+    #[test_case("h'a' g\"b\"p x 'c'", Program::from(vec![
+        Action::Condition(Always, 5),
+        Action::Command(Hold(Some('a'))),
+        Action::Command(Get(Some('b'))),
+        Action::Command(Println),
+        Action::Command(Exchange(None)),
+        Action::Command(Insert("c".to_string())),
+    ]); "named registers")]
+    #[test_case(r"y/a\/\tą/b|\nę/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Transliterate(vec!['a', '/', '\t', 'ą'], vec!['b', '|', '\n', 'ę'])),
+    ]); "transliterate")]
+    #[test_case("a 'x\ty' i\"z\" '1'", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(AppendAfter("x\ty".to_string())),
+        Action::Command(InsertBefore("z".to_string())),
+        Action::Command(Insert("1".to_string())),
+    ]); "append and insert")]
+    #[test_case("i'x' ; indent>2 i \"y\"", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(InsertBefore("x".to_string())),
+        Action::Condition(Indent { op: std::cmp::Ordering::Greater, n: 2, tab_width: 8 }, 1),
+        Action::Command(InsertBefore("y".to_string())),
+    ]); "insert and indent")]
+    #[test_case("w  out file.txt \n p ; w x", Program::from(vec![
+        Action::Condition(Always, 2),
+        Action::Command(Write("out file.txt".into())),
+        Action::Command(Println),
+        Action::Condition(Always, 1),
+        Action::Command(Write("x".into())),
+    ]); "write")]
+    #[test_case("T T< T>p", Program::from(vec![
+        Action::Condition(Always, 4),
+        Action::Command(Trim(TrimSide::Both)),
+        Action::Command(Trim(TrimSide::Start)),
+        Action::Command(Trim(TrimSide::End)),
+        Action::Command(Println),
+    ]); "trim")]
+    #[test_case(":a s/x/y/ t a\n b a ; =tp ; t 'x' ; t p q", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(Label("a".to_string())),
+        Action::Command(Substitute(crate::Regex::from_str("x").unwrap(), "y".to_string(), 0)),
+        Action::Command(BranchIfSub("a".to_string())),
+        Action::Condition(Always, 1),
+        Action::Command(Branch("a".to_string())),
+        Action::Condition(Always, 3),
+        Action::Command(LineNumber),
+        Action::Command(Insert("\t".to_string())),
+        Action::Command(Println),
+        Action::Condition(Always, 2),
+        Action::Command(Insert("\t".to_string())),
+        Action::Command(Insert("x".to_string())),
+        Action::Condition(Always, 3),
+        Action::Command(Insert("\t".to_string())),
+        Action::Command(Println),
+        Action::Command(Quit(0, None)),
+    ]); "labels and tabs")]
     fn parse(input: &str, expected: Program) {
         let result = Program::from_str(input).unwrap();
         assert_eq!(result, expected)
     }
+
+    #[test]
+    fn preserve_implicit_any() {
+        let options = ParseOptions {
+            preserve_implicit_any: true,
+            ..Default::default()
+        };
+        let implicit = Program::parse_str("p", &options).unwrap();
+        let explicit = Program::parse_str("//p", &options).unwrap();
+        assert_ne!(implicit, explicit);
+
+        let (Action::Condition(lhs, _), Action::Condition(rhs, _)) =
+            (&implicit.actions[0], &explicit.actions[0])
+        else {
+            unreachable!()
+        };
+        assert_eq!(lhs.to_string(), "");
+        assert_eq!(rhs.to_string(), "//");
+        for i in 1..5 {
+            let line = Line(i, i.to_string());
+            assert_eq!(
+                lhs.matches(&line, None, false),
+                rhs.matches(&line, None, false)
+            );
+        }
+
+        // by default both are the same
+        assert_eq!(
+            Program::from_str("p").unwrap(),
+            Program::from_str("//p").unwrap()
+        );
+    }
+
+    #[test_case("", ParseStats::default(); "empty")]
+    #[test_case("p", ParseStats {
+        instructions: 1,
+        commands: 1,
+        regexes: 0,
+    }; "print")]
+    #[test_case("/a/-/b/, 3! s/x/y/ p ; 5 d ; :{ /c/ . R } ; $ '\n' =", ParseStats {
+        instructions: 6,
+        commands: 8,
+        regexes: 4,
+    }; "complex")]
+    fn stats(input: &str, expected: ParseStats) {
+        let (_, stats) = Program::parse_with_stats(input, &ParseOptions::default()).unwrap();
+        assert_eq!(stats, expected)
+    }
+
+    #[test]
+    fn final_block() {
+        let result = Program::from_str("p ; $ { /a/ p ; 'end' } = ; $ { q }").unwrap();
+        let expected = super::Program {
+            actions: vec![Action::Condition(Always, 1), Action::Command(Println)],
+            finally: vec![
+                Action::Condition(Regex(crate::Regex::from_str("a").unwrap()), 1),
+                Action::Command(Println),
+                Action::Condition(Always, 1),
+                Action::Command(Insert("end".to_string())),
+                Action::Condition(Always, 1),
+                Action::Command(LineNumber),
+                Action::Condition(Always, 1),
+                Action::Command(Quit(0, None)),
+            ],
+            options: Default::default(),
+        };
+        assert_eq!(result, expected);
+    }
+
+    #[test_case("$ { $ { p } }"; "nested final block")]
+    #[test_case(":{ $ { p } }"; "final block in loop")]
+    #[test_case("$ { p "; "unclosed final block")]
+    #[test_case("2~0 p"; "zero step")]
+    #[test_case("y/abc/AB/"; "transliterate different lengths")]
+    #[test_case("y/abc/ABC"; "transliterate unclosed")]
+    #[test_case("a p"; "append without text")]
+    #[test_case("w ; p"; "write without path")]
+    #[test_case("i'text"; "insert unclosed")]
+    #[test_case("2~p"; "missing step")]
+    #[test_case("101% p"; "percentage over 100")]
+    #[test_case("/a/{0,} p"; "zero count")]
+    #[test_case("/a/{3} p"; "exact count")]
+    #[test_case("/a/{,3} p"; "count at most")]
+    #[test_case("C md5"; "unknown hash")]
+    #[test_case("h12"; "multi-digit register")]
+    #[test_case("g'ab'"; "multi-character register")]
+    #[test_case("x''"; "empty register")]
+    #[test_case("C ; p"; "hash without name")]
+    #[test_case("/a/-< p"; "exclusive range without end")]
+    #[test_case("3-<3 p"; "empty exclusive range")]
+    #[test_case("1!! p"; "double negation without brackets")]
+    #[test_case("1!-3 p"; "range from negated address")]
+    #[test_case("(1)-3 p"; "range from brackets")]
+    #[test_case("1-(3) p"; "range to brackets")]
+    #[test_case("b a"; "undefined label")]
+    #[test_case(":a ; :a ; b a"; "duplicate label")]
+    #[test_case(":a ; :{ b a }"; "label outside of the loop")]
+    #[test_case(":{ :a } ; b a"; "label inside of the loop")]
+    #[test_case(":a ; b a p"; "command after branch")]
+    #[test_case(": ; p"; "label without name")]
+    fn invalid(input: &str) {
+        assert!(Program::from_str(input).is_err())
+    }
+
+    #[test]
+    fn expand_env() {
+        std::env::set_var("SE_TEST_USER", "alice");
+        let options = ParseOptions {
+            expand_env: true,
+            ..Default::default()
+        };
+        let script = "/${SE_TEST_USER}/ s/x/${SE_TEST_USER}-${SE_TEST_UNSET}/ ; \
+            s/(?P<SE_TEST_USER>y)/${SE_TEST_USER}/";
+        assert_eq!(
+            Program::parse_str(script, &options).unwrap(),
+            Program::from_str(
+                "/alice/ s/x/alice-${SE_TEST_UNSET}/ ; s/(?P<SE_TEST_USER>y)/${SE_TEST_USER}/"
+            )
+            .unwrap()
+        );
+
+        // not expanded by default
+        assert_eq!(
+            Program::from_str("s/x/${SE_TEST_USER}/").unwrap(),
+            Program::from(vec![
+                Action::Condition(Always, 1),
+                Action::Command(Substitute(
+                    crate::Regex::from_str("x").unwrap(),
+                    "${SE_TEST_USER}".to_string(),
+                    0
+                )),
+            ])
+        );
+        assert!(Program::from_str("/${SE_TEST_USER}/").is_err());
+    }
+
+    #[test]
+    fn expand_env_literally() {
+        std::env::set_var("SE_TEST_DOTTED", "a.b");
+        std::env::set_var("SE_TEST_PAREN", "(");
+        let options = ParseOptions {
+            expand_env: true,
+            ..Default::default()
+        };
+        let program = Program::parse_str("/${SE_TEST_DOTTED}/ p", &options).unwrap();
+        let mut out = Vec::new();
+        program.process("a.b\naxb\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a.b\n");
+
+        let program = Program::parse_str("s/${SE_TEST_PAREN}/[/ p", &options).unwrap();
+        let mut out = Vec::new();
+        program.process("f(x\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "f[x\n");
+    }
+
+    #[test]
+    fn env_function() {
+        std::env::set_var("SE_TEST_GREETING", "hello");
+        let script = r"s/\$\{(\w+)\}/${env:$1:?}/ p";
+        assert!(Program::from_str(script).is_err());
+        let options = ParseOptions {
+            expand_env: true,
+            ..Default::default()
+        };
+        let program = Program::parse_str(script, &options).unwrap();
+        let mut out = Vec::new();
+        program
+            .process(
+                "${SE_TEST_GREETING} ${SE_TEST_UNSET}\n".as_bytes(),
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "hello ?\n");
+    }
+
+    #[test_case("1d", true; "line number")]
+    #[test_case("/a/-/b/ p ; 3-+2 d ; ? p", true; "ranges")]
+    #[test_case(":{ /a/ R ; /b/ . }", true; "in loop")]
+    #[test_case("$d", false; "last line")]
+    #[test_case("$ { p }", true; "final block")]
+    #[test_case("$ { $ p }", false; "last line in final block")]
+    #[test_case("1-$ p", false; "range to last line")]
+    #[test_case("/a/-</b/ p", false; "exclusive range")]
+    #[test_case("1, ($)! p", false; "negated last line")]
+    #[test_case(":{ /a/>/b/ . }", false; "followed by in loop")]
+    #[test_case("A buf", false; "named buffer")]
+    #[test_case("50% p", false; "percentage")]
+    fn streaming(script: &str, accepted: bool) {
+        let options = ParseOptions {
+            streaming: true,
+            ..Default::default()
+        };
+        assert_eq!(Program::parse_str(script, &options).is_ok(), accepted);
+        assert!(Program::from_str(script).is_ok());
+    }
+
+    #[test_case(r"s/x/\n/", "\n", r"\\n"; "newline")]
+    #[test_case(r"s/x/\t$1\//", "\t${1}/", r"\\t${1}/"; "escaped delimiter")]
+    #[test_case(r"s/x/\U$$\\/", r"\U$$\\", r"\\U$$\\\\"; "case conversion")]
+    fn literal_replacement(script: &str, interpreted: &str, literal: &str) {
+        let template = |program: Program| match &program.actions[1] {
+            Action::Command(Substitute(_, template, _)) => template.clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(template(Program::from_str(script).unwrap()), interpreted);
+        let options = ParseOptions {
+            literal_replacement: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            template(Program::parse_str(script, &options).unwrap()),
+            literal
+        );
+    }
+
+    #[test]
+    fn multiline() {
+        let options = ParseOptions {
+            multiline: true,
+            ..Default::default()
+        };
+        let program = Program::parse_str("r2 ; /^b$/ s/^/> /gi", &options).unwrap();
+        assert_eq!(
+            program,
+            Program::from_str("r2 ; /(?m)^b$/ s/(?mi)^/> /").unwrap()
+        );
+
+        assert_eq!(program.output(&["a", "b", "c"], true), "> a\n> b\n> c\n");
+    }
+
+    #[test_case("/a.b/ p", "a.b\n"; "dot")]
+    #[test_case("/a.b/i p", "A.B\na.b\n"; "with flags")]
+    #[test_case("^a.b$ p", "a.b\n"; "whole line")]
+    #[test_case(r"s/(.)/[\/]/ p", "[/]\nab\naxb\nA.B\na.b\n[/]\n"; "substitute")]
+    fn fixed_strings(script: &str, expected: &str) {
+        let options = ParseOptions {
+            fixed_strings: true,
+            ..Default::default()
+        };
+        let program = Program::parse_str(script, &options).unwrap();
+        assert_eq!(
+            program.output(&["(.)", "ab", "axb", "A.B", "a.b", "(.)"], false),
+            expected
+        );
+    }
+
+    #[test_case("p }", "1:3: unexpected '}'"; "unexpected")]
+    #[test_case("1p ;\n/a/ {\n  p", "2:5: unexpected '{'"; "second line")]
+    #[test_case("p ;\n\n  2~0 p", "3:5: invalid step: 2~0"; "message")]
+    #[test_case(":{ p\n", "1:5: missing '}'"; "at the end")]
+    fn error_position(script: &str, expected: &str) {
+        let err = Program::from_str(script).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[test]
+    fn shared_regexes() {
+        let regex = |action: &Action| match action {
+            Action::Condition(Regex(regex), _) => regex.0.clone(),
+            Action::Command(Substitute(regex, ..)) => regex.0.clone(),
+            _ => unreachable!(),
+        };
+        let program = Program::from_str("/ERROR/ p ; /ERROR/ d ; s/ERROR/x/ ; /ERROR/i p").unwrap();
+        let first = regex(&program.actions[0]);
+        assert!(std::sync::Arc::ptr_eq(&first, &regex(&program.actions[2])));
+        assert!(std::sync::Arc::ptr_eq(&first, &regex(&program.actions[5])));
+        assert!(!std::sync::Arc::ptr_eq(&first, &regex(&program.actions[6])));
+
+        // the regexes are not shared between the scripts
+        let other = Program::from_str("/ERROR/ p").unwrap();
+        assert!(!std::sync::Arc::ptr_eq(&first, &regex(&other.actions[0])));
+    }
+
+    #[test_case("C crc32", cfg!(feature = "crc"); "crc32")]
+    #[test_case("C sha256 p", cfg!(feature = "sha2"); "sha256")]
+    #[test_case("Csha256", cfg!(feature = "sha2"); "without space")]
+    fn hash(script: &str, enabled: bool) {
+        let result = Program::from_str(script);
+        if enabled {
+            let program = result.unwrap();
+            assert!(matches!(program.actions[1], Action::Command(Hash(_))));
+        } else {
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("needs se to be built with"), "{}", err);
+        }
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("p"; "print")]
+    #[test_case("/a/ p ; 2 d ; $ p"; "instructions")]
+    #[test_case(r"/a\/b/i s/x\/y/[$1]\n\t\/\\/2 ; s/a/\Ub/ ; s/(?<n>a)/${n}$$0/g"; "substitutions")]
+    #[test_case("s/a/A/ s/b/B/? ; _ s/c/C/p"; "conditional substitution")]
+    #[test_case(r#"'it''s' "\"quoted\"\n" a'a\\b' i"\u0001" c'%n' n t ="#; "strings")]
+    #[test_case("1-3 p ; /a/-+2 p ; 2- p ; -/b/ p ; (1-2)! p ; 3-4! p ; /a/-</b/ p ; 2-<+3! p"; "ranges")]
+    #[test_case("1, 3, /a/ p ; (/a/, /b/)! p ; /a/, /b/ d ; (2, /c/)! d"; "sets")]
+    #[test_case(r"$! p ; 0~2 p ; 50% p ; =/0$/ p ; /a/#2 p ; /a/{3,} p ; /a/>/b/ p ; = ; =/1/ ="; "addresses")]
+    #[test_case(r"indent>4 p ; indent=0 p ; ? p ; \0 p ; ^ab$ p"; "more addresses")]
+    #[test_case("k2- k1-3 k4 y/a\\//\\/b/ F/,/ f2/;/ s/a/b/ ~ U L T T< T>"; "editing")]
+    #[test_case("h g x h1 g2 x3 h'a' g'b' x'\\'' j J A buf r r3 r'file' N R z e"; "buffers")]
+    #[test_case("w out.txt\np ; /a/ w other file.txt"; "write")]
+    #[test_case("/a/ p . 2 q ; 3 q 5 ; 4 q'stop' 2 ; /x/ b ; p"; "stop")]
+    #[test_case(":{ /a/ s/a/b/ ; /b/ . } H{ s/^/x/ ; x } p"; "blocks")]
+    #[test_case("p ; $ { /a/ p ; 'end' } = ; $ q"; "final block")]
+    #[test_case("$ { p }"; "only final block")]
+    #[test_case(":a ; s/a/b/ ; /b/ t a ; 2 b end ; p ; :end :{ :x . b x }"; "labels")]
+    #[test_case("/a/ ; ; p"; "empty instructions")]
+    fn round_trip(script: &str) {
+        let program = Program::from_str(script).unwrap();
+        let formatted = program.to_string();
+        let parsed = Program::from_str(&formatted)
+            .unwrap_or_else(|err| panic!("cannot parse '{}': {}", formatted, err));
+        assert_eq!(parsed, program, "{}", formatted);
+        assert_eq!(parsed.to_string(), formatted);
+    }
 }
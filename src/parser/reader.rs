@@ -1,16 +1,29 @@
 use super::Error;
 use anyhow::{bail, Result};
 use std::{
+    collections::VecDeque,
     fs::File,
     io::{BufRead, BufReader, Lines},
-    iter::Peekable,
-    path::PathBuf,
-    vec::IntoIter,
+    iter::Enumerate,
+    path::{Path, PathBuf},
+    str::Chars,
 };
 
+/// How deeply the `@include` directives can be nested.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
 pub(crate) trait Reader {
     fn next(&mut self) -> Result<Option<char>>;
-    fn peek(&mut self) -> Result<Option<char>>;
+    /// The `n`-th of the following characters without consuming any of them,
+    /// `peek_n(0)` is the character that would be returned by `next`.
+    fn peek_n(&mut self, n: usize) -> Result<Option<char>>;
+    /// Line and column (both starting at 1) of the last consumed character,
+    /// the column is 0 if nothing was consumed in the line yet.
+    fn position(&self) -> (usize, usize);
+
+    fn peek(&mut self) -> Result<Option<char>> {
+        self.peek_n(0)
+    }
 
     fn skip(&mut self) {
         self.next().unwrap();
@@ -35,74 +48,349 @@ pub(crate) trait Reader {
     }
 }
 
-pub(crate) struct StringReader(Peekable<IntoIter<char>>);
+/// Position of the last consumed character.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    line: usize,
+    col: usize,
+    after_newline: bool,
+}
 
-impl From<&str> for StringReader {
-    fn from(value: &str) -> Self {
-        StringReader(value.chars().collect::<Vec<char>>().into_iter().peekable())
+impl Default for Position {
+    fn default() -> Self {
+        Position {
+            line: 1,
+            col: 0,
+            after_newline: false,
+        }
     }
 }
 
-impl Reader for StringReader {
+impl Position {
+    /// Move past the consumed character `c`. The newline belongs to the line it ends.
+    fn advance(&mut self, c: char) {
+        if self.after_newline {
+            self.line += 1;
+            self.col = 0;
+        }
+        self.col += 1;
+        self.after_newline = c == '\n';
+    }
+}
+
+/// Reads the characters of the borrowed string, without copying it.
+pub(crate) struct StringReader<'a> {
+    chars: Chars<'a>,
+    position: Position,
+}
+
+impl<'a> From<&'a str> for StringReader<'a> {
+    fn from(value: &'a str) -> Self {
+        StringReader {
+            chars: value.chars(),
+            position: Position::default(),
+        }
+    }
+}
+
+impl Reader for StringReader<'_> {
     fn next(&mut self) -> Result<Option<char>> {
-        Ok(self.0.next())
+        let c = self.chars.next();
+        if let Some(c) = c {
+            self.position.advance(c);
+        }
+        Ok(c)
     }
 
-    fn peek(&mut self) -> Result<Option<char>> {
-        Ok(self.0.peek().cloned())
+    fn peek_n(&mut self, n: usize) -> Result<Option<char>> {
+        // cloning the iterator only copies the pointers to the rest of the string
+        Ok(self.chars.clone().nth(n))
+    }
+
+    fn position(&self) -> (usize, usize) {
+        (self.position.line, self.position.col)
     }
 }
 
+/// Reads the script file, splicing in the files from `@include path` lines.
 pub(crate) struct FileReader {
-    file: Lines<BufReader<File>>,
-    buffer: StringReader,
+    // the stack of the files being read, the included ones at the top
+    files: Vec<(PathBuf, Enumerate<Lines<BufReader<File>>>)>,
+    // the characters read from the files, but not consumed yet
+    buffer: VecDeque<char>,
+    // the numbers of the lines in the buffer, in the files they come from
+    line_numbers: VecDeque<usize>,
+    // the column is tracked in the line at the front of the `line_numbers`
+    position: Position,
+    // errors are repeated, so they are not lost when the parser ignores them
+    error: Option<String>,
 }
 
 impl TryFrom<&PathBuf> for FileReader {
     type Error = anyhow::Error;
 
     fn try_from(value: &PathBuf) -> Result<Self, Self::Error> {
-        let file = BufReader::new(File::open(value)?).lines();
-        let chars = StringReader::from("");
-        Ok(FileReader {
-            file,
-            buffer: chars,
-        })
+        let mut reader = FileReader {
+            files: Vec::new(),
+            buffer: VecDeque::new(),
+            line_numbers: VecDeque::new(),
+            position: Position::default(),
+            error: None,
+        };
+        reader.open(value)?;
+        Ok(reader)
     }
 }
 
 impl Reader for FileReader {
     fn next(&mut self) -> Result<Option<char>> {
-        loop {
-            if let c @ Some(_) = self.buffer.next()? {
-                return Ok(c);
-            }
-            if !self.next_line()? {
-                return Ok(None);
+        if self.buffer.is_empty() && !self.next_line()? {
+            return Ok(None);
+        }
+        let c = self.buffer.pop_front();
+        if let Some(c) = c {
+            if self.position.after_newline {
+                self.line_numbers.pop_front();
             }
+            self.position.advance(c);
         }
+        Ok(c)
     }
 
-    fn peek(&mut self) -> Result<Option<char>> {
-        loop {
-            if let c @ Some(_) = self.buffer.peek()? {
-                return Ok(c);
-            }
+    fn peek_n(&mut self, n: usize) -> Result<Option<char>> {
+        // the following lines are read into the buffer if needed
+        while self.buffer.len() <= n {
             if !self.next_line()? {
                 return Ok(None);
             }
         }
+        Ok(self.buffer.get(n).cloned())
+    }
+
+    fn position(&self) -> (usize, usize) {
+        let line = self.line_numbers.front().copied().unwrap_or(1);
+        (line, self.position.col)
     }
 }
 
 impl FileReader {
+    fn open(&mut self, path: &Path) -> Result<()> {
+        let path = path.canonicalize()?;
+        if self.files.iter().any(|(p, _)| *p == path) {
+            bail!("include cycle: {} includes itself", path.display());
+        }
+        if self.files.len() >= MAX_INCLUDE_DEPTH {
+            bail!("includes nested deeper than {} files", MAX_INCLUDE_DEPTH);
+        }
+        let file = BufReader::new(File::open(&path)?).lines().enumerate();
+        self.files.push((path, file));
+        Ok(())
+    }
+
     fn next_line(&mut self) -> Result<bool> {
-        if let Some(res) = self.file.next() {
+        if let Some(msg) = &self.error {
+            bail!("{}", msg);
+        }
+        self.read_line()
+            .inspect_err(|err| self.error = Some(err.to_string()))
+    }
+
+    fn read_line(&mut self) -> Result<bool> {
+        while let Some((path, file)) = self.files.last_mut() {
+            let Some((index, res)) = file.next() else {
+                self.files.pop();
+                continue;
+            };
             let mut line = res?;
+            // skip the byte order mark at the start of each file, including the included ones
+            if index == 0 && line.starts_with('\u{feff}') {
+                line.remove(0);
+            }
+            if let Some(name) = include_directive(&line) {
+                // the paths are relative to the including file
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let included = dir.join(name);
+                self.open(&included)?;
+                continue;
+            }
             line.push('\n');
-            self.buffer = StringReader::from(line.as_str());
+            self.buffer.extend(line.chars());
+            self.line_numbers.push_back(index + 1);
             return Ok(true);
         }
         Ok(false)
     }
 }
+
+/// Parse the `@include path` line.
+fn include_directive(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix("@include")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let name = rest.trim();
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileReader, Reader, StringReader};
+
+    fn read_all<R: Reader>(reader: &mut R) -> String {
+        let mut acc = String::new();
+        while let Some(c) = reader.next().unwrap() {
+            acc.push(c);
+        }
+        acc
+    }
+
+    #[test]
+    fn peek_n() {
+        let mut reader = StringReader::from("abc");
+        assert_eq!(reader.peek_n(2).unwrap(), Some('c'));
+        assert_eq!(reader.peek_n(3).unwrap(), None);
+        assert_eq!(reader.peek().unwrap(), Some('a'));
+        assert_eq!(read_all(&mut reader), "abc");
+    }
+
+    #[test]
+    fn borrowed_string() {
+        let script = String::from("1p ; 2d");
+        let mut reader = StringReader::from(script.as_str());
+        reader.next().unwrap();
+        reader.peek_n(3).unwrap();
+        // the rest is read directly from the borrowed script
+        assert_eq!(reader.chars.as_str().as_ptr(), script[1..].as_ptr());
+
+        let program = crate::Program::parse_str(&script, &Default::default()).unwrap();
+        assert_eq!(program.actions.len(), 4);
+        assert_eq!(script, "1p ; 2d");
+    }
+
+    #[test]
+    fn position() {
+        let mut reader = StringReader::from("ab\n\nc");
+        let mut positions = vec![reader.position()];
+        while reader.next().unwrap().is_some() {
+            positions.push(reader.position());
+        }
+        assert_eq!(positions, [(1, 0), (1, 1), (1, 2), (1, 3), (2, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn position_in_included_file() {
+        let dir = std::env::temp_dir().join("se-position-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.se"), "\u{feff}1p\n@include other.se\n3p").unwrap();
+        std::fs::write(dir.join("other.se"), "\n2p").unwrap();
+        let mut reader = FileReader::try_from(&dir.join("main.se")).unwrap();
+        let mut positions = Vec::new();
+        while let Some(c) = reader.next().unwrap() {
+            // peeking the next lines doesn't change the position
+            reader.peek_n(3).unwrap();
+            positions.push((c, reader.position()));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            positions,
+            [
+                ('1', (1, 1)),
+                ('p', (1, 2)),
+                ('\n', (1, 3)),
+                ('\n', (1, 1)),
+                ('2', (2, 1)),
+                ('p', (2, 2)),
+                ('\n', (2, 3)),
+                ('3', (3, 1)),
+                ('p', (3, 2)),
+                ('\n', (3, 3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn peek_n_across_lines() {
+        let path = std::env::temp_dir().join("se-peek-n-test.se");
+        std::fs::write(&path, "1p\n\n2p").unwrap();
+        let mut reader = FileReader::try_from(&path).unwrap();
+        let peeked = (0..7)
+            .map(|n| reader.peek_n(n).unwrap())
+            .collect::<Vec<_>>();
+        let result = read_all(&mut reader);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            peeked,
+            [
+                Some('1'),
+                Some('p'),
+                Some('\n'),
+                Some('\n'),
+                Some('2'),
+                Some('p'),
+                Some('\n')
+            ]
+        );
+        assert_eq!(result, "1p\n\n2p\n");
+        assert_eq!(reader.peek_n(0).unwrap(), None);
+    }
+
+    #[test]
+    fn file_with_bom() {
+        let path = std::env::temp_dir().join("se-bom-test.se");
+        std::fs::write(&path, "\u{feff}1p\n\u{feff}2p").unwrap();
+        let mut reader = FileReader::try_from(&path).unwrap();
+        let result = read_all(&mut reader);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, "1p\n\u{feff}2p\n");
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join("se-include-test");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("main.se"), "1p\n  @include lib/other.se\n3p").unwrap();
+        std::fs::write(dir.join("lib/other.se"), "@include last.se\n2p").unwrap();
+        std::fs::write(dir.join("lib/last.se"), "4p").unwrap();
+        let mut reader = FileReader::try_from(&dir.join("main.se")).unwrap();
+        let result = read_all(&mut reader);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, "1p\n4p\n2p\n3p\n");
+    }
+
+    #[test]
+    fn include_with_bom() {
+        let dir = std::env::temp_dir().join("se-include-bom-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.se"), "\u{feff}1p\n@include other.se\n3p").unwrap();
+        std::fs::write(dir.join("other.se"), "\u{feff}2p\n\u{feff}4p").unwrap();
+        std::fs::write(dir.join("directive.se"), "\u{feff}@include other.se").unwrap();
+        let mut reader = FileReader::try_from(&dir.join("main.se")).unwrap();
+        let result = read_all(&mut reader);
+        let mut reader = FileReader::try_from(&dir.join("directive.se")).unwrap();
+        let directive = read_all(&mut reader);
+        std::fs::remove_dir_all(&dir).unwrap();
+        // only the byte order marks at the start of the files are skipped
+        assert_eq!(result, "1p\n2p\n\u{feff}4p\n3p\n");
+        assert_eq!(directive, "2p\n\u{feff}4p\n");
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = std::env::temp_dir().join("se-include-cycle-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.se"), "1p\n@include b.se").unwrap();
+        std::fs::write(dir.join("b.se"), "@include a.se").unwrap();
+        let result = crate::Program::try_from(&dir.join("a.se"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_script_with_bom() {
+        let path = std::env::temp_dir().join("se-bom-parse-test.se");
+        std::fs::write(&path, "\u{feff}1p").unwrap();
+        let result = crate::Program::try_from(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), std::str::FromStr::from_str("1p").unwrap());
+    }
+}
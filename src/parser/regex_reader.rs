@@ -18,6 +18,33 @@ pub(crate) fn read_regex<R: Reader>(reader: &mut R) -> Result<String> {
     Ok(acc)
 }
 
+/// Read the pattern as a literal string, where only `\\` and the escaped delimiter
+/// are unescaped, the other characters (including other backslashes) are kept as they are.
+/// Returns the string and if it should match the whole line.
+pub(crate) fn read_literal<R: Reader>(reader: &mut R) -> Result<(String, bool)> {
+    let (delim, whole_line) = match reader.next()? {
+        Some('/') => ('/', false),
+        Some('^') => ('$', true),
+        Some(c) => bail!(Error::Unexpected(c)),
+        _ => unreachable!(),
+    };
+    let mut acc = String::new();
+    while let Some(c) = reader.next()? {
+        match c {
+            c if c == delim => return Ok((acc, whole_line)),
+            '\\' => match reader.peek()? {
+                Some(e) if e == delim || e == '\\' => {
+                    acc.push(e);
+                    reader.skip();
+                }
+                _ => acc.push(c),
+            },
+            _ => acc.push(c),
+        }
+    }
+    bail!(Error::Missing(delim))
+}
+
 fn read_until<R: Reader>(
     reader: &mut R,
     delim: char,
@@ -71,11 +98,9 @@ fn read_brackets<R: Reader>(reader: &mut R, verbose: bool, acc: &mut String) ->
                 ')' => return Ok(local_verbose),
                 // verbose flag
                 'x' => local_verbose = true,
-                '-' => {
-                    if reader.next_is('x')? {
-                        acc.push('x');
-                        local_verbose = false;
-                    }
+                '-' if reader.next_is('x')? => {
+                    acc.push('x');
+                    local_verbose = false;
                 }
                 // other flags
                 _ => (),
@@ -100,7 +125,7 @@ fn read_line<R: Reader>(reader: &mut R, acc: &mut String) -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::read_regex;
+    use super::{read_literal, read_regex};
     use crate::parser::reader::StringReader;
     use test_case::test_case;
 
@@ -198,4 +223,15 @@ mod tests {
         assert_eq!(result, expected);
         regex::Regex::new(&result).expect("regex should parse");
     }
+
+    #[test_case(r"/a.b/<not this>", "a.b", false; "dot")]
+    #[test_case(r"/(a/<not this>", "(a", false; "unclosed bracket")]
+    #[test_case(r"/\d\/\\/<not this>", r"\d/\", false; "backslashes")]
+    #[test_case(r"^a+$<not this>", "a+", true; "whole line")]
+    #[test_case(r"^\$5$<not this>", "$5", true; "escaped dollar in whole line")]
+    fn literal(input: &str, expected: &str, whole_line: bool) {
+        let reader = &mut StringReader::from(input);
+        let result = read_literal(reader).unwrap();
+        assert_eq!(result, (expected.to_string(), whole_line));
+    }
 }
@@ -1,17 +1,80 @@
-use super::{reader::Reader, regex_reader};
+use super::{reader::Reader, regex_reader, ParseOptions};
 use crate::Regex;
-use anyhow::Result;
-use std::str::FromStr;
+use anyhow::{bail, Result};
+use std::{collections::HashMap, sync::Arc};
 
+/// The options and the regexes compiled so far when parsing the script.
+/// The compiled regexes are shared between the identical patterns,
+/// so each of them is compiled only once.
+pub(crate) struct ParseContext<'a> {
+    pub(crate) options: &'a ParseOptions,
+    regexes: HashMap<String, Regex>,
+}
+
+impl<'a> ParseContext<'a> {
+    pub(crate) fn new(options: &'a ParseOptions) -> Self {
+        ParseContext {
+            options,
+            regexes: HashMap::new(),
+        }
+    }
+
+    /// Compile the regular expression, limiting its size if set in the options.
+    pub(crate) fn compile(&mut self, pattern: &str) -> Result<Regex> {
+        if let Some(regex) = self.regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let mut builder = regex::RegexBuilder::new(pattern);
+        if let Some(limit) = self.options.regex_size_limit {
+            builder.size_limit(limit).dfa_size_limit(limit);
+        }
+        let regex = Regex(Arc::new(builder.build()?));
+        self.regexes.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+}
+
+/// Skip all the whitespace characters, including the newlines.
 pub(crate) fn skip_whitespace<R: Reader>(reader: &mut R) {
-    while reader
-        .peek()
-        .is_ok_and(|o| o.is_some_and(|c| c.is_whitespace()))
-    {
+    skip_while(reader, char::is_whitespace)
+}
+
+/// Skip the whitespace characters, but stop at the newline,
+/// so that it can end the instruction.
+pub(crate) fn skip_inline_whitespace<R: Reader>(reader: &mut R) {
+    skip_while(reader, |c| c != '\n' && c.is_whitespace())
+}
+
+fn skip_while<R: Reader>(reader: &mut R, pred: impl Fn(char) -> bool) {
+    while reader.peek().is_ok_and(|o| o.is_some_and(&pred)) {
         reader.skip();
     }
 }
 
+/// Read the flags following the regular expression: `i` for case-insensitive
+/// and `m` for multi-line matching, and return them as the `(?flags)` prefix of the pattern,
+/// or an empty string if there are none. The multi-line matching is also used
+/// when it is enabled by the options.
+pub(crate) fn read_flags<R: Reader>(reader: &mut R, context: &ParseContext) -> Result<String> {
+    let mut flags = String::new();
+    if context.options.multiline {
+        flags.push('m');
+    }
+    while let Some(c) = reader.peek()? {
+        if !matches!(c, 'i' | 'm') {
+            break;
+        }
+        if !flags.contains(c) {
+            flags.push(c);
+        }
+        reader.skip();
+    }
+    if flags.is_empty() {
+        return Ok(flags);
+    }
+    Ok(format!("(?{})", flags))
+}
+
 pub(crate) fn skip_line<R: Reader>(reader: &mut R) {
     while reader.next().is_ok_and(|o| o.is_some_and(|c| c != '\n')) {}
 }
@@ -28,10 +91,154 @@ pub(crate) fn read_integer<R: Reader>(reader: &mut R) -> Result<String> {
     Ok(num)
 }
 
-pub(crate) fn parse_regex<R: Reader>(reader: &mut R) -> Result<Option<Regex>> {
-    let regex = regex_reader::read_regex(reader)?;
+pub(crate) fn read_name<R: Reader>(reader: &mut R) -> Result<String> {
+    let mut name = String::new();
+    while let Some(c) = reader.peek()? {
+        if !(c.is_alphanumeric() || c == '_') {
+            break;
+        }
+        name.push(c);
+        reader.skip();
+    }
+    if name.is_empty() {
+        bail!("missing name");
+    }
+    Ok(name)
+}
+
+/// Read the `/regex/` and compile it, `None` if it is empty.
+pub(crate) fn parse_regex<R: Reader>(
+    reader: &mut R,
+    context: &mut ParseContext,
+) -> Result<Option<Regex>> {
+    match read_pattern(reader, context)? {
+        Some(pattern) => Ok(Some(context.compile(&pattern)?)),
+        None => Ok(None),
+    }
+}
+
+/// Read the `/regex/` followed by the optional flags and compile them together.
+pub(crate) fn parse_regex_with_flags<R: Reader>(
+    reader: &mut R,
+    context: &mut ParseContext,
+) -> Result<Option<Regex>> {
+    let Some(pattern) = read_pattern(reader, context)? else {
+        return Ok(None);
+    };
+    let flags = read_flags(reader, context)?;
+    Ok(Some(context.compile(&format!("{}{}", flags, pattern))?))
+}
+
+/// Read the pattern of the `/regex/`, or of the literal string with the `--fixed-strings`
+/// option, `None` if it is empty.
+pub(crate) fn read_pattern<R: Reader>(
+    reader: &mut R,
+    context: &ParseContext,
+) -> Result<Option<String>> {
+    if context.options.fixed_strings {
+        let (mut literal, whole_line) = regex_reader::read_literal(reader)?;
+        if literal.is_empty() && !whole_line {
+            return Ok(None);
+        }
+        if context.options.expand_env {
+            literal = expand_env(&literal, &[], false);
+        }
+        let mut regex = regex::escape(&literal);
+        if whole_line {
+            regex = format!("^{}$", regex);
+        }
+        return Ok(Some(regex));
+    }
+    let mut regex = regex_reader::read_regex(reader)?;
     if regex.is_empty() {
         return Ok(None);
     }
-    Ok(Some(Regex::from_str(&regex)?))
+    if context.options.expand_env {
+        regex = expand_env(&regex, &[], true);
+    }
+    Ok(Some(regex))
+}
+
+/// Replace `${NAME}` with the value of the environment variable, unless the variable
+/// is not set or the name is one of the `skip` names. `$$` and `\$` are not expanded.
+/// With `escape`, the values are escaped, so they match literally in a regex.
+pub(crate) fn expand_env(s: &str, skip: &[&str], escape: bool) -> String {
+    let mut acc = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find(['$', '\\']) {
+        acc.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with("$$") || rest.starts_with('\\') {
+            let len = rest[1..].chars().next().map_or(1, |c| 1 + c.len_utf8());
+            acc.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+        let value = rest
+            .strip_prefix("${")
+            .and_then(|r| r.split_once('}'))
+            .filter(|(name, _)| is_env_name(name) && !skip.contains(name))
+            .and_then(|(name, r)| Some((std::env::var(name).ok()?, r)));
+        match value {
+            Some((value, r)) if escape => {
+                acc.push_str(&regex::escape(&value));
+                rest = r;
+            }
+            Some((value, r)) => {
+                acc.push_str(&value);
+                rest = r;
+            }
+            None => {
+                acc.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    acc.push_str(rest);
+    acc
+}
+
+fn is_env_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{skip_inline_whitespace, skip_whitespace, ParseContext};
+    use crate::parser::{reader::Reader, ParseOptions, StringReader};
+    use test_case::test_case;
+
+    #[test_case(" \t p", Some('p'), Some('p'); "spaces and tabs")]
+    #[test_case(" \t\n p", Some('p'), Some('\n'); "newline")]
+    #[test_case("\n\n", None, Some('\n'); "only newlines")]
+    #[test_case("", None, None; "empty")]
+    fn skipping(input: &str, all: Option<char>, inline: Option<char>) {
+        let reader = &mut StringReader::from(input);
+        skip_whitespace(reader);
+        assert_eq!(reader.peek().unwrap(), all);
+
+        let reader = &mut StringReader::from(input);
+        skip_inline_whitespace(reader);
+        assert_eq!(reader.peek().unwrap(), inline);
+    }
+
+    #[test]
+    fn regex_size_limit() {
+        let pattern = r"\w{100}";
+        let options = ParseOptions::default();
+        assert!(ParseContext::new(&options).compile(pattern).is_ok());
+
+        let options = ParseOptions {
+            regex_size_limit: Some(1000),
+            ..Default::default()
+        };
+        let mut context = ParseContext::new(&options);
+        let err = context.compile(pattern).unwrap_err();
+        assert!(err.to_string().contains("size limit"), "{}", err);
+        assert!(context.compile("a+b").is_ok());
+    }
 }
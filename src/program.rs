@@ -1,63 +1,552 @@
-use crate::{command, run, Action, Line, Status};
+use crate::{
+    address::Address,
+    command,
+    context::Context,
+    format_actions,
+    lines::{Counted, Lookahead, Records},
+    output::{Deferred, SideFile},
+    run, Action, Line, OutputSink, State, Status,
+};
 use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::PathBuf,
+};
 
 #[derive(Debug, PartialEq)]
-pub struct Program(pub(crate) Vec<Action>, pub(crate) Vec<command::Command>);
+pub struct Program {
+    /// The instructions run on each line
+    pub(crate) actions: Vec<Action>,
+    /// The instructions of the final block (`$ { ... }`), run after processing the input
+    pub(crate) finally: Vec<Action>,
+    /// How the program is run, set with the methods of the program
+    pub(crate) options: RunOptions,
+}
+
+/// Settings changing how the program is run, they are not part of the script.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RunOptions {
+    /// The named buffers written to the files after processing the input
+    pub(crate) dumps: Vec<(String, PathBuf)>,
+    /// Prefix the printed lines with the index of the instruction that changed them
+    pub(crate) annotate: bool,
+    /// Limit of the loop iterations per line
+    pub(crate) max_iterations: Option<usize>,
+    /// The file logging the changed lines
+    pub(crate) changes_log: Option<PathBuf>,
+    /// Prefix the printed lines with the line number and a tab
+    pub(crate) number_tab: bool,
+    /// Process only the lines equal to one of these
+    pub(crate) only_lines: Option<HashSet<String>>,
+    /// Number of the lines printed before and after the matched lines
+    pub(crate) context: Option<(usize, usize)>,
+    /// Print only the parts matching the regex address with `p`
+    pub(crate) only_matching: bool,
+    /// The line separating the groups of lines printed with the context
+    pub(crate) group_separator: Option<String>,
+    /// Count the matches of the regex addresses
+    pub(crate) count_matches: bool,
+    /// Highlight the matches in the printed lines
+    pub(crate) color: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            dumps: Vec::new(),
+            annotate: false,
+            max_iterations: Some(DEFAULT_MAX_ITERATIONS),
+            changes_log: None,
+            number_tab: false,
+            only_lines: None,
+            context: None,
+            only_matching: false,
+            group_separator: Some(DEFAULT_GROUP_SEPARATOR.to_string()),
+            count_matches: false,
+            color: false,
+        }
+    }
+}
+
+/// How running the program ended.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// All the input was processed
+    Finished,
+    /// Stopped by `q` with the exit code and the message to be printed to stderr
+    Quit(i32, Option<String>),
+}
+
+/// Counts collected when running the program.
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Number of the lines that matched any of the addresses
+    pub matches: usize,
+    /// Number of the lines read from the input, including the ones read by the commands
+    pub lines: usize,
+    /// Number of the processed lines that were not deleted
+    pub kept: usize,
+    /// Number of the processed lines that were deleted
+    pub deleted: usize,
+    /// Number of the kept lines that were changed
+    pub modified: usize,
+    /// Number of the non-empty matches of the regex addresses in the matched lines,
+    /// counted only when enabled with [`Program::count_matches`]
+    pub hits: usize,
+}
+
+impl std::ops::AddAssign for Summary {
+    fn add_assign(&mut self, other: Summary) {
+        self.matches += other.matches;
+        self.lines += other.lines;
+        self.kept += other.kept;
+        self.deleted += other.deleted;
+        self.modified += other.modified;
+        self.hits += other.hits;
+    }
+}
+
+/// The outputs kept between the runs of the program on separate inputs, e.g. when processing
+/// each file on its own, so that the files written by `w`, the named buffers dumped at the end,
+/// and the log of the changes are not truncated by the following runs.
+/// See [`Program::run_with`] and [`Program::finish`].
+#[derive(Debug, Default)]
+pub struct Session {
+    files: HashMap<PathBuf, SideFile>,
+    named: HashMap<String, String>,
+    changes: Option<BufWriter<File>>,
+    file: Option<PathBuf>,
+}
+
+impl Session {
+    /// Set the path of the file processed by the following run, it prefixes the entries
+    /// in the log of the changes.
+    pub fn set_file(&mut self, path: Option<PathBuf>) {
+        self.file = path;
+    }
+}
+
+/// The default limit of the loop iterations per line.
+pub(crate) const DEFAULT_MAX_ITERATIONS: usize = 1_000_000;
+
+/// The default separator of the groups of lines printed with the context.
+pub(crate) const DEFAULT_GROUP_SEPARATOR: &str = "--";
 
 impl Program {
-    pub fn run<R: Iterator<Item = Result<Line>>>(
+    pub(crate) fn new(actions: Vec<Action>, finally: Vec<Action>) -> Self {
+        Program {
+            actions,
+            finally,
+            options: RunOptions::default(),
+        }
+    }
+
+    /// Build the program from the instructions, without parsing a script.
+    ///
+    /// ```
+    /// use se::{Address, Program};
+    ///
+    /// let program = Program::builder()
+    ///     .on(Address::regex("world")?)
+    ///     .substitute("world", "there", 0)?
+    ///     .print()
+    ///     .on(Address::line(1)?)
+    ///     .line_number()
+    ///     .build();
+    /// let mut out = Vec::new();
+    /// program.process("hello world\nbye world\n".as_bytes(), &mut out)?;
+    /// assert_eq!(out, b"hello there\n1bye there\n");
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn builder() -> crate::Builder {
+        crate::Builder::default()
+    }
+
+    /// Process all the lines of the `input` and write the results to the `output`,
+    /// e.g. when using `se` as a library. Like the command line tool, it stops after `q`
+    /// and returns its exit code in the outcome. The output is flushed at the end.
+    pub fn process<R: BufRead, W: Write>(&self, input: R, mut output: W) -> Result<Outcome> {
+        let mut reader = Records::new(input, false)
+            .enumerate()
+            .map(|(i, line)| -> Result<Line> { Ok(Line(i + 1, line?)) });
+        let (outcome, _) = self.run(&mut reader, &mut output, false)?;
+        output.flush()?;
+        Ok(outcome)
+    }
+
+    /// Run the program on the lines of the `reader`. With `print_all`, the lines are printed
+    /// after running the script on them, like `sed` does without `-n`, unless they were
+    /// deleted or already printed by `p`.
+    pub fn run<R: Iterator<Item = Result<Line>>, W: OutputSink>(
         &self,
         reader: &mut R,
+        out: &mut W,
         print_all: bool,
-    ) -> Result<(Status, usize)> {
+    ) -> Result<(Outcome, Summary)> {
+        let mut session = Session::default();
+        let result = self.run_with(reader, out, print_all, &mut session)?;
+        self.finish(session)?;
+        Ok(result)
+    }
+
+    /// Like [`Program::run`], but the files written by `w`, the named buffers,
+    /// and the log of the changes are kept in the `session` for the following runs,
+    /// while the line numbers, the ranges, and the hold space start anew.
+    /// Call [`Program::finish`] after the last run to write them.
+    pub fn run_with<R: Iterator<Item = Result<Line>>, W: OutputSink>(
+        &self,
+        reader: &mut R,
+        out: &mut W,
+        print_all: bool,
+        session: &mut Session,
+    ) -> Result<(Outcome, Summary)> {
         use Status::*;
 
-        let mut matches = 0;
+        // the program can be run again on another input
+        reset(&self.actions);
+        reset(&self.finally);
+
+        let mut summary = Summary::default();
         let mut status = Normal;
-        let mut hold = String::new();
+        let mut state = State {
+            annotate: self.options.annotate,
+            number_tab: self.options.number_tab,
+            max_iterations: self.options.max_iterations,
+            only_matching: self.options.only_matching,
+            hits: self.options.count_matches.then_some(0),
+            color: self.options.color,
+            files: std::mem::take(&mut session.files),
+            named: std::mem::take(&mut session.named),
+            ..Default::default()
+        };
         let mut pattern: Line = Line::default();
+        // look ahead to know when the last line is processed
+        let mut reader = Counted::new(reader);
+
+        if let (Some(path), None) = (&self.options.changes_log, &session.changes) {
+            session.changes = Some(BufWriter::new(File::create(path)?));
+        }
+
+        // empty script behaves like `cat`
+        let print_all = print_all || self.is_empty();
+        // when printing all the lines, there is no context to add
+        let mut context = match self.options.context {
+            Some((before, after)) if !print_all => Some(Context::new(
+                before,
+                after,
+                self.options.group_separator.clone(),
+            )),
+            _ => None,
+        };
 
         while let Some(line) = reader.next() {
-            pattern = line?;
+            let line = line?;
+            if self
+                .options
+                .only_lines
+                .as_ref()
+                .is_some_and(|set| !set.contains(&line.1))
+            {
+                summary.deleted += 1;
+                continue;
+            }
+            pattern = line;
+            let (number, original) = (pattern.0, pattern.1.clone());
             status = Normal;
+            state.changed_by = None;
+            state.substituted = false;
+            state.printed = false;
+            state.replaced = None;
+            state.iterations = 0;
 
-            if let Some(s) = run(&self.0, &mut pattern, &mut hold, reader)? {
+            // the output is held until knowing if the context needs to be printed before it
+            let mut deferred = Deferred::default();
+            let sink: &mut dyn OutputSink = match context {
+                Some(_) => &mut deferred,
+                None => out,
+            };
+            let result = run(&self.actions, &mut pattern, &mut state, &mut reader, sink)?;
+            if let Some(context) = context.as_mut() {
+                state.flush_appended(&mut deferred)?;
+                match result {
+                    Some(_) => context.matched(number, pattern.0, out)?,
+                    None => context.unmatched(Line(number, original.clone()), out)?,
+                }
+                deferred.replay(out)?;
+            }
+            if let Some(s) = result {
                 status = s;
-                matches += 1;
+                summary.matches += 1;
             }
 
             if status == NoPrint {
+                state.flush_appended(out)?;
+                summary.deleted += 1;
                 continue;
             }
-            if print_all {
-                println!("{}", pattern.1)
+            summary.kept += 1;
+            if pattern.1 != original {
+                summary.modified += 1;
+                if let Some(log) = session.changes.as_mut() {
+                    let prefix = match &session.file {
+                        Some(path) => format!("{}:{}", path.display(), number),
+                        None => number.to_string(),
+                    };
+                    writeln!(log, "{}:-{}\n{}:+{}", prefix, original, prefix, pattern.1)?;
+                }
+            }
+            if print_all && !state.printed {
+                let text = state.highlight(&pattern.1, false);
+                out.emit(&format!("{}{}\n", state.prefix(&pattern), text))?
             }
-            if let Quit(_) = status {
+            state.flush_appended(out)?;
+            if let Quit(..) = status {
                 break;
             }
         }
 
-        for cmd in self.1.iter() {
-            let s = cmd.run(&mut pattern, &mut hold, reader)?;
-            if s != Status::Normal {
-                status = s;
-                break;
+        if let Some(s) = self.finalize(&mut pattern, &mut state, &mut reader, out)? {
+            status = s;
+        }
+
+        session.files = state.files;
+        session.named = state.named;
+        summary.lines = reader.consumed;
+        summary.hits = state.hits.unwrap_or(0);
+        let outcome = match status {
+            Quit(code, message) => Outcome::Quit(code, message),
+            _ => Outcome::Finished,
+        };
+        Ok((outcome, summary))
+    }
+
+    /// Write the named buffers to their dump files, and flush and close the files
+    /// written by `w` and the log of the changes, after the last run in the `session`.
+    pub fn finish(&self, mut session: Session) -> Result<()> {
+        for (name, path) in self.options.dumps.iter() {
+            let content = session.named.get(name).map_or("", |s| s.as_str());
+            std::fs::write(path, content)?;
+        }
+        if let Some(mut log) = session.changes.take() {
+            log.flush()?;
+        }
+        for (_, file) in session.files.drain() {
+            file.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Run the final block (`$`) once, after processing all the input.
+    ///
+    /// The pattern space contains whatever was left after processing the last line,
+    /// or is empty when there was no input, so `1!j ; $p ; h` can work like `tac`.
+    /// Returns the status if the final block stopped with a non-normal status.
+    fn finalize<R: Lookahead>(
+        &self,
+        pattern: &mut Line,
+        state: &mut State,
+        reader: &mut R,
+        out: &mut dyn OutputSink,
+    ) -> Result<Option<Status>> {
+        let status =
+            run(&self.finally, pattern, state, reader, out)?.filter(|s| *s != Status::Normal);
+        state.flush_appended(out)?;
+        Ok(status)
+    }
+
+    /// Write the content of the named buffer to the file after processing the input.
+    pub fn dump_buffer(&mut self, name: &str, path: PathBuf) {
+        self.options.dumps.push((name.to_string(), path));
+    }
+
+    /// Prefix the printed lines with `[N] `, where `N` is the index (starting at 1)
+    /// of the instruction that last changed the line. The unchanged lines are not prefixed.
+    /// For the commands in loops, the index of the loop's instruction is used.
+    pub fn annotate(&mut self) {
+        self.options.annotate = true;
+    }
+
+    /// Print also up to `before` lines preceding and `after` lines following the matched lines,
+    /// like `grep -C`. The non-adjacent groups of lines are separated with `--`.
+    /// It has no effect when printing all the lines.
+    pub fn context(&mut self, before: usize, after: usize) {
+        self.options.context = Some((before, after));
+    }
+
+    /// Separate the non-adjacent groups of lines printed with the context by the `separator`
+    /// line instead of `--`, or don't separate them when it is `None`,
+    /// like `grep --group-separator` and `--no-group-separator`.
+    pub fn group_separator(&mut self, separator: Option<String>) {
+        self.options.group_separator = separator;
+    }
+
+    /// Count the non-empty matches of the regex addresses in the matched lines, rather than
+    /// the lines, like `grep -o | wc -l`. The count is returned as [`Summary::hits`].
+    pub fn count_matches(&mut self) {
+        self.options.count_matches = true;
+    }
+
+    /// Highlight the matches in the lines printed by `p` and `P` with the ANSI escape codes,
+    /// like `grep --color`. The text replaced by the substitution is highlighted as long as
+    /// the line is not changed otherwise, if not, the matches of the regex address
+    /// of the instruction. With `-a`, only the replaced text is highlighted.
+    pub fn color(&mut self) {
+        self.options.color = true;
+    }
+
+    /// Make `p` print only the parts of the pattern space matching the regex address
+    /// of its instruction, each on a separate line, like `grep -o`. The empty matches
+    /// are skipped. For other addresses `p` prints the whole pattern space.
+    pub fn only_matching(&mut self) {
+        self.options.only_matching = true;
+    }
+
+    /// Process only the lines equal to one of the `lines`, the other lines are dropped
+    /// before running the program, as if they were deleted.
+    pub fn only_lines(&mut self, lines: HashSet<String>) {
+        self.options.only_lines = Some(lines);
+    }
+
+    /// Prefix the printed lines with their line number followed by a tab, e.g. for
+    /// importing the output to a spreadsheet.
+    pub fn number_tab(&mut self) {
+        self.options.number_tab = true;
+    }
+
+    /// Write the changed lines to the file, each as a pair of `N:-old` and `N:+new` lines,
+    /// where `N` is the line number. The deleted lines are not logged.
+    pub fn log_changes(&mut self, path: PathBuf) {
+        self.options.changes_log = Some(path);
+    }
+
+    /// Fail when the loops run more than `max` iterations in total when processing
+    /// a single line, so that a buggy script cannot hang. `None` disables the limit.
+    pub fn max_iterations(&mut self, max: Option<usize>) {
+        self.options.max_iterations = max;
+    }
+
+    /// Reorder the members of the address sets, so that the cheaper ones are checked first.
+    ///
+    /// The instructions are not reordered, since each of their addresses is checked
+    /// anyway, while the set stops at the first matching member. The stateful members
+    /// (ranges, `?`) are checked even after another member matched, so the results
+    /// stay the same.
+    pub fn optimize(&mut self) {
+        optimize(&mut self.actions)
+    }
+
+    /// Run the instructions of the `prelude` before the ones of this program, and its final
+    /// block before this program's final block. A `.` in the prelude skips the whole program.
+    pub fn prepend(&mut self, prelude: Program) {
+        self.actions.splice(0..0, prelude.actions);
+        self.finally.splice(0..0, prelude.finally);
+    }
+
+    /// Check if the program uses the percentage addresses,
+    /// that need to know the number of lines in the input.
+    pub fn needs_line_count(&self) -> bool {
+        let mut needs = false;
+        for_each_address(&self.actions, &mut |addr| needs |= addr.needs_line_count());
+        for_each_address(&self.finally, &mut |addr| needs |= addr.needs_line_count());
+        needs
+    }
+
+    /// Set the number of lines in the input, used to find the lines at the percentages
+    /// of the input. Until it is set, the percentage addresses don't match any lines.
+    pub fn line_count(&self, total: usize) {
+        for_each_address(&self.actions, &mut |addr| addr.set_line_count(total));
+        for_each_address(&self.finally, &mut |addr| addr.set_line_count(total));
+    }
+
+    /// Check if the program has no instructions at all.
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty() && self.finally.is_empty()
+    }
+}
+
+fn optimize(actions: &mut [Action]) {
+    for action in actions.iter_mut() {
+        match action {
+            Action::Condition(addr, _) => addr.optimize(),
+            Action::Command(command::Command::Loop(body) | command::Command::WithHold(body)) => {
+                optimize(body)
             }
+            _ => (),
         }
+    }
+}
 
-        Ok((status, matches))
+/// Forget the state of the addresses, e.g. the ranges that started.
+fn for_each_address<F: FnMut(&Address)>(actions: &[Action], f: &mut F) {
+    for action in actions.iter() {
+        match action {
+            Action::Condition(addr, _) => f(addr),
+            Action::Command(command::Command::Loop(body) | command::Command::WithHold(body)) => {
+                for_each_address(body, f)
+            }
+            _ => (),
+        }
+    }
+}
+
+fn reset(actions: &[Action]) {
+    for action in actions.iter() {
+        match action {
+            Action::Condition(addr, _) => addr.reset(),
+            Action::Command(command::Command::Loop(body) | command::Command::WithHold(body)) => {
+                reset(body)
+            }
+            _ => (),
+        }
     }
 }
 
 impl From<Vec<Action>> for Program {
     fn from(value: Vec<Action>) -> Self {
-        Program(value, Vec::new())
+        Program::new(value, Vec::new())
+    }
+}
+
+impl std::fmt::Display for Program {
+    /// Write the program as the script, parsing it gives back the same program.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_actions(&self.actions))?;
+        if !self.finally.is_empty() {
+            if !self.actions.is_empty() {
+                write!(f, " ")?;
+            }
+            write!(f, "$ {{ {} }}", format_actions(&self.finally))?;
+        }
+        Ok(())
+    }
+}
+
+/// Number the `input` lines, starting from 1.
+#[cfg(test)]
+pub(crate) fn numbered<'a>(input: &'a [&str]) -> impl Iterator<Item = Result<Line>> + 'a {
+    input
+        .iter()
+        .enumerate()
+        .map(|(i, s)| Ok(Line(i + 1, s.to_string())))
+}
+
+#[cfg(test)]
+impl Program {
+    /// Run the program on the numbered `input` lines and return the output.
+    pub(crate) fn output(&self, input: &[&str], print_all: bool) -> String {
+        let mut reader = numbered(input);
+        let mut out = Vec::new();
+        self.run(&mut reader, &mut out, print_all).unwrap();
+        String::from_utf8(out).unwrap()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{lines::MockReader, run, Line, Program};
+    use super::numbered;
+    use crate::{lines::MockReader, run, Line, Outcome, Program, Session, State, Summary};
     use std::str::FromStr;
     use test_case::test_case;
 
@@ -94,7 +583,559 @@ mod tests {
     fn keep(command: &str, expected: &str) {
         let func = Program::from_str(command).unwrap();
         let pattern = &mut Line(0, "123456789".to_string());
-        run(&func.0, pattern, &mut String::new(), &mut MockReader {}).unwrap();
+        run(
+            &func.actions,
+            pattern,
+            &mut State::default(),
+            &mut MockReader {},
+            &mut std::io::sink(),
+        )
+        .unwrap();
         assert_eq!(pattern.1, expected)
     }
+
+    #[test_case("", true; "empty script")]
+    #[test_case("p", false; "print")]
+    #[test_case("$p", false; "only final block")]
+    fn is_empty(script: &str, expected: bool) {
+        let program = Program::from_str(script).unwrap();
+        assert_eq!(program.is_empty(), expected)
+    }
+
+    #[test_case("/a/, 3, /b/"; "set")]
+    #[test_case("/a/ ; 3 ; /b/, 1"; "pure matchers")]
+    #[test_case("/a/ ; 3 ; 2-4 ; /b/"; "with range")]
+    #[test_case("/b/, 4-6, 3 ; ? ; 1"; "stateful set")]
+    #[test_case("/b/, /a/-/b/, (/c/-3)! ; /a/#2, 1"; "ranges in set")]
+    #[test_case("(/a/, 2)!, 7 ; 5 z ; /c/, 4"; "with commands")]
+    fn optimize(script: &str) {
+        let example = ["a", "b", "ab", "c", "", "a", "a", "c", "b", "abc"];
+        let count = |program: &Program| {
+            let mut reader = numbered(&example);
+            program
+                .run(&mut reader, &mut std::io::sink(), false)
+                .unwrap()
+        };
+
+        let original = Program::from_str(script).unwrap();
+        let mut optimized = Program::from_str(script).unwrap();
+        optimized.optimize();
+        assert_eq!(count(&original), count(&optimized));
+    }
+
+    #[test]
+    fn optimize_order() {
+        // the members of the sets are reordered, but the instructions are not
+        let mut program = Program::from_str("/a/, 3 ; /b/ ; 1 ; 2 p").unwrap();
+        program.optimize();
+        assert_eq!(
+            program,
+            Program::from_str("3, /a/ ; /b/ ; 1 ; 2 p").unwrap()
+        );
+
+        let mut program = Program::from_str("/a/, 3-5 ; /b/ ; 1-2").unwrap();
+        program.optimize();
+        assert_eq!(program, Program::from_str("3-5, /a/ ; /b/ ; 1-2").unwrap());
+    }
+
+    #[test_case("/a/, 2, /b/ s/^/x/ p ; (/c/, 3)! =n ; 4, /a/-/c/ d"; "sets with commands")]
+    #[test_case("/a/, 5 s/a/A/ ; /A/, 1-3, ? s/$/!/ p"; "changed lines in set")]
+    fn optimize_output(script: &str) {
+        let example = ["a", "b", "ab", "c", "", "a", "a", "c", "b", "abc"];
+        let original = Program::from_str(script).unwrap();
+        let mut optimized = Program::from_str(script).unwrap();
+        optimized.optimize();
+        assert_ne!(original, optimized);
+        assert_eq!(
+            original.output(&example, true),
+            optimized.output(&example, true)
+        );
+    }
+
+    #[test]
+    fn dump_state() {
+        let dir = std::env::temp_dir();
+        let (odd, even) = (dir.join("se-dump-odd.txt"), dir.join("se-dump-even.txt"));
+
+        let mut program = Program::from_str("/[13579]$/ A odd ; /[02468]$/ A even").unwrap();
+        program.dump_buffer("odd", odd.clone());
+        program.dump_buffer("even", even.clone());
+
+        let mut reader = (1..=5).map(|i| Ok(Line(i, format!("line {}", i))));
+        program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+
+        let result = (
+            std::fs::read_to_string(&odd).unwrap(),
+            std::fs::read_to_string(&even).unwrap(),
+        );
+        std::fs::remove_file(&odd).unwrap();
+        std::fs::remove_file(&even).unwrap();
+        assert_eq!(result.0, "line 1\nline 3\nline 5\n");
+        assert_eq!(result.1, "line 2\nline 4\n");
+    }
+
+    #[test]
+    fn shared_session() {
+        let path = std::env::temp_dir().join("se-dump-session.txt");
+        let mut program = Program::from_str("1 A out ; /a/ h ; $ { g p }").unwrap();
+        program.dump_buffer("out", path.clone());
+
+        let mut session = Session::default();
+        let mut out = Vec::new();
+        for input in [["a", "b"], ["c", "d"]] {
+            program
+                .run_with(&mut numbered(&input), &mut out, false, &mut session)
+                .unwrap();
+        }
+        program.finish(session).unwrap();
+
+        let result = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, "a\nc\n");
+        // the hold space is not shared
+        assert_eq!(String::from_utf8(out).unwrap(), "a\n\n");
+    }
+
+    #[test_case("$ { A out }", 0, "\n"; "no input")]
+    #[test_case("$ { A out }", 5, "line 5\n"; "last line")]
+    #[test_case("z ; $ { A out }", 5, "\n"; "cleared pattern space")]
+    #[test_case("A out ; $ { /5/ z ; /3/ A out }", 3, "line 1\nline 2\nline 3\nline 3\n"; "block")]
+    fn final_block_runs_once(script: &str, lines: usize, expected: &str) {
+        let name = format!("se-final-{}.txt", script.len() + lines);
+        let path = std::env::temp_dir().join(name);
+        let mut program = Program::from_str(script).unwrap();
+        program.dump_buffer("out", path.clone());
+
+        let mut reader = (1..=lines).map(|i| Ok(Line(i, format!("line {}", i))));
+        program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+
+        let result = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn read_file() {
+        let path = std::env::temp_dir().join("se-read-file.txt");
+        std::fs::write(&path, "x\ny\n").unwrap();
+        let script = format!(
+            "/a/ r '{}' ; /b/ a'z' r '{}' d ; r 'se-missing-file.txt'",
+            path.display(),
+            path.display()
+        );
+        let program = Program::from_str(&script).unwrap();
+        let mut reader = numbered(&["a", "b", "c"]);
+        let mut out = Vec::new();
+        let result = program.run(&mut reader, &mut out, true);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a\nx\ny\nz\nx\ny\nc\n");
+    }
+
+    #[test]
+    fn run_again() {
+        let program = Program::from_str("/b/-/c/ p ; /a/#2 p ; ? p").unwrap();
+        let input = ["a", "b", "a", "b", "b"];
+        let first = program.output(&input, false);
+        assert_eq!(first, "a\nb\nb\na\na\na\nb\nb\nb\n");
+        assert_eq!(program.output(&input, false), first);
+    }
+
+    #[test_case(r"N s/\n/ /", "1 2\n3 4\n5\n"; "join pairs")]
+    #[test_case(r"N ; s/\n/ /", "1 2\n3 4\n5\n"; "separate instructions")]
+    #[test_case(r"$!N s/\n/-/", "1-2\n3-4\n5\n"; "not on the last line")]
+    #[test_case(r":{ N } s/\n/ /", "1\n2\n3\n4\n5\n"; "until the end in loop")]
+    #[test_case(r"H{ N }", "1\n3\n5\n"; "in hold block")]
+    #[test_case("N =", "21\n2\n43\n4\n5\n"; "line number")]
+    fn next(script: &str, expected: &str) {
+        let program = Program::from_str(script).unwrap();
+        let mut reader = (1..=5).map(|i| Ok(Line(i, i.to_string())));
+        let mut out = Vec::new();
+        program.run(&mut reader, &mut out, true).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test_case("50% p", 5, "3\n"; "middle of odd")]
+    #[test_case("50% p", 4, "2\n"; "middle of even")]
+    #[test_case("0% p ; 100% p", 4, "1\n4\n"; "first and last")]
+    #[test_case("25%-75%! p", 8, "1\n7\n8\n"; "negated range")]
+    #[test_case(":{ 10% p ; . }", 10, "1\n"; "in loop")]
+    fn percent(script: &str, total: usize, expected: &str) {
+        let program = Program::from_str(script).unwrap();
+        assert!(program.needs_line_count());
+        program.line_count(total);
+        let input = (1..=total).map(|i| i.to_string()).collect::<Vec<_>>();
+        let input = input.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        assert_eq!(program.output(&input, false), expected);
+    }
+
+    #[test_case(r":{ /aa/! . s/aa/a/ }", &["aaaab caaa", "b"], "ab ca\nb\n"; "squeeze")]
+    #[test_case(r":{ /[0-9]{4}/! . s/([0-9])([0-9]{3})\b/$1,$2/ }", &["1234567 89012", "12"], "1,234,567 89,012\n12\n"; "thousands separators")]
+    #[test_case(r":{ /\(\)/! . s/\(\)//g }", &["((())())x"], "x\n"; "nested parentheses")]
+    fn until_no_change(script: &str, input: &[&str], expected: &str) {
+        let program = Program::from_str(&format!("{} p", script)).unwrap();
+        assert_eq!(program.output(input, false), expected);
+    }
+
+    #[test_case(r"s/\r$//", "p", &["a\r", "b"], "a\nb\n"; "strip carriage returns")]
+    #[test_case("s/a/A/", "s/A/B/ p", &["a"], "B\n"; "runs first")]
+    #[test_case("/x/ d .", "p", &["x", "y"], "y\n"; "skips the script")]
+    #[test_case("$ { 'pre\n' }", "$ { 'main\n' }", &["a"], "pre\nmain\n"; "final blocks")]
+    fn prepend(prelude: &str, script: &str, input: &[&str], expected: &str) {
+        let mut program = Program::from_str(script).unwrap();
+        program.prepend(Program::from_str(prelude).unwrap());
+        assert_eq!(program.output(input, false), expected);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn write_compressed_file() {
+        use std::io::Read;
+
+        let path = std::env::temp_dir().join("se-write-compressed.txt.gz");
+        let program = Program::from_str(&format!("/a/ w {}", path.display())).unwrap();
+        let mut reader = numbered(&["a", "b", "ab"]);
+        program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+
+        let compressed = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut output = String::new();
+        flate2::read::MultiGzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(compressed[..2], [0x1f, 0x8b]);
+        assert_eq!(output, "a\nab\n");
+    }
+
+    #[test]
+    fn write_files() {
+        let dir = std::env::temp_dir();
+        let (foo, bar) = (dir.join("se-write-foo.txt"), dir.join("se-write-bar.txt"));
+        let script = format!(
+            "/foo/ w {}\np ; /bar/ w {} ; /foo/ w {}",
+            foo.display(),
+            bar.display(),
+            foo.display()
+        );
+        let program = Program::from_str(&script).unwrap();
+        let mut reader = numbered(&["foo 1", "bar", "baz", "foo 2"]);
+        program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+
+        let result = (
+            std::fs::read_to_string(&foo).unwrap(),
+            std::fs::read_to_string(&bar).unwrap(),
+        );
+        std::fs::remove_file(&foo).unwrap();
+        std::fs::remove_file(&bar).unwrap();
+        assert_eq!(result.0, "foo 1\nfoo 1\nfoo 2\nfoo 2\n");
+        assert_eq!(result.1, "bar\n");
+    }
+
+    #[test]
+    fn changes_log() {
+        let path = std::env::temp_dir().join("se-changes-log.txt");
+        let mut program = Program::from_str("s/a/A/ ; /c/ d ; /x/ s/x/x/").unwrap();
+        program.log_changes(path.clone());
+        let mut reader = numbered(&["abc", "b", "ac", "ba", "x"]);
+        program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+        let result = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, "4:-ba\n4:+bA\n");
+    }
+
+    #[test]
+    fn changes_log_per_file() {
+        let path = std::env::temp_dir().join("se-changes-log-files.txt");
+        let mut program = Program::from_str("s/b/Y/").unwrap();
+        program.log_changes(path.clone());
+        let mut session = Session::default();
+        for file in ["e1.txt", "e2.txt"] {
+            session.set_file(Some(file.into()));
+            program
+                .run_with(
+                    &mut numbered(&["a", "b"]),
+                    &mut std::io::sink(),
+                    false,
+                    &mut session,
+                )
+                .unwrap();
+        }
+        program.finish(session).unwrap();
+        let result = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result,
+            "e1.txt:2:-b\ne1.txt:2:+Y\ne2.txt:2:-b\ne2.txt:2:+Y\n"
+        );
+    }
+
+    #[test_case("", "a\nc\na\n"; "empty script")]
+    #[test_case("s/a/A/p", "A\nc\nA\n"; "substitute")]
+    #[test_case("$ { p }", "a\n"; "final block")]
+    fn only_lines(script: &str, expected: &str) {
+        let mut program = Program::from_str(script).unwrap();
+        program.only_lines(["a", "c", "x"].map(String::from).into());
+        let mut reader = numbered(&["a", "b", "c", "a ", "a", "d"]);
+        let mut out = Vec::new();
+        let (_, summary) = program.run(&mut reader, &mut out, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!((summary.kept, summary.deleted), (3, 3));
+    }
+
+    #[test_case(1, 1, "/x/p", "a2\nx3\na4\n--\na8\nx9\nx10\na11\n"; "around")]
+    #[test_case(2, 0, "/x/p", "a1\na2\nx3\n--\na7\na8\nx9\nx10\n"; "before")]
+    #[test_case(0, 3, "/x/p", "x3\na4\na5\na6\n--\nx9\nx10\na11\n"; "after")]
+    #[test_case(1, 1, "/x/ s/x/X/p ; /x/d", "a2\nX3\na4\n--\na8\nX9\nX10\na11\n"; "modified")]
+    #[test_case(1, 0, "/x/d ; 11 'end\n'", "a2\n--\na8\nend\n"; "deleted")]
+    fn context(before: usize, after: usize, script: &str, expected: &str) {
+        let mut program = Program::from_str(script).unwrap();
+        program.context(before, after);
+        let mut reader = ["a", "a", "x", "a", "a", "a", "a", "a", "x", "x", "a"]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Ok(Line(i + 1, format!("{}{}", s, i + 1))));
+        let mut out = Vec::new();
+        program.run(&mut reader, &mut out, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test_case(Some("==="), "a2\nx3\n===\na8\nx9\n"; "custom")]
+    #[test_case(Some(""), "a2\nx3\n\na8\nx9\n"; "empty line")]
+    #[test_case(None, "a2\nx3\na8\nx9\n"; "none")]
+    fn group_separator(separator: Option<&str>, expected: &str) {
+        let mut program = Program::from_str("/x/p").unwrap();
+        program.context(1, 0);
+        program.group_separator(separator.map(String::from));
+        let mut reader = ["a", "a", "x", "a", "a", "a", "a", "a", "x"]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Ok(Line(i + 1, format!("{}{}", s, i + 1))));
+        let mut out = Vec::new();
+        program.run(&mut reader, &mut out, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test_case(r":a ; s/([0-9])([0-9]{3})\b/$1,$2/ ; t a", &["1234567", "12 12345", "x"], "1,234,567\n12 12,345\nx\n"; "loop until no change")]
+    #[test_case("/x/ b skip ; s/$/!/ ; :skip", &["a", "x"], "a!\nx\n"; "skip forward")]
+    #[test_case("s/a/A/ ; t done ; s/$/?/ ; :done", &["a", "b"], "A\nb?\n"; "if substituted")]
+    #[test_case("s/a/A/ ; t one ; :one t two ; s/$/?/ ; :two", &["a"], "A?\n"; "reset after branching")]
+    #[test_case(":{ s/a/A/1 ; t next ; . ; :next }", &["aa", "b"], "AA\nb\n"; "in loop")]
+    #[test_case("$ { b end ; p ; :end 'done' n }", &["a"], "a\ndone\n"; "in final block")]
+    fn branches(script: &str, input: &[&str], expected: &str) {
+        let program = Program::from_str(script).unwrap();
+        assert_eq!(program.output(input, true), expected)
+    }
+
+    #[test]
+    fn infinite_branching() {
+        let mut program = Program::from_str(":a ; /x/ b a").unwrap();
+        program.max_iterations(Some(100));
+        let mut reader = numbered(&["y", "x"]);
+        let err = program
+            .run(&mut reader, &mut Vec::new(), false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "loop exceeded the limit of 100 iterations at line 2"
+        );
+    }
+
+    #[test]
+    fn infinite_loop() {
+        let mut program = Program::from_str(":{ h ; /x/ b ; }").unwrap();
+        program.max_iterations(Some(100));
+        let mut reader = numbered(&["x", "y"]);
+        let err = program
+            .run(&mut reader, &mut Vec::new(), false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "loop exceeded the limit of 100 iterations at line 2"
+        );
+    }
+
+    #[test_case("p", &["a", "", "b c"], "1\ta\n2\t\n3\tb c\n"; "print")]
+    #[test_case("/b/ p ; /c/ P", &["a", "b c"], "2\tb c\n2\tb c"; "only printed lines")]
+    #[test_case("1 R ; p", &["a", "b", "c"], "2\tb\n3\tc\n"; "read line")]
+    fn number_tab(script: &str, input: &[&str], expected: &str) {
+        let mut program = Program::from_str(script).unwrap();
+        program.number_tab();
+        assert_eq!(program.output(input, false), expected);
+    }
+
+    #[test_case("s/a/A/ ; /b/ s/c/C/ ; :{ s/d/D/ . } ; p", &["abc", "bcd", "x"], "[2] AbC\n[3] bCD\nx\n"; "annotate")]
+    fn annotate(script: &str, input: &[&str], expected: &str) {
+        let mut program = Program::from_str(script).unwrap();
+        program.annotate();
+        assert_eq!(program.output(input, false), expected)
+    }
+
+    #[test_case("$!p", &["a", "b", "c"], "a\nb\n"; "all but last")]
+    #[test_case("1!p", &["a", "b", "c"], "b\nc\n"; "all but first")]
+    #[test_case("1,$ p", &["a", "b", "c"], "a\nc\n"; "first and last")]
+    #[test_case("R ; /x/, $ p", &["a", "b"], "b\n"; "last after reading")]
+    #[test_case("$!p", &[], ""; "no input")]
+    fn last_line(script: &str, input: &[&str], expected: &str) {
+        assert_eq!(
+            Program::from_str(script).unwrap().output(input, false),
+            expected
+        )
+    }
+
+    #[test_case("a'x' a\"y\" i'z'", &["a", "b"], true, "z\na\nx\ny\nz\nb\nx\ny\n"; "order")]
+    #[test_case("a'x' p ; i'y'", &["a"], false, "a\ny\nx\n"; "after printing")]
+    #[test_case("/b/ a'x' d", &["a", "b", "c"], true, "a\nx\nc\n"; "deleted")]
+    #[test_case("2 a'x' q", &["a", "b", "c"], true, "a\nb\nx\n"; "quit")]
+    #[test_case("$ { a'end' }", &["a"], true, "a\nend\n"; "final block")]
+    fn append_and_insert(script: &str, input: &[&str], print_all: bool, expected: &str) {
+        let program = Program::from_str(script).unwrap();
+        assert_eq!(program.output(input, print_all), expected)
+    }
+
+    #[test_case("/x/p", &["a", "x", "b"], "a\nx\nb\n"; "printed once")]
+    #[test_case("/x/ p s/x/y/", &["x"], "x\n"; "changed after printing")]
+    #[test_case("/x/ P", &["x"], "xx\n"; "print without newline")]
+    #[test_case(":{ p . }", &["a", "b"], "a\nb\n"; "in loop")]
+    #[test_case("/x/d", &["a", "x", "b"], "a\nb\n"; "deleted")]
+    #[test_case("$d", &["a", "b", "c"], "a\nb\n"; "last line deleted")]
+    #[test_case("($) d", &["a", "b", "c"], "a\nb\n"; "last line in brackets deleted")]
+    #[test_case("$ s/c/X/", &["c", "c"], "c\nX\n"; "last line substituted")]
+    #[test_case("$ { s/c/X/ p }", &["c", "c"], "c\nc\nX\n"; "final block after printing")]
+    fn print_all(script: &str, input: &[&str], expected: &str) {
+        let program = Program::from_str(script).unwrap();
+        assert_eq!(program.output(input, true), expected)
+    }
+
+    #[test_case("/b/ c'x'", &["a", "b", "c", "b"], "a\nx\nc\nx\n"; "single lines")]
+    #[test_case("2-3 c'x'", &["a", "b", "c", "d"], "a\nx\nd\n"; "range")]
+    #[test_case("/b/-/c/ c'x'", &["b", "c", "b", "d", "c"], "x\nx\n"; "repeated range")]
+    #[test_case("/b/-/b/ c'x'", &["a", "b", "c"], "a\nx\nc\n"; "range of one line")]
+    #[test_case("/a/-+1 c'x'", &["a", "b", "c"], "x\nc\n"; "offset range")]
+    #[test_case("/b/-/z/ c'x'", &["a", "b", "c"], "a\n"; "range not closed")]
+    #[test_case("2-3! c'x'", &["a", "b", "c", "d"], "x\nb\nc\nx\n"; "negated range")]
+    #[test_case("/b/-</d/ c'x'", &["a", "b", "c", "d"], "a\nx\nd\n"; "exclusive range")]
+    #[test_case("/a/-</b/#2 c'x'", &["a", "b", "b", "c"], "x\nb\nc\n"; "exclusive range to nth match")]
+    #[test_case("/a/-<? c'x'", &["a", "a", "b"], "x\nx\nb\n"; "exclusive range to changed line")]
+    #[test_case("/b/ c'x' p", &["a", "b"], "a\nx\n"; "skips the commands")]
+    #[test_case("/b/ a'after' c'x'", &["b", "c"], "x\nafter\nc\n"; "appended text")]
+    fn change(script: &str, input: &[&str], expected: &str) {
+        let program = Program::from_str(script).unwrap();
+        assert_eq!(program.output(input, true), expected)
+    }
+
+    #[test_case(r"/\d+/p", &["a 12 b 3", "none", "4"], "12\n3\n4\n"; "all matches")]
+    #[test_case(r"/\d+/ s/1/x/ p", &["a 12 b 3"], "2\n3\n"; "after substitution")]
+    #[test_case(r"/\d*/p", &["a1"], "1\n"; "skip empty matches")]
+    #[test_case(r"/\d+/#2 p ; 1 p", &["a1", "b2 c3"], "a1\n2\n3\n"; "nth match and line number")]
+    #[test_case(r"/\d+/p", &["1 22 333 4444", "5,6"], "1\n22\n333\n4444\n5\n6\n"; "many matches per line")]
+    #[test_case(r"/1/-/3/ p", &["a1", "b2", "c3"], "a1\nb2\nc3\n"; "range prints lines")]
+    #[test_case(r"/\d/! p ; /\d/, 9 p", &["a", "b2"], "a\nb2\n"; "negation and set print lines")]
+    fn only_matching(script: &str, input: &[&str], expected: &str) {
+        let mut program = Program::from_str(script).unwrap();
+        program.only_matching();
+        assert_eq!(program.output(input, false), expected)
+    }
+
+    #[test_case("p", b"a\nb\r\nc", b"a\nb\nc\n", Outcome::Finished; "print")]
+    #[test_case("/b/ d ; s/a/A/ p", b"a\nb\n", b"A\n", Outcome::Finished; "delete")]
+    #[test_case("2 q 'stop' 4 ; p", b"a\nb\nc\n", b"a\n", Outcome::Quit(4, Some("stop".into())); "quit")]
+    #[test_case("N s/\n/ / p ; $ { = }", b"a\nb\nc\n", b"a b\n3", Outcome::Finished; "read next")]
+    #[test_case("p", b"", b"", Outcome::Finished; "empty input")]
+    fn process(script: &str, input: &[u8], expected: &[u8], outcome: Outcome) {
+        let program = Program::from_str(script).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(program.process(input, &mut out).unwrap(), outcome);
+        assert_eq!(out, expected);
+    }
+
+    #[test_case("p", Outcome::Finished; "print")]
+    #[test_case("d", Outcome::Finished; "delete last line")]
+    #[test_case(":{ . }", Outcome::Finished; "break the loop")]
+    #[test_case("$ { q 3 }", Outcome::Quit(3, None); "quit in final block")]
+    fn outcome(script: &str, expected: Outcome) {
+        let program = Program::from_str(script).unwrap();
+        let mut reader = numbered(&["a", "b"]);
+        let result = program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+        assert_eq!(result.0, expected);
+    }
+
+    #[test]
+    fn quit_with_message() {
+        let program = Program::from_str("/b/ q'build failed' 2").unwrap();
+        let mut reader = numbered(&["a", "b", "c"]);
+        let result = program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+        assert_eq!(result.0, Outcome::Quit(2, Some("build failed".to_string())));
+        assert_eq!(result.1.matches, 1);
+    }
+
+    #[test_case("/b/ d ; /c/ s/c/C/", Summary { matches: 4, lines: 5, kept: 3, deleted: 2, modified: 2, hits: 0 }; "delete and substitute")]
+    #[test_case("/a/ R ; 3 q", Summary { matches: 2, lines: 3, kept: 2, deleted: 0, modified: 1, hits: 0 }; "read and quit")]
+    fn summary(script: &str, expected: Summary) {
+        let program = Program::from_str(script).unwrap();
+        let mut reader = numbered(&["a", "b", "c", "bc", "ac"]);
+        let (_, summary) = program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+        assert_eq!(summary, expected);
+    }
+
+    #[test_case("/a/", 6; "regex")]
+    #[test_case("/a/ ; /a/", 12; "counted by each instruction")]
+    #[test_case("/a/#2", 3; "nth match")]
+    #[test_case("/a*/", 4; "skip empty matches")]
+    #[test_case("1-2", 0; "no regex")]
+    fn count_matches(script: &str, expected: usize) {
+        let mut program = Program::from_str(script).unwrap();
+        program.count_matches();
+        let mut reader = numbered(&["a a", "b", "aaa", "ba"]);
+        let (_, summary) = program
+            .run(&mut reader, &mut std::io::sink(), false)
+            .unwrap();
+        assert_eq!(summary.hits, expected);
+    }
+
+    #[test_case(
+        r"/(?P<id>\d+):/ s/^/${id} / p",
+        &["12: a", "b", "7: c"],
+        "12 12: a\n7 7: c\n";
+        "prefix with captured id"
+    )]
+    #[test_case(
+        r"/(?P<x>a)/ s/(?P<x>b)/[${x}]/ p",
+        &["ab"],
+        "a[b]\n";
+        "own group takes precedence"
+    )]
+    #[test_case(
+        r"/(?P<id>\d)/ s/x/$${id}/ p",
+        &["1x"],
+        "1${id}\n";
+        "escaped dollar"
+    )]
+    #[test_case(
+        r"/(?P<id>\d)/ ; s/x/${id}/ p",
+        &["1x"],
+        "1\n";
+        "scoped to the instruction"
+    )]
+    fn address_captures(script: &str, input: &[&str], expected: &str) {
+        assert_eq!(
+            Program::from_str(script).unwrap().output(input, false),
+            expected
+        )
+    }
 }
@@ -0,0 +1,67 @@
+use crate::{run, Line, ParseOptions, Program, State, Status};
+use anyhow::Result;
+use std::io::{BufRead, Write};
+
+/// Interactive mode: the first line of the `input` is placed in the pattern space,
+/// each of the following lines is parsed as a script and applied to it. After running
+/// each script, the content of the pattern space is printed, parsing errors are printed
+/// and skipped. The hold space and the named buffers are kept between the scripts.
+pub fn repl<R: BufRead, W: Write>(input: R, out: &mut W, options: &ParseOptions) -> Result<()> {
+    let mut lines = input.lines();
+    let Some(first) = lines.next() else {
+        return Ok(());
+    };
+    let mut pattern = Line(1, first?);
+    let mut state = State::default();
+    // there is no more input to read by `r` or `R`
+    let mut reader = std::iter::empty().peekable();
+
+    for script in lines {
+        let script = script?;
+        if script.trim().is_empty() {
+            continue;
+        }
+        let program = match Program::parse_str(&script, options) {
+            Ok(program) => program,
+            Err(err) => {
+                writeln!(out, "error: {}", err)?;
+                continue;
+            }
+        };
+        state.max_iterations = program.options.max_iterations;
+        state.iterations = 0;
+        let mut status = run(&program.actions, &mut pattern, &mut state, &mut reader, out)?;
+        if !matches!(status, Some(Status::Quit(..))) {
+            status = run(&program.finally, &mut pattern, &mut state, &mut reader, out)?;
+        }
+        writeln!(out, "{}", pattern.1)?;
+        state.flush_appended(out)?;
+        if let Some(Status::Quit(..)) = status {
+            break;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::repl;
+    use crate::ParseOptions;
+
+    #[test]
+    fn scripted_session() {
+        let input = "hello world\ns/world/there/\nh ; s/hello/bye/\n\np =\nk(\nj\nq\np";
+        let mut out = Vec::new();
+        repl(input.as_bytes(), &mut out, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "hello there\n\
+            bye there\n\
+            bye there\n1bye there\n\
+            error: 1:2: unexpected '('\n\
+            bye there\nhello there\n\
+            bye there\nhello there\n"
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use regex::Captures;
+
+type Function = fn(&str) -> String;
+
+/// Functions that can be used in the substitution templates as `${name:template}`.
+const FUNCTIONS: [(&str, Function); 5] = [
+    ("html", html_escape),
+    ("unhtml", html_unescape),
+    ("url", url_encode),
+    ("unurl", url_decode),
+    ("env", env_var),
+];
+
+/// Check if the template calls the `${env:...}` function, that is allowed only with `--expand-env`.
+pub(crate) fn uses_env(template: &str) -> bool {
+    let mut rest = template;
+    while let Some((start, end, _)) = find_function(rest) {
+        if rest[start..].starts_with("${env:") {
+            return true;
+        }
+        rest = &rest[end..];
+    }
+    false
+}
+
+/// Check if the template can be expanded with [`Captures::expand`], so it has
+/// no function calls and no backslash escapes.
+pub(crate) fn is_plain(template: &str) -> bool {
+    !template.contains('\\') && find_function(template).is_none()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+impl Case {
+    fn push(self, c: char, acc: &mut String) {
+        match self {
+            Case::Upper => acc.extend(c.to_uppercase()),
+            Case::Lower => acc.extend(c.to_lowercase()),
+        }
+    }
+}
+
+/// The case conversion pending while expanding the template.
+#[derive(Debug, Default)]
+struct Conversion {
+    /// Set by `\U` or `\L`, until `\E`
+    all: Option<Case>,
+    /// Set by `\u` or `\l`, only for the next character
+    next: Option<Case>,
+}
+
+impl Conversion {
+    fn push_str(&mut self, s: &str, acc: &mut String) {
+        for c in s.chars() {
+            match self.next.take().or(self.all) {
+                Some(case) => case.push(c, acc),
+                None => acc.push(c),
+            }
+        }
+    }
+}
+
+/// Expand the template like [`Captures::expand`], additionally applying
+/// the `${name:template}` functions to their expanded arguments, and
+/// the `\U`, `\L`, `\E`, `\u`, `\l` case conversions. Literal backslashes
+/// are escaped as `\\`.
+pub(crate) fn expand(template: &str, caps: &Captures) -> String {
+    let mut acc = String::new();
+    let mut conversion = Conversion::default();
+    let mut rest = template;
+    while let Some(i) = rest.find('\\') {
+        conversion.push_str(&expand_functions(&rest[..i], caps), &mut acc);
+        let escape = rest[i + 1..].chars().next();
+        match escape {
+            Some('U') => conversion.all = Some(Case::Upper),
+            Some('L') => conversion.all = Some(Case::Lower),
+            Some('E') => conversion = Conversion::default(),
+            Some('u') => conversion.next = Some(Case::Upper),
+            Some('l') => conversion.next = Some(Case::Lower),
+            Some('\\') | None => conversion.push_str("\\", &mut acc),
+            Some(c) => {
+                acc.push('\\');
+                conversion.push_str(&c.to_string(), &mut acc);
+            }
+        }
+        rest = &rest[i + 1 + escape.map_or(0, char::len_utf8)..];
+    }
+    conversion.push_str(&expand_functions(rest, caps), &mut acc);
+    acc
+}
+
+/// Expand the template applying the `${name:template}` functions.
+fn expand_functions(template: &str, caps: &Captures) -> String {
+    let mut acc = String::new();
+    let mut rest = template;
+    while let Some((start, end, func)) = find_function(rest) {
+        caps.expand(&rest[..start], &mut acc);
+        let body = &rest[start..end];
+        let (_, arg) = body[2..body.len() - 1].split_once(':').unwrap();
+        let mut expanded = String::new();
+        caps.expand(arg, &mut expanded);
+        acc.push_str(&func(&expanded));
+        rest = &rest[end..];
+    }
+    caps.expand(rest, &mut acc);
+    acc
+}
+
+/// Find the first `${name:...}` call, returns its bounds and the function.
+fn find_function(s: &str) -> Option<(usize, usize, Function)> {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        if bytes[i] != b'$' {
+            i += 1;
+            continue;
+        }
+        match bytes[i + 1] {
+            // escaped dollar
+            b'$' => i += 2,
+            b'{' => {
+                let inner = &s[i + 2..];
+                let func = FUNCTIONS.iter().find_map(|(name, func)| {
+                    inner
+                        .strip_prefix(name)
+                        .filter(|r| r.starts_with(':'))
+                        .map(|_| *func)
+                });
+                if let (Some(func), Some(len)) = (func, closing_brace(inner)) {
+                    return Some((i, i + 2 + len + 1, func));
+                }
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Position of the `}` closing the already opened brace.
+fn closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+fn html_escape(s: &str) -> String {
+    let mut acc = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => acc.push_str("&amp;"),
+            '<' => acc.push_str("&lt;"),
+            '>' => acc.push_str("&gt;"),
+            '"' => acc.push_str("&quot;"),
+            '\'' => acc.push_str("&#39;"),
+            _ => acc.push(c),
+        }
+    }
+    acc
+}
+
+fn html_unescape(s: &str) -> String {
+    let mut acc = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        acc.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                entity => {
+                    let code = match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => entity.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                acc.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                acc.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    acc.push_str(rest);
+    acc
+}
+
+fn url_encode(s: &str) -> String {
+    let mut acc = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                acc.push(b as char)
+            }
+            _ => acc.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    acc
+}
+
+/// The value of the environment variable, for `NAME:default` the `default`
+/// is used when the variable is not set, otherwise it is an empty string.
+fn env_var(s: &str) -> String {
+    let (name, default) = s.split_once(':').unwrap_or((s, ""));
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut acc = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s.get(i + 1..i + 3).filter(|_| bytes[i] == b'%');
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(b) => {
+                acc.push(b);
+                i += 3;
+            }
+            None => {
+                acc.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&acc).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    #[test_case("<.*>", "${html:$0}", "x <a> y", "x &lt;a&gt; y"; "html escape")]
+    #[test_case(".+", "${unhtml:$0}", "&lt;a&gt; &amp;amp; &#65;&#x42; &foo", "<a> &amp; AB &foo"; "html unescape")]
+    #[test_case(".+", "${url:$0}", "a b/ü", "a%20b%2F%C3%BC"; "url encode")]
+    #[test_case(".+", "${unurl:$0}", "a%20b%2F%C3%BC%zz", "a b/ü%zz"; "url decode")]
+    #[test_case("(?P<k>\\w+)=(?P<v>.*)", "${k}=${url:${v}!}", "q=a b", "q=a%20b%21"; "named groups")]
+    #[test_case("a", "$${url:$0}", "a", "${url:a}"; "escaped dollar")]
+    #[test_case("a", "${url:$0", "a", "${url:a"; "not closed")]
+    #[test_case(r"(\w+)", r"\U$1", "hello world", "HELLO WORLD"; "uppercase")]
+    #[test_case(r"(\w+) (\w+)", r"\L$1\E $2", "HeLLo WoRLD", "hello WoRLD"; "lowercase until end")]
+    #[test_case(r"\w+", r"\u$0", "hello world", "Hello World"; "uppercase next")]
+    #[test_case(r"\w+", r"\l\U$0", "hello", "hELLO"; "lowercase next")]
+    #[test_case(r"\w+", r"\u\L$0", "hELLO", "Hello"; "uppercase next then lowercase")]
+    #[test_case("ß", r"\U$0-\u${url:$0}", "ß", "SS-%C3%9F"; "multiple characters")]
+    #[test_case("a", r"\\U$0\\", "a", r"\Ua\"; "escaped backslash")]
+    #[test_case(r"\$\{(\w+)\}", "${env:$1}", "${SE_TEST_HOME}/${SE_TEST_MISSING}", "/home/se/"; "env")]
+    #[test_case(r"%(\w+)%", "${env:$1:$0}", "%SE_TEST_HOME% %SE_TEST_MISSING%", "/home/se %SE_TEST_MISSING%"; "env with default")]
+    fn expand(regex: &str, template: &str, input: &str, expected: &str) {
+        std::env::set_var("SE_TEST_HOME", "/home/se");
+        let regex = regex::Regex::new(regex).unwrap();
+        let result = regex.replace_all(input, |caps: &regex::Captures| {
+            super::expand(template, caps)
+        });
+        assert_eq!(result, expected)
+    }
+}
@@ -22,7 +22,7 @@ teardown() {
 }
 
 @test "Using q command results in proper error code" {
-	run ./se 'q 13' README.md
+	run ./se -n 'q 13' README.md
 	[ "$status" -eq 13 ]
 }
 
@@ -31,123 +31,165 @@ teardown() {
 }
 
 @test "Print all" {
-	run diff <(./se 'p' README.md) <(cat README.md)
+	run diff <(./se -n 'p' README.md) <(cat README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Print vs Println" {
-	run diff <(./se 'p' README.md) <(./se 'Pn' README.md)
+	run diff <(./se -n 'p' README.md) <(./se -n 'Pn' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Print all with -a and no command" {
-	run diff <(./se -a '' README.md) <(cat README.md)
+	run diff <(./se '' README.md) <(cat README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Empty script works like cat" {
+   run diff <(printf "1\n2\n3\n" | ./se -n '') <(printf "1\n2\n3\n")
+   [ "$status" -eq 0 ]
+
+   run diff <(printf "1\n2\n3\n" | ./se '') <(printf "1\n2\n3\n")
    [ "$status" -eq 0 ]
 }
 
 @test "Group of commands" {
-	run diff <(./se '1ppp' README.md) <(sed -n '1 {p;p;p;}' README.md)
+	run diff <(./se -n '1ppp' README.md) <(sed -n '1 {p;p;p;}' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Delete lines" {
-	run diff <(./se -a '/sed/ d' README.md) <(sed '/sed/ d' README.md)
+	run diff <(./se '/sed/ d' README.md) <(sed '/sed/ d' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Use negation" {
-   run diff <(./se '(1-3)! p' README.md) <(tail -n +4 README.md)
+   run diff <(./se -n '(1-3)! p' README.md) <(tail -n +4 README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Use negation with set" {
-   run diff <(./se '(1,2,3)! p' README.md) <(tail -n +4 README.md)
+   run diff <(./se -n '(1,2,3)! p' README.md) <(tail -n +4 README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Count is consistent with number of printed lines" {
-	[ $(./se -c '/the/' README.md) -eq $(./se '/the/ p' README.md | wc -l | sed 's/ *//') ]
+	[ $(./se -c '/the/' README.md) -eq $(./se -n '/the/ p' README.md | wc -l | sed 's/ *//') ]
 }
 
 @test "Consistent with sed line counting" {
-   run diff <(sed '=' README.md) <(./se '=np' README.md)
+   run diff <(sed '=' README.md) <(./se -n '=np' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Special characters in template" {
-   run diff <(./se '=np' README.md) <(./se '="\n"p' README.md)
+   run diff <(./se -n '=np' README.md) <(./se -n '="\n"p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Substitute like sed" {
    run diff <(sed -E 's/(sed)/__\1__/g' README.md) \
-            <(./se 's/(sed)/__$1__/p' README.md)
+            <(./se -n 's/(sed)/__$1__/p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Case conversion like sed" {
+   run diff <(sed -E 's/(\w+) (\w+)/\U\1\E \u\2/g' README.md) \
+            <(./se 's/(\w+) (\w+)/\U$1\E \u$2/' README.md)
    [ "$status" -eq 0 ]
 }
 
+@test "Quiet mode is like sed -n" {
+   run diff <(sed -n '/sed/p' README.md) <(./se -n '/sed/p' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(./se -n '/sed/p' README.md) <(./se '/sed/!d' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(sed '/sed/d' README.md) <(./se '/sed/d' README.md)
+   [ "$status" -eq 0 ]
+   run diff README.md <(./se '/sed/p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Literal replacement" {
+   run ./se -n 's/x/\n$0/p' <<< 'x'
+   [ "$output" = "$(printf '\nx')" ]
+   run ./se -n --literal-replacement 's/x/\n$0\//p' <<< 'x'
+   [ "$output" = '\nx/' ]
+}
+
 @test "Simple substitute is like in sed" {
-   run diff <(sed 's/a/#/g' README.md) <(./se -a 's/a/#/' README.md)
+   run diff <(sed 's/a/#/g' README.md) <(./se 's/a/#/' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Simple substitute with one replacement is like in sed" {
-   run diff <(sed 's/a/#/' README.md) <(./se -a 's/a/#/1' README.md)
+   run diff <(sed 's/a/#/' README.md) <(./se 's/a/#/1' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Substitute and print vs sed" {
-   run diff <(sed -n 's/a/#/gp' README.md) <(./se '_s/a/#/p' README.md)
+   run diff <(sed -n 's/a/#/gp' README.md) <(./se -n '_s/a/#/p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Print selected lines like in sed" {
-   run diff <(sed -n '3,/address/ p' README.md) <(./se '3-/address/ p' README.md)
+   run diff <(sed -n '3,/address/ p' README.md) <(./se -n '3-/address/ p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Print head using line matching" {
-   run diff <(head -n 5 README.md) <(./se '1-5p.q' README.md)
+   run diff <(head -n 5 README.md) <(./se -n '1-5p.q' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Print head using repeated read" {
-   run diff <(head -n 5 README.md) <(./se 'r4 p q' README.md)
+   run diff <(head -n 5 README.md) <(./se -n 'r4 p q' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Print tail" {
    run diff <(tail -n 5 README.md) \
-            <(./se '1 r4x . x s/[^\n]*\n(.*)/$1/1 jx . $ xp' README.md)
+            <(./se -n '1 r4x . x s/[^\n]*\n(.*)/$1/1 jx . $ { xp }' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Be like cut" {
-   run diff <(cut -c '2-7' src/main.rs) <(./se 'k2-7p' src/main.rs)
+   run diff <(cut -c '2-7' src/main.rs) <(./se -n 'k2-7p' src/main.rs)
+   [ "$status" -eq 0 ]
+}
+
+@test "Split into fields" {
+   run diff <(printf "a,,b\nc\n" | ./se -n 'F/,/') <(printf "a\n\nb\nc\n")
    [ "$status" -eq 0 ]
 }
 
 @test "Replace all like in sed" {
-   run diff <(sed -nE 's/in (`sed`)/__&__/p' README.md) <(./se '/in `sed`/ s/in (`sed`)/__$0__/p' README.md)
+   run diff <(sed -nE 's/in (`sed`)/__&__/p' README.md) <(./se -n '/in `sed`/ s/in (`sed`)/__$0__/p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Replace captured group like in sed" {
-   run diff <(sed -nE 's/in (`sed`)/__\1__/p' README.md) <(./se '/in `sed`/ s/in (`sed`)/__$1__/p' README.md)
+   run diff <(sed -nE 's/in (`sed`)/__\1__/p' README.md) <(./se -n '/in `sed`/ s/in (`sed`)/__$1__/p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Count lines like sed" {
-   run diff <(sed -n '$=' README.md) <(./se '$="\n"' README.md)
+   run diff <(sed -n '$=' README.md) <(./se -n '$="\n"' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Stop early" {
-   run diff <(se '7=q' README.md) <(printf "7")
+   run diff <(se -n '7=q' README.md) <(printf "7")
    [ "$status" -eq 0 ]
 }
 
 @test "Eval works" {
-   run diff <(pwd) <(./se 'ep' <(echo 'printf $(pwd)'))
+   run diff <(pwd) <(./se -n 'ep' <(echo 'printf $(pwd)'))
+   [ "$status" -eq 0 ]
+}
+
+@test "Remove duplicates like uniq" {
+   run diff <(printf "a\na\nb\na\n" | uniq) <(printf "a\na\nb\na\n" | ./se -n '? p')
    [ "$status" -eq 0 ]
 }
 
@@ -157,27 +199,40 @@ teardown() {
 }
 
 @test "Reverse lines like tac" {
-   run diff <(tac README.md) <(./se '1! j ; $ p ; h' README.md)
+   run diff <(tac README.md) <(./se -n '1! j ; $ p ; h' README.md)
    [ "$status" -eq 0 ]
 }
 
+@test "Delete the last line like sed" {
+   run diff <(sed '$d' README.md) <(./se '$d' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(sed '$ s/^/last: /' README.md) <(./se '$ s/^/last: /' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Change the case" {
+   [ "$(echo 'Grüße' | ./se -n 'U p')" = "GRÜSSE" ]
+   [ "$(echo 'Grüße' | ./se -n 'L p')" = "grüße" ]
+   [ "$(printf 'a\nb\n' | ./se '2 U')" = "$(printf 'a\nB')" ]
+}
+
 @test "Flags work" {
    # no flag
-   run diff <(sed -n '/Address/p' README.md) <(./se '/Address/p' README.md)
+   run diff <(sed -n '/Address/p' README.md) <(./se -n '/Address/p' README.md)
    [ "$status" -eq 0 ]
 
    # with flag
-   run diff <(sed -n '/Address/Ip' README.md) <(./se '/(?i)Address/p' README.md)
+   run diff <(sed -n '/Address/Ip' README.md) <(./se -n '/(?i)Address/p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Whole line syntax" {
-   run diff <(./se '/^## Commands$/ p' README.md) <(./se '^## Commands$ p' README.md)
+   run diff <(./se -n '/^## Commands$/ p' README.md) <(./se -n '^## Commands$ p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Empty regex in address" {
-   run diff <(./se '// p' README.md) <(cat README.md)
+   run diff <(./se -n '// p' README.md) <(cat README.md)
    [ "$status" -eq 0 ]
 }
 
@@ -198,24 +253,24 @@ EOF
 
 @test "Use script file with comments" {
    run create_commented_script
-   run diff <(./se -f /tmp/script.sed README.md) <(./se '/sed\` /p' README.md)
+   run diff <(./se -n -f /tmp/script.sed README.md) <(./se -n '/sed\` /p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Use hold buffer to delay printing lines" {
-   run diff <(sed -n '{x;p;}' README.md) <(./se 'xp' README.md)
+   run diff <(sed -n '{x;p;}' README.md) <(./se -n 'xp' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Use hold and pattern buffers" {
    run diff <(sed -n '7h ; 8{x;G;h} ; 9{x;G;p}' README.md) \
-            <(./se '7h; 8xjh; 9xjp' README.md)
+            <(./se -n '7h; 8xjh; 9xjp' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Condition on substitute like sed" {
    run diff <(sed -nE 's/(sed)/__\1__/gp' README.md) \
-            <(./se '_ s/(sed)/__$1__/p' README.md)
+            <(./se -n '_ s/(sed)/__$1__/p' README.md)
    [ "$status" -eq 0 ]
 }
 
@@ -233,7 +288,7 @@ bash_line_marker() {
 }
 
 @test "The stop behavior works as intended" {
-   run diff <(bash_line_marker) <(./se '/sed/ ">>> " p . /the/ "*** " p . "    " p' README.md)
+   run diff <(bash_line_marker) <(./se -n '/sed/ ">>> " p . /the/ "*** " p . "    " p' README.md)
    [ "$status" -eq 0 ]
 }
 
@@ -245,19 +300,19 @@ only_for_gsed() {
 
 @test "Clear buffer like gsed" {
    only_for_gsed
-   run diff <(./se -a '/sed/ z' README.md) <(sed '/sed/ z' README.md)
+   run diff <(./se '/sed/ z' README.md) <(sed '/sed/ z' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Append text like gsed" {
    only_for_gsed
-   run diff <(sed '/sed/a >>>' README.md) <(./se '/sed/ p ">>>\n" . p' README.md)
+   run diff <(sed '/sed/a >>>' README.md) <(./se -n '/sed/ p ">>>\n" . p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Insert text like gsed" {
    only_for_gsed
-   run diff <(sed '/sed/i >>>' README.md) <(./se '/sed/ ">>>\n" p . p' README.md)
+   run diff <(sed '/sed/i >>>' README.md) <(./se -n '/sed/ ">>>\n" p . p' README.md)
    [ "$status" -eq 0 ]
 }
 
@@ -266,23 +321,542 @@ only_for_gsed() {
    echo 2 > /tmp/b.txt
    echo 3 > /tmp/c.txt
 
-   run diff <(./se 'p' /tmp/a.txt /tmp/b.txt /tmp/c.txt) <(printf "1\n2\n3\n")
+   run diff <(./se -n 'p' /tmp/a.txt /tmp/b.txt /tmp/c.txt) <(printf "1\n2\n3\n")
    [ "$status" -eq 0 ]
 
    echo 'p' > /tmp/script.sed
-   run diff <(./se -f /tmp/script.sed /tmp/a.txt /tmp/b.txt /tmp/c.txt) <(printf "1\n2\n3\n")
+   run diff <(./se -n -f /tmp/script.sed /tmp/a.txt /tmp/b.txt /tmp/c.txt) <(printf "1\n2\n3\n")
+   [ "$status" -eq 0 ]
+}
+
+@test "Read the list of files from NUL-separated file" {
+   echo 1 > /tmp/a.txt
+   echo 2 > /tmp/b.txt
+
+   printf "/tmp/a.txt\0/tmp/b.txt\0" > /tmp/c.txt
+   run diff <(./se -n --files0-from /tmp/c.txt 'p') <(printf "1\n2\n")
+   [ "$status" -eq 0 ]
+
+   # the names don't need to be valid UTF-8
+   echo 3 > "$(printf '/tmp/se-\377.txt')"
+   printf "/tmp/a.txt\0/tmp/se-\377.txt\0" > /tmp/c.txt
+   run diff <(./se -n --files0-from /tmp/c.txt 'p') <(printf "1\n3\n")
+   rm "$(printf '/tmp/se-\377.txt')"
+   [ "$status" -eq 0 ]
+
+   run diff <(printf "/tmp/a.txt\0/tmp/b.txt" | ./se -n --files0-from - 'p') <(printf "1\n2\n")
+   [ "$status" -eq 0 ]
+}
+
+@test "Walk the directories with find" {
+   dir=$(mktemp -d)
+   mkdir -p "$dir/a/b/c"
+   echo 0 > "$dir/top.txt"
+   echo 1 > "$dir/a/one.txt"
+   echo 2 > "$dir/a/b/two.txt"
+   echo 3 > "$dir/a/b/c/three.txt"
+   run bash -c "find '$dir' -maxdepth 2 -type f -print0 | sort -z | ./se -n --files0-from - p"
+   [ "$output" = "$(printf '1\n0')" ]
+   run bash -c "find '$dir' -type f -print0 | ./se --files0-from - -c //"
+   [ "$output" = "4" ]
+   rm -r "$dir"
+}
+
+@test "Walk the directories recursively" {
+   dir=$(mktemp -d)
+   mkdir -p "$dir/a/b/c"
+   echo 0 > "$dir/top.txt"
+   echo 1 > "$dir/a/one.txt"
+   echo 2 > "$dir/a/b/two.txt"
+   echo 3 > "$dir/a/b/c/three.txt"
+   run ./se -n -r p "$dir"
+   [ "$output" = "$(printf '3\n2\n1\n0')" ]
+   run ./se -n -r --max-depth 0 p "$dir"
+   [ "$output" = "0" ]
+   run ./se -n -r --max-depth 1 p "$dir" "$dir/top.txt"
+   [ "$output" = "$(printf '1\n0\n0')" ]
+   run ./se -n -r --max-depth 0 p "$dir/a/b"
+   [ "$output" = "2" ]
+   run ./se -n --max-depth 1 p "$dir"
+   [ "$status" -ne 0 ]
+   rm -r "$dir"
+}
+
+@test "Sorting instructions does not change the results" {
+   run diff <(./se -c '/sed/, 3 ; /the/ ; 1' README.md) \
+            <(./se -n --sort-instructions -c '/sed/, 3 ; /the/ ; 1' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "NUL bytes are preserved" {
+   run diff <(printf "a\0b\nc\n" | ./se -n 'p' | od -c) <(printf "a\0b\nc\n" | od -c)
+   [ "$status" -eq 0 ]
+
+   run diff <(printf "a\0b\nc\n" | ./se -n '\0 s/b/X/p' | od -c) <(printf "a\0X\n" | od -c)
+   [ "$status" -eq 0 ]
+
+   run diff <(printf "a\377\0b\nc\n" | ./se -n '\0 p' | od -c) <(printf "a\357\277\275\0b\n" | od -c)
+   [ "$status" -eq 0 ]
+}
+
+@test "Dump named buffers to files" {
+   seq 1 10 | ./se -n --dump odd=/tmp/a.txt --dump even=/tmp/b.txt '/[13579]$/ A odd ; /[02468]$/ A even'
+
+   run diff /tmp/a.txt <(seq 1 2 10)
+   [ "$status" -eq 0 ]
+
+   run diff /tmp/b.txt <(seq 2 2 10)
+   [ "$status" -eq 0 ]
+}
+
+@test "Skip the rest of the file on timeout" {
+   seq 1 5000000 > /tmp/a.txt
+   echo end > /tmp/b.txt
+
+   run ./se -n --timeout-per-file 0.001 'p' /tmp/a.txt /tmp/b.txt
+   [ "$status" -eq 0 ]
+   [ "${lines[-1]}" = "end" ]
+   [[ "$output" == *"warning: timeout exceeded, skipping the rest of /tmp/a.txt"* ]]
+   [ "${#lines[@]}" -lt 5000001 ]
+}
+
+@test "Final block runs once after the input" {
+   run diff <(printf "a\nb\n" | ./se -n 'p ; $ { "done\n" ; /b/ "last was b\n" ; /a/ "never\n" }') \
+            <(printf "a\nb\ndone\nlast was b\n")
+   [ "$status" -eq 0 ]
+}
+
+@test "No trailing newline" {
+   run diff <(./se -n --no-trailing-sep 'p' README.md) <(head -c -1 README.md)
+   [ "$status" -eq 0 ]
+
+   run diff <(printf "a\nb\n" | ./se -n --no-trailing-sep -c '//') <(printf "2")
+   [ "$status" -eq 0 ]
+}
+
+@test "All but the first or the last line" {
+   run diff <(printf "b\nc\n") <(printf "a\nb\nc\n" | ./se -n '1!p')
+   [ "$status" -eq 0 ]
+   run diff <(printf "a\nb\n") <(printf "a\nb\nc\n" | ./se -n '$!p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Indentation" {
+   run diff <(printf "\tb\n        c\n") <(printf "a\n\tb\n  x\n        c\n" | ./se -n 'indent>4 p')
+   [ "$status" -eq 0 ]
+   run diff <(printf "        c\n") <(printf "a\n\tb\n  x\n        c\n" | ./se -n --tab-width 2 'indent>4 p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Quit with a message" {
+   run ./se -n "/b/ q'build failed' 2" <(printf "a\nb\nc\n")
+   [ "$status" -eq 2 ]
+   [ "$output" = "build failed" ]
+}
+
+@test "Include script files" {
+   dir=$(mktemp -d)
+   printf "s/a/A/\n" > "$dir/upper.se"
+   printf "@include upper.se\np\n" > "$dir/main.se"
+   run diff <(printf "A\nbA\n") <(printf "a\nba\n" | ./se -n -f "$dir/main.se")
+   rm -r "$dir"
+   [ "$status" -eq 0 ]
+}
+
+@test "Measure throughput" {
+   run diff <(printf "b\n") <(printf "a\nb\nc\n" | ./se -n --measure '/b/p' 2>/dev/null)
+   [ "$status" -eq 0 ]
+   run bash -c "printf 'a\nb\nc\n' | ./se -n --measure '/b/p' 2>&1 >/dev/null"
+   [[ "$output" == "lines: 3, bytes: 6, elapsed: "* ]]
+}
+
+@test "Summary of the processed lines" {
+   run diff <(printf "a\nC\naC\n") <(printf "a\nb\nc\nbc\nac\n" | ./se --summary '/b/ d ; /c/ s/c/C/' 2>/dev/null)
+   [ "$status" -eq 0 ]
+   run bash -c "printf 'a\nb\nc\nbc\nac\n' | ./se --summary '/b/ d ; /c/ s/c/C/' 2>&1 >/dev/null"
+   [ "$output" = "lines: 5, kept: 3, deleted: 2, modified: 2" ]
+}
+
+@test "Line followed by another line" {
+   run diff <(printf "2\n6\n") <(printf "a\nb\n\nb\nc\nb\n\n" | ./se -n '/b/>^$ =n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Log of the changed lines" {
+   log=$(mktemp)
+   run diff <(printf "1\nX\n3\n") <(printf "1\n2\n3\n" | ./se --changes-log "$log" 's/2/X/')
+   [ "$status" -eq 0 ]
+   run diff <(printf "2:-2\n2:+X\n") "$log"
+   rm "$log"
+   [ "$status" -eq 0 ]
+}
+
+@test "Log of the changed lines in the edited files" {
+   dir=$(mktemp -d)
+   printf "a\nb\n" > "$dir/e1.txt"
+   printf "b\nc\n" > "$dir/e2.txt"
+   ./se -i --changes-log "$dir/log" 's/b/Y/' "$dir/e1.txt" "$dir/e2.txt"
+   run diff <(printf "$dir/e1.txt:2:-b\n$dir/e1.txt:2:+Y\n$dir/e2.txt:1:-b\n$dir/e2.txt:1:+Y\n") "$dir/log"
+   rm -r "$dir"
+   [ "$status" -eq 0 ]
+}
+
+@test "Tab-separated line numbers" {
+   run diff <(printf "1\ta\n3\tc d\n") <(printf "a\nb\nc d\n" | ./se -n --number-tab '2!p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Match the lines from a file" {
+   set=$(mktemp)
+   printf "b\nd\n" > "$set"
+   run diff <(printf "b\nd\nb\n") <(printf "a\nb\nc\nd\nbb\nb\n" | ./se --match-lines-from "$set" '')
+   rm "$set"
+   [ "$status" -eq 0 ]
+}
+
+@test "Transform the hold space" {
+   run diff <(printf "abc\nXbc\n") <(printf "abc\n" | ./se -n 'h ; H{ s/a/X/ } ; p ; g ; p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Regex flags" {
+   run diff <(printf "xbc\nxBC\n") <(printf "abc\nABC\ndef\n" | ./se -n '/ABC/i s/a/x/ip')
+   [ "$status" -eq 0 ]
+}
+
+@test "Ranges excluding the end" {
+   run diff <(sed -n '/^## Syntax/,/^## Addresses/ p' README.md | sed '$d') \
+            <(./se -n '/^## Syntax/-</^## Addresses/ p' README.md)
+   [ "$status" -eq 0 ]
+   [ "$(printf 'a\nb\na\nb\n' | ./se -c '/a/-</a/')" = "4" ]
+}
+
+@test "Environment variables in the substitutions" {
+   [ "$(echo 'home=${HOME} x=${SE_UNSET_VAR}' | ./se -n --expand-env 's/\$\{(\w+)\}/${env:$1:?}/p')" = "home=$HOME x=?" ]
+   run ./se -n 's/\$\{(\w+)\}/${env:$1}/p' README.md
+   [ "$status" -ne 0 ]
+}
+
+@test "Count the matches" {
+   [ "$(./se -co '/sed/' README.md)" = "$(grep -o 'sed' README.md | wc -l)" ]
+   [ "$(./se --count-matches '/sed/' README.md)" = "$(grep -o 'sed' README.md | wc -l)" ]
+   [ "$(printf 'a a\nb\naaa\n' | ./se -co '/a/')" = "5" ]
+   [ "$(printf 'a a\nb\naaa\n' | ./se -c '/a/')" = "2" ]
+}
+
+@test "Highlight the matches" {
+   [ "$(echo 'banana' | ./se -n --color=always '/n/ p')" = "$(printf 'ba\033[01;31mn\033[0ma\033[01;31mn\033[0ma')" ]
+   [ "$(echo 'banana' | ./se --color=always 's/b/B/')" = "$(printf '\033[01;31mB\033[0manana')" ]
+   [ "$(echo 'banana' | ./se -n --color=never '/n/ p')" = "banana" ]
+   [ "$(echo 'banana' | ./se -n '/n/ p')" = "banana" ]
+}
+
+@test "Context of the matches" {
+   run diff <(grep -C 2 'sed' README.md) <(./se -C 2 '/sed/ p' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(grep -B 1 -A 3 'se' README.md) <(./se -B 1 -A 3 '/se/ p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Context with the attached numbers like grep -C2" {
+   run diff <(grep -C2 'sed' README.md) <(./se -C2 '/sed/ p' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(grep -B1 -A3 'se' README.md) <(./se -B1 -A3 '/se/ p' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(grep -C1 'sed' README.md) <(./se -nC1 '/sed/ p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Separators of the context groups" {
+   run diff <(grep -C 1 --group-separator '==' 'sed' README.md) \
+            <(./se -C 1 --group-separator '==' '/sed/ p' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(grep -C 1 --no-group-separator 'sed' README.md) \
+            <(./se -C 1 --no-group-separator '/sed/ p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Multi-line mode" {
+   run diff <(printf "> a\n> b\n> c\n") <(printf "a\nb\nc\n" | ./se -n --multiline 'r2 ; s/^/> /p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Transliterate" {
+   run diff <(printf "CAB\nB-A\n") <(printf "cab\nb\ta\n" | ./se -n 'y/abc\t/ABC-/p')
+   [ "$status" -eq 0 ]
+   run ./se -n 'y/abc/AB/' <<< ''
+   [ "$status" -eq 1 ]
+}
+
+@test "Append and insert lines" {
+   run diff <(printf "[\na\n]\n)\n[\nc\n]\n") \
+            <(printf "a\nb\nc\n" | ./se '/b/ a")" d ; i"[" a"]"')
+   [ "$status" -eq 0 ]
+}
+
+@test "Only matching like grep -o" {
+   run diff <(grep -oE '[0-9]+' README.md) <(./se -o '/[0-9]+/p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Only matching prints each match" {
+   [ "$(seq 1 100 | paste -d ' ' - - - - | ./se -o '/\d+/p' | wc -l)" = "100" ]
+   [ "$(printf '1 22\nx\n333\n' | ./se -o '/\d+/p')" = "$(printf '1\n22\n333')" ]
+   [ "$(printf '1\n2\n3\n' | ./se -o '/1/-/2/ p')" = "$(printf '1\n2')" ]
+}
+
+@test "Regex size limit" {
+   run ./se -n --regex-size-limit 1000 '/\w{100}/p' <<< ''
+   [ "$status" -eq 1 ]
+   [[ "$output" == *"size limit"* ]]
+   run ./se -n --regex-size-limit 1000 '/a+/p' <<< 'aa'
+   [ "$output" = "aa" ]
+}
+
+@test "Write to files" {
+   dir=$(mktemp -d)
+   ./se -n "/sed/ w $dir/sed.txt ; /se\b/ w $dir/se.txt" README.md
+   run diff <(grep 'sed' README.md) "$dir/sed.txt"
+   [ "$status" -eq 0 ]
+   run diff <(grep -P 'se\b' README.md) "$dir/se.txt"
+   [ "$status" -eq 0 ]
+   rm -r "$dir"
+}
+
+@test "Errors point at the position in the script" {
+   printf '1p ;\n/a/ s/a/b/ ;\n  /b/ p }\n' > /tmp/script.sed
+   run ./se -n -f /tmp/script.sed README.md
+   [ "$status" -eq 1 ]
+   [[ "$output" == *"3:9: unexpected '}'"* ]]
+}
+
+@test "N joins the lines like in sed" {
+   run diff <(seq 1 7 | sed 'N;s/\n/ /') <(seq 1 7 | ./se 'N s/\n/ /')
+   [ "$status" -eq 0 ]
+}
+
+@test "Write compressed files" {
+   gzip -c README.md | ./se -n '' 2>/dev/null | cmp -s - README.md || skip "built without the gzip feature"
+   ./se -n '/se/ w /tmp/a.txt.gz' README.md
+   run diff <(grep 'se' README.md) <(gzip -dc /tmp/a.txt.gz)
+   rm -f /tmp/a.txt.gz
+   [ "$status" -eq 0 ]
+}
+
+@test "Read files" {
+   run diff <(sed '/^## /r tests.bats' README.md) <(./se '/^## / r "tests.bats"' README.md)
+   [ "$status" -eq 0 ]
+   run diff README.md <(./se 'r "missing-file.txt"' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Streaming mode" {
+   run ./se -n --streaming '$d' <<< ''
+   [ "$status" -eq 1 ]
+   [[ "$output" == *"cannot be used when streaming"* ]]
+   run bash -c "yes | ./se -n --streaming '/y/ p ; 3 q' 2>/dev/null"
+   [ "$output" = "$(printf 'y\ny\ny')" ]
+   [ "$(printf 'a\nb\n' | ./se -n --streaming 'p ; $ { "end\n" }')" = "$(printf 'a\nb\nend')" ]
+}
+
+@test "Fixed strings" {
+   [ "$(printf 'a.b\naxb\n(a\n' | ./se -n -F '/a.b/ p ; /(a/ p')" = "$(printf 'a.b\n(a')" ]
+   [ "$(printf 'a.b\naxb\n' | ./se --fixed-strings 's/./-/g')" = "$(printf 'a-b\naxb')" ]
+}
+
+@test "Percentage addresses" {
+   seq 1 9 > /tmp/a.txt
+   [ "$(./se -n '50% p' /tmp/a.txt)" = "5" ]
+   [ "$(./se -n '0%-20%! d ; 90%! p' /tmp/a.txt)" = "$(printf '1\n2')" ]
+   run bash -c "seq 1 9 | ./se -n '50% p'"
+   [ "$status" -eq 1 ]
+   [[ "$output" == *"cannot be used with the standard input"* ]]
+}
+
+@test "Process the files separately" {
+   dir=$(mktemp -d)
+   printf "a\nb\n" > "$dir/one.txt"
+   printf "c\nd\ne\n" > "$dir/two.txt"
+   [ "$(./se -n '= p' "$dir/one.txt" "$dir/two.txt")" = "$(printf '1a\n2b\n3c\n4d\n5e')" ]
+   [ "$(./se -n -s '= p' "$dir/one.txt" "$dir/two.txt")" = "$(printf '1a\n2b\n1c\n2d\n3e')" ]
+   [ "$(./se -n -s '$ p ; 1 h ; $ { g p }' "$dir/one.txt" "$dir/two.txt")" = "$(printf 'b\na\ne\nc')" ]
+   [ "$(./se --separate --count '1-/b|d/' "$dir/one.txt" "$dir/two.txt")" = "4" ]
+   [ "$(./se -n -s 'p ; 2 q' "$dir/one.txt" "$dir/two.txt")" = "$(printf 'a\nb')" ]
+   rm -r "$dir"
+}
+
+@test "Write to files when processing the files separately" {
+   dir=$(mktemp -d)
+   printf "a\nb\n" > "$dir/one.txt"
+   printf "c\nd\n" > "$dir/two.txt"
+   ./se -n -s "1 w $dir/first.txt ; A buf" --dump "buf=$dir/buf.txt" "$dir/one.txt" "$dir/two.txt"
+   [ "$(cat "$dir/first.txt")" = "$(printf 'a\nc')" ]
+   [ "$(cat "$dir/buf.txt")" = "$(printf 'a\nb\nc\nd')" ]
+   rm -r "$dir"
+}
+
+@test "In-place editing" {
+   dir=$(mktemp -d)
+   printf "a\nb\n" > "$dir/one.txt"
+   printf "c\na\n" > "$dir/two.txt"
+   ./se -n -i 's/a/A/ ; 1 "1:" ; p' "$dir/one.txt" "$dir/two.txt"
+   [ "$(cat "$dir/one.txt")" = "$(printf '1:A\nb')" ]
+   [ "$(cat "$dir/two.txt")" = "$(printf '1:c\nA')" ]
+
+   ./se --in-place=.bak 's/A/a/' "$dir/one.txt"
+   [ "$(cat "$dir/one.txt")" = "$(printf '1:a\nb')" ]
+   [ "$(cat "$dir/one.txt.bak")" = "$(printf '1:A\nb')" ]
+
+   run ./se -i --max-iterations 10 '/b/ :{ h }' "$dir/one.txt"
+   [ "$status" -ne 0 ]
+   [ "$(cat "$dir/one.txt")" = "$(printf '1:a\nb')" ]
+   [ "$(ls -A "$dir" | wc -l)" -eq 3 ]
+
+   run ./se -n -i p <<< 'a'
+   [ "$status" -eq 1 ]
+   [[ "$output" == *"cannot edit the standard input"* ]]
+   rm -r "$dir"
+}
+
+@test "Write to files when editing in place" {
+   dir=$(mktemp -d)
+   printf "a\nb\n" > "$dir/one.txt"
+   printf "c\nd\n" > "$dir/two.txt"
+   ./se -i "1 w $dir/first.txt" "$dir/one.txt" "$dir/two.txt"
+   [ "$(cat "$dir/first.txt")" = "$(printf 'a\nc')" ]
+   [ "$(cat "$dir/two.txt")" = "$(printf 'c\nd')" ]
+   rm -r "$dir"
+}
+
+@test "Hold and get with registers" {
+   run diff <(printf "b\na\n") <(printf "a\nb\nc\n" | ./se -n '1 h1 ; 2 h2 ; $ { g2 p g1 p }')
+   [ "$status" -eq 0 ]
+}
+
+@test "Named registers" {
+   run diff <(printf "b\na\n") <(printf "a\nb\nc\n" | ./se -n "1 h'a' ; 2 h'b' ; \$ { g'b' p g'a' p }")
+   [ "$status" -eq 0 ]
+}
+
+@test "Exchange with registers" {
+   run diff <(printf "c\nb\na\n") <(printf "a\nb\nc\n" | ./se -n '1 x1 ; 2 x2 ; 3 p x2 p x1 p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Large outputs are buffered" {
+   run bash -c "seq 1 1000000 | timeout 10 ./se -n p | tail -n 1"
+   [ "$output" = "1000000" ]
+}
+
+@test "Interactive mode" {
+   run diff <(printf "aXc\n>aXc\naXc\n") <(printf "abc\ns/b/X/\nh '>' p\n" | ./se --repl)
+   [ "$status" -eq 0 ]
+}
+
+@test "Binary mode preserves CRLF" {
+   run diff <(printf "a\r\nb\r\n") <(printf "a\r\nb\r\n" | ./se -n --binary p)
+   [ "$status" -eq 0 ]
+   run diff <(printf "a\nb\n") <(printf "a\r\nb\r\n" | ./se -n p)
    [ "$status" -eq 0 ]
 }
 
+@test "Expand environment variables" {
+   run diff <(printf "hi bob\n") <(printf "bob\nalice\n" | SE_NAME=bob ./se -n --expand-env '/^${SE_NAME}$/ s/^/hi /p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Output is flushed on quit" {
+   run bash -c "seq 1 100000 | ./se -n 'p ; 50000 q 3' | tail -n 1"
+   [ "$output" = "50000" ]
+   run bash -c "printf 'a\nb\nc\n' | ./se -n 'P ; 2 q' | od -c"
+   [[ "$output" == *"a   b"* ]]
+}
+
+@test "Prelude script" {
+   run bash -c "printf 'a\r\nb\r\n' | ./se -n --pre 's/\r$//' 'p' | od -c"
+   [[ "$output" != *"\r"* ]]
+   run bash -c "printf 'a\nb\n' | ./se -n --pre 's/a/A/' '/A/ p'"
+   [ "$output" = "A" ]
+}
+
+@test "Hash the lines" {
+   ./se -n 'C crc32' < /dev/null 2>/dev/null || skip "built without the crc feature"
+   run bash -c "echo 123456789 | ./se -n 'C crc32 p'"
+   [ "$output" = "$(printf '123456789\tcbf43926')" ]
+}
+
+@test "Conditional substitution" {
+   run bash -c "printf 'my colour\nred\n' | ./se 's/colour/color/ ; s/$/ (fixed)/?'"
+   [ "$output" = "$(printf 'my color (fixed)\nred')" ]
+}
+
+@test "Count the matches in the line" {
+   run diff <(./se -n '/the/{3,} p' README.md) <(grep -E '(the.*){3,}' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Read gzip compressed input" {
+   gzip -c README.md | ./se -n '' 2>/dev/null | cmp -s - README.md || skip "built without the gzip feature"
+   dir=$(mktemp -d)
+   gzip -c README.md > "$dir/README.md.gz"
+   run diff <(./se -n '/sed/ =tp' "$dir/README.md.gz") <(./se -n '/sed/ =tp' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(./se -n '/sed/ =tp' < "$dir/README.md.gz") <(./se -n '/sed/ =tp' README.md)
+   [ "$status" -eq 0 ]
+   rm -r "$dir"
+}
+
+@test "Change the lines like sed" {
+   run diff <(sed '/sed/ c\
+changed' README.md) <(./se "/sed/ c'changed'" README.md)
+   [ "$status" -eq 0 ]
+   run diff <(sed '/^## Syntax/,/^## Addresses/ c\
+changed' README.md) <(./se "/^## Syntax/-/^## Addresses/ c'changed'" README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Line numbers in the appended text" {
+   [ "$(printf 'a\nb\n' | ./se "a'line %n'")" = "$(printf 'a\nline 1\nb\nline 2')" ]
+   [ "$(printf 'a\nb\n' | ./se -n "2 i'%n%%'")" = "2%" ]
+}
+
 @test "Loops example" {
-   run diff <(./se '/other/ =tpq' README.md) \
-            <(./se ':{ /other/ . R } =tpq' README.md)
+   run diff <(./se -n '/other/ =tpq' README.md) \
+            <(./se -n ':{ /other/ . R } =tpq' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Nested loops" {
    run diff <(printf "3\n4\n13\n14\n21\n22\n23\n24\n") \
-            <(seq 1 25 | ./se ':{ R ; /2/ :{ R ; /5/ . p } }')
+            <(seq 1 25 | ./se -n ':{ R ; /2/ :{ R ; /5/ . p } }')
+   [ "$status" -eq 0 ]
+}
+
+@test "Loop until the substitution changes nothing" {
+   run diff <(printf "1234567 89012\n12\n" | sed -E ':a ; s/([0-9])([0-9]{3})\b/\1,\2/ ; ta') \
+            <(printf "1234567 89012\n12\n" | ./se ':{ /[0-9]{4}/! . s/([0-9])([0-9]{3})\b/$1,$2/ }')
+   [ "$status" -eq 0 ]
+}
+
+@test "Branch to labels like sed" {
+   run diff <(printf "1234567 89012\n12\n" | sed -E ':a ; s/([0-9])([0-9]{3})\b/\1,\2/ ; ta') \
+            <(printf "1234567 89012\n12\n" | ./se ':a ; s/([0-9])([0-9]{3})\b/$1,$2/ ; t a')
+   [ "$status" -eq 0 ]
+   run diff <(seq 1 10 | sed '/[37]/ b skip ; s/$/!/ ; :skip') \
+            <(seq 1 10 | ./se '/[37]/ b skip ; s/$/!/ ; :skip')
+   [ "$status" -eq 0 ]
+}
+
+@test "Infinite loops are stopped" {
+   run bash -c "printf 'a\nb\n' | ./se -n --max-iterations 1000 ':{ h }'"
+   [ "$status" -eq 1 ]
+   [[ "$output" == *"loop exceeded the limit of 1000 iterations at line 1"* ]]
+   run bash -c "./se -n --max-iterations 0 ':{ \"yes\\n\" }' <<< '' 2>/dev/null | head -n 2000000 | wc -l"
+   [ "$output" = "2000000" ]
+}
+
+@test "Ranges in sets see all the lines" {
+   run diff <(printf "a\nb\nc\n" | ./se -n '/b/, /a/-/b/ p') <(printf "a\nb\n")
    [ "$status" -eq 0 ]
 }
 